@@ -1,10 +1,31 @@
 use crate::UbwError;
-use crate::client::{Http1ConnectionPool, WorkInstance};
-use crate::opts::{Opts, WrappedHeaderMap};
-use crate::work_mode::{PostWorkModeSpec, RequestCounter, WorkMode};
+use crate::budget::RequestBudget;
+use crate::check::{FailureSamples, ResponseChecks};
+use crate::client::{
+    ConnectionPool, Http1ConnectionPool, HttpVersion, Origin, WorkInstance, negotiate_http_version,
+};
+use crate::feeder::{FeedFormat, Feeder};
+use crate::http2::Http2ConnectionPool;
+use crate::http3::Http3ConnectionPool;
+use crate::latency::LatencyRecorder;
+use crate::mix::{Endpoint, EndpointStats, MixError, RequestEntry, RequestMix, read_mix_file};
+use crate::opts::{Opts, ParseHeaderListError, WrappedHeaderMap};
+use crate::profile::{LoadProfile, ProfileRunner, ProfileTarget};
+use crate::proxy::{self, Proxy, ProxyError};
+use crate::rate::RateLimiter;
+use crate::scenario::{Scenario, read_scenario_file};
+use crate::template::RequestTemplate;
+use crate::timeseries::{TimeSeries, TimeSeriesFormat};
+use crate::tls::{TlsConfig, TlsOptions};
+use crate::work_mode::{BodySpec, RequestCounter, WorkMode};
+use compact_str::CompactString;
+use hyper::HeaderMap;
+use hyper::header::{ACCEPT, CONTENT_TYPE, HeaderName, HeaderValue};
 use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+use std::sync::Arc;
 use tokio::net::lookup_host;
-use url::Host;
+use url::{Host, Url};
 
 pub async fn read_body_from(path: &std::path::PathBuf) -> Result<bytes::Bytes, std::io::Error> {
     tokio::fs::read(path).await.map(bytes::Bytes::from)
@@ -36,9 +57,14 @@ pub async fn resolve_ipv6(host: &str) -> Result<Option<IpAddr>, std::io::Error>
     Ok(None)
 }
 
-pub async fn prepare_work_instance(args: Opts) -> Result<WorkInstance, UbwError> {
-    let url = args.url;
-    let resolve = match (url.host(), args.ipv4, args.ipv6) {
+/// Resolves the address to connect to for the host of a URL.
+async fn resolve_address(
+    url: &Url,
+    ipv4: bool,
+    ipv6: bool,
+    host: Option<IpAddr>,
+) -> Result<SocketAddr, UbwError> {
+    let resolve = match (url.host(), ipv4, ipv6) {
         (Some(Host::Domain(host)), v4, v6) => {
             if v6 {
                 let v6_resolve = resolve_ipv6(host)
@@ -67,46 +93,608 @@ pub async fn prepare_work_instance(args: Opts) -> Result<WorkInstance, UbwError>
             return Err(UbwError::NoWayToResolveHost);
         }
     };
-    let address = resolve.or(args.host).ok_or(UbwError::NoWayToResolveHost)?;
-    let address = SocketAddr::new(
+    let address = resolve.or(host).ok_or(UbwError::NoWayToResolveHost)?;
+    Ok(SocketAddr::new(
         address,
         url.port_or_known_default().ok_or(UbwError::WeirdUrl)?,
-    );
+    ))
+}
 
-    let work_mode = match (args.method, args.body_string, args.body_file) {
-        (hyper::Method::GET, _, _) => WorkMode::Get,
-        (hyper::Method::POST, Some(body), None) => WorkMode::Post(PostWorkModeSpec {
-            body: bytes::Bytes::from(body),
-            content_type: args.content_type,
-        }),
-        (hyper::Method::POST, None, Some(path)) => WorkMode::Post(PostWorkModeSpec {
-            body: read_body_from(&path)
+/// The method and body of a request, checking that they go together.
+fn work_mode(
+    method: hyper::Method,
+    body: Option<bytes::Bytes>,
+    content_type: Option<CompactString>,
+) -> Result<WorkMode, UbwError> {
+    if method == hyper::Method::CONNECT {
+        return Err(UbwError::UnsupportedMethod(method));
+    }
+    if method == hyper::Method::POST && body.is_none() {
+        return Err(UbwError::RequirePostBody);
+    }
+    Ok(WorkMode {
+        method,
+        body: body.map(|body| BodySpec { body, content_type }),
+    })
+}
+
+/// Reads an inline body or a body file, there may not be both.
+async fn read_body(
+    inline: Option<String>,
+    file: Option<&std::path::Path>,
+) -> Result<Option<bytes::Bytes>, UbwError> {
+    match (inline, file) {
+        (Some(body), None) => Ok(Some(bytes::Bytes::from(body))),
+        (None, Some(path)) => Ok(Some(
+            read_body_from(&path.to_path_buf())
                 .await
                 .map_err(UbwError::FailedToReadBodyFromFile)?,
-            content_type: args.content_type,
-        }),
-        (hyper::Method::POST, None, None) => return Err(UbwError::RequirePostBody),
-        (hyper::Method::POST, Some(_), Some(_)) => return Err(UbwError::RequirePostBody),
-        (method, _, _) => return Err(UbwError::UnsupportedMethod(method)),
-    };
+        )),
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => Err(UbwError::ConflictingBody),
+    }
+}
+
+/// Guesses the content type of a body file from its extension.
+pub fn content_type_for_extension(path: &std::path::Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match extension.as_str() {
+        "json" => "application/json",
+        "jsonl" | "ndjson" => "application/x-ndjson",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        "html" | "htm" => "text/html",
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "js" => "text/javascript",
+        "pb" | "protobuf" => "application/x-protobuf",
+        "msgpack" => "application/msgpack",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "wasm" => "application/wasm",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "bin" => "application/octet-stream",
+        _ => return None,
+    })
+}
 
+/// The content type a body is sent with: the explicit one of `-T` or the mix or scenario entry, else the one of a
+/// `Content-Type` header, else a guess from the extension of the body file.
+///
+/// A `Content-Type` header next to an explicit content type is dropped, with a warning added to `warnings` if they
+/// disagree, so the request does not carry two of them.
+fn body_content_type(
+    endpoint: &str,
+    header_map: &mut HeaderMap,
+    explicit: Option<CompactString>,
+    body_file: Option<&std::path::Path>,
+    has_body: bool,
+    warnings: &mut Vec<String>,
+) -> Option<CompactString> {
+    if !has_body {
+        return None;
+    }
+    match (explicit, header_map.get(CONTENT_TYPE)) {
+        (Some(content_type), Some(header)) => {
+            if !header
+                .as_bytes()
+                .eq_ignore_ascii_case(content_type.as_bytes())
+            {
+                warnings.push(format!(
+                    "{}: the header Content-Type: {} is replaced by the content type {}",
+                    endpoint,
+                    String::from_utf8_lossy(header.as_bytes()),
+                    content_type
+                ));
+            }
+            header_map.remove(CONTENT_TYPE);
+            Some(content_type)
+        }
+        (Some(content_type), None) => Some(content_type),
+        (None, Some(_)) => None,
+        (None, None) => body_file
+            .and_then(content_type_for_extension)
+            .map(CompactString::from),
+    }
+}
+
+/// Sends the `-A` media types as one `Accept` header, replacing an `Accept` header given with `-H` with a warning added
+/// to `warnings`.
+fn apply_accept(
+    header_map: &mut HeaderMap,
+    accept: &[CompactString],
+    warnings: &mut Vec<String>,
+) -> Result<(), UbwError> {
+    if accept.is_empty() {
+        return Ok(());
+    }
+    let accept = HeaderValue::from_str(&accept.join(", ")).map_err(ParseHeaderListError::from)?;
+    if let Some(header) = header_map.get(ACCEPT)
+        && header != accept
+    {
+        warnings.push(format!(
+            "the header Accept: {} is replaced by Accept: {}",
+            String::from_utf8_lossy(header.as_bytes()),
+            String::from_utf8_lossy(accept.as_bytes())
+        ));
+    }
+    header_map.insert(ACCEPT, accept);
+    Ok(())
+}
+
+/// One endpoint of the mix, before its connections are set up.
+struct EndpointSpec {
+    name: String,
+    weight: u32,
+    url: Url,
+    mode: WorkMode,
+    header_map: HeaderMap,
+}
+
+/// Turns one request of a mix or scenario file into an endpoint, on top of the `-u` URL and `-H` headers.
+async fn request_endpoint(
+    entry: RequestEntry,
+    weight: u32,
+    directory: &std::path::Path,
+    base_url: &Url,
+    header_map: &HeaderMap,
+    content_type: Option<&CompactString>,
+    warnings: &mut Vec<String>,
+) -> Result<EndpointSpec, UbwError> {
+    let url = base_url
+        .join(&entry.url)
+        .map_err(|e| MixError::InvalidUrl(entry.url.clone(), e))?;
+    let method = hyper::Method::from_bytes(entry.method.as_bytes())
+        .map_err(|_| MixError::InvalidMethod(entry.method.clone()))?;
+    let mut endpoint_headers = header_map.clone();
+    for (name, value) in &entry.headers {
+        let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) else {
+            return Err(MixError::InvalidHeader(name.clone()).into());
+        };
+        endpoint_headers.insert(name, value);
+    }
+    let body_file = entry.body_file.map(|file| directory.join(file));
+    let body = read_body(entry.body, body_file.as_deref()).await?;
+    let name = entry
+        .name
+        .unwrap_or_else(|| format!("{} {}", method, entry.url));
+    let content_type = body_content_type(
+        &name,
+        &mut endpoint_headers,
+        entry
+            .content_type
+            .map(CompactString::from)
+            .or_else(|| content_type.cloned()),
+        body_file.as_deref(),
+        body.is_some(),
+        warnings,
+    );
+    Ok(EndpointSpec {
+        name,
+        weight,
+        url,
+        mode: work_mode(method, body, content_type)?,
+        header_map: endpoint_headers,
+    })
+}
+
+/// Turns the entries of a mix file into endpoints.
+async fn mix_endpoints(
+    path: &std::path::Path,
+    base_url: &Url,
+    header_map: &HeaderMap,
+    content_type: Option<&CompactString>,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<EndpointSpec>, UbwError> {
+    let directory = path.parent().unwrap_or(std::path::Path::new(""));
+    let mut endpoints = Vec::new();
+    for entry in read_mix_file(path).await? {
+        endpoints.push(
+            request_endpoint(
+                entry.request,
+                entry.weight,
+                directory,
+                base_url,
+                header_map,
+                content_type,
+                warnings,
+            )
+            .await?,
+        );
+    }
+    Ok(endpoints)
+}
+
+/// Turns the steps of a scenario file into endpoints, one per step in order, and the scenario going through them.
+async fn scenario_endpoints(
+    path: &std::path::Path,
+    base_url: &Url,
+    header_map: &HeaderMap,
+    content_type: Option<&CompactString>,
+    warnings: &mut Vec<String>,
+) -> Result<(Vec<EndpointSpec>, Scenario), UbwError> {
+    let directory = path.parent().unwrap_or(std::path::Path::new(""));
+    let file = read_scenario_file(path).await?;
+    let (scenario, requests) = file.into_scenario()?;
+    let mut endpoints = Vec::new();
+    for request in requests {
+        endpoints.push(
+            request_endpoint(
+                request,
+                1,
+                directory,
+                base_url,
+                header_map,
+                content_type,
+                warnings,
+            )
+            .await?,
+        );
+    }
+    Ok((endpoints, scenario))
+}
+
+pub async fn prepare_work_instance(args: Opts) -> Result<WorkInstance, UbwError> {
+    // Certificates are read once here, every connection shares the connectors
+    let tls = Arc::new(TlsConfig::load(TlsOptions::from(&args))?);
+
+    let url = args.url;
     let header_map: WrappedHeaderMap = args.header.try_into()?;
-    let header_map = header_map.0;
+    let mut header_map = header_map.0;
+    let mut warnings = Vec::new();
+    apply_accept(&mut header_map, &args.accept_headers, &mut warnings)?;
+
+    let mut scenario = None;
+    let endpoint_specs = match (&args.mix, &args.scenario) {
+        (Some(path), _) => {
+            mix_endpoints(
+                path,
+                &url,
+                &header_map,
+                args.content_type.as_ref(),
+                &mut warnings,
+            )
+            .await?
+        }
+        (None, Some(path)) => {
+            let (specs, steps) = scenario_endpoints(
+                path,
+                &url,
+                &header_map,
+                args.content_type.as_ref(),
+                &mut warnings,
+            )
+            .await?;
+            scenario = Some(steps);
+            specs
+        }
+        (None, None) => {
+            let body = read_body(args.body_string, args.body_file.as_deref()).await?;
+            let name = format!("{} {}", args.method, url);
+            let content_type = body_content_type(
+                &name,
+                &mut header_map,
+                args.content_type,
+                args.body_file.as_deref(),
+                body.is_some(),
+                &mut warnings,
+            );
+            vec![EndpointSpec {
+                name,
+                weight: 1,
+                url: url.clone(),
+                mode: work_mode(args.method, body, content_type)?,
+                header_map,
+            }]
+        }
+    };
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let profile = match (args.profile, args.profile_file) {
+        (Some(profile), _) => Some(profile),
+        (None, Some(path)) => Some(
+            tokio::fs::read_to_string(&path)
+                .await
+                .map_err(UbwError::FailedToReadProfileFromFile)?
+                .parse::<LoadProfile>()?,
+        ),
+        (None, None) => None,
+    };
+    let profile = profile.map(|profile| ProfileRunner::new(profile, args.profile_target));
+
+    let rate_limiter = match (&profile, args.rate) {
+        (Some(profile), _) if profile.target == ProfileTarget::Rate => {
+            Some(RateLimiter::new(profile.current().1))
+        }
+        (_, Some(rate)) => Some(RateLimiter::new(f64::from(rate.get()))),
+        _ => None,
+    };
+
+    let time_series = match &args.timeseries_file {
+        Some(path) => TimeSeries::with_file(
+            path,
+            args.timeseries_format
+                .unwrap_or_else(|| TimeSeriesFormat::from_path(path)),
+        )
+        .map_err(UbwError::FailedToCreateTimeSeriesFile)?,
+        None => TimeSeries::new(),
+    };
+
+    let checks = ResponseChecks {
+        status: args.expect_status,
+        headers: args.expect_headers,
+        body_contains: args.expect_body,
+        body_regex: args.expect_body_regex,
+        json: args.expect_json,
+        max_body_size: args.max_body_size,
+        max_latency: args.max_latency.map(Into::into),
+        samples: match &args.save_failures {
+            Some(directory) => Some(
+                FailureSamples::new(directory, args.save_failures_max)
+                    .map_err(UbwError::FailedToCreateSamplesDirectory)?,
+            ),
+            None => None,
+        },
+    };
+
+    let proxy = match args.proxy {
+        Some(proxy_url) => {
+            let proxy_url = proxy::with_default_port(proxy_url);
+            let address = resolve_address(&proxy_url, args.ipv4, args.ipv6, None).await?;
+            let headers: WrappedHeaderMap = args.proxy_headers.try_into()?;
+            Some(Arc::new(Proxy::new(proxy_url, address, headers.0)?))
+        }
+        None => None,
+    };
+
+    let worker_count = profile
+        .as_ref()
+        .map_or(0, ProfileRunner::peak_workers)
+        .max(args.concurrent as usize);
+
+    let feeder = match &args.feed {
+        Some(path) => Some(
+            Feeder::open(
+                path,
+                args.feed_format
+                    .unwrap_or_else(|| FeedFormat::from_path(path)),
+                args.feed_order,
+                seed,
+                worker_count,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
+    // The `-u` URL always gets the first origin, endpoints on the same scheme, host and port share it
+    let mut origins: Vec<Origin> = Vec::new();
+    let mut endpoints = Vec::with_capacity(endpoint_specs.len());
+    let mixed = endpoint_specs.len() > 1 || scenario.is_some();
+    for spec in std::iter::once(None).chain(endpoint_specs.into_iter().map(Some)) {
+        let origin_url = spec.as_ref().map_or(&url, |spec| &spec.url);
+        let origin = match origins
+            .iter()
+            .position(|origin| origin.url.origin() == origin_url.origin())
+        {
+            Some(index) => index,
+            None => {
+                if let Some(proxy) = &proxy {
+                    proxy.check_target(origin_url)?;
+                }
+                let address = match &proxy {
+                    Some(proxy) if proxy.resolves_hosts() => proxy.address,
+                    _ => resolve_address(origin_url, args.ipv4, args.ipv6, args.host).await?,
+                };
+                let connection_pool = connection_pool(
+                    origin_url,
+                    address,
+                    proxy.as_deref(),
+                    &tls,
+                    args.http_version,
+                    worker_count,
+                    args.connections,
+                    args.max_streams,
+                    args.zero_rtt,
+                )
+                .await?;
+                origins.push(Origin {
+                    url: origin_url.clone(),
+                    address,
+                    connection_pool,
+                    proxy: proxy.clone(),
+                    tls: tls.clone(),
+                });
+                origins.len() - 1
+            }
+        };
+        let Some(mut spec) = spec else {
+            continue;
+        };
+
+        let template = RequestTemplate::extract(
+            &spec.url[url::Position::BeforePath..url::Position::AfterQuery],
+            &mut spec.header_map,
+            spec.mode.body.as_ref().map(|body| &body.body),
+        )?;
+        for column in template.iter().flat_map(RequestTemplate::feed_columns) {
+            match feeder.as_ref().map(Feeder::columns) {
+                None => return Err(UbwError::FeedColumnWithoutFeed(column.to_string())),
+                Some(Some(columns)) if !columns.iter().any(|c| c == column) => {
+                    return Err(UbwError::UnknownFeedColumn(column.to_string()));
+                }
+                Some(_) => {}
+            }
+        }
+        for name in template.iter().flat_map(RequestTemplate::session_vars) {
+            match &scenario {
+                None => return Err(UbwError::VariableWithoutScenario(name.to_string())),
+                // The endpoint about to be pushed is sent by the step of the same index
+                Some(scenario) => scenario.check_variables(endpoints.len(), [name])?,
+            }
+        }
+        let stats = if mixed {
+            Some(EndpointStats::new()?)
+        } else {
+            None
+        };
+        endpoints.push(Endpoint {
+            name: spec.name,
+            weight: spec.weight,
+            url: spec.url,
+            origin,
+            mode: spec.mode,
+            header_map: spec.header_map,
+            template,
+            stats,
+        });
+    }
 
     Ok(WorkInstance {
-        url: url.clone(),
-        address,
-        mode: work_mode,
-        header_map,
+        origins,
+        mix: RequestMix::new(endpoints)?,
+        seed,
         request_counter: RequestCounter::new(),
-        connection_pool: Http1ConnectionPool::new(args.concurrent as usize),
+        latency: LatencyRecorder::new()?,
+        timeout: args.timeout.map(Into::into),
+        rate_limiter,
+        profile,
+        budget: args
+            .requests
+            .map(|requests| RequestBudget::new(requests.get())),
+        time_series,
+        worker_count,
+        paused: tokio::sync::watch::Sender::new(false),
+        paused_time: std::sync::Mutex::default(),
+        quiet: args.dashboard,
+        feeder,
+        scenario,
+        checks: (!checks.is_empty()).then_some(checks),
+        proxy,
+        warnings,
+        worker_errors: std::sync::Mutex::default(),
     })
 }
 
-pub async fn shutdown(
-    shutdown_tx: tokio::sync::watch::Sender<bool>,
-    duration: std::time::Duration,
-) -> Result<(), tokio::sync::watch::error::SendError<bool>> {
-    tokio::time::sleep(duration).await;
-    shutdown_tx.send(true)
-}
\ No newline at end of file
+/// Connections to one origin in the HTTP version asked for, checked against the URL scheme.
+#[allow(clippy::too_many_arguments)]
+async fn connection_pool(
+    url: &Url,
+    address: SocketAddr,
+    proxy: Option<&Proxy>,
+    tls: &TlsConfig,
+    http_version: HttpVersion,
+    worker_count: usize,
+    connections: NonZeroU32,
+    max_streams: NonZeroU32,
+    zero_rtt: bool,
+) -> Result<ConnectionPool, UbwError> {
+    let http_version = match http_version {
+        HttpVersion::H2cUpgrade if url.scheme() != "http" => {
+            return Err(UbwError::H2cUpgradeRequiresHttp);
+        }
+        HttpVersion::Http3 if url.scheme() != "https" => return Err(UbwError::Http3RequiresTls),
+        HttpVersion::Http3 if proxy.is_some() => return Err(ProxyError::Http3ThroughProxy.into()),
+        HttpVersion::Auto => negotiate_http_version(url, address, proxy, tls)
+            .await
+            .map_err(UbwError::FailedToNegotiateHttpVersion)?,
+        version => version,
+    };
+    Ok(match http_version {
+        HttpVersion::Http2 | HttpVersion::H2cUpgrade => {
+            ConnectionPool::Http2(Http2ConnectionPool::new(
+                connections.get() as usize,
+                max_streams.get() as usize,
+                http_version == HttpVersion::H2cUpgrade,
+            ))
+        }
+        HttpVersion::Http3 => ConnectionPool::Http3(Http3ConnectionPool::new(
+            tls.quic_client_config()?,
+            address,
+            connections.get() as usize,
+            max_streams.get() as usize,
+            zero_rtt,
+        )?),
+        HttpVersion::Http1 | HttpVersion::Auto => {
+            ConnectionPool::Http1(Http1ConnectionPool::new(worker_count))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_content_type() {
+        let json_file = Some(std::path::Path::new("data/payload.JSON"));
+        let mut headers = HeaderMap::new();
+        let mut warnings = Vec::new();
+        assert_eq!(
+            body_content_type("test", &mut headers, None, json_file, true, &mut warnings)
+                .as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(
+            body_content_type("test", &mut headers, None, json_file, false, &mut warnings),
+            None
+        );
+
+        // An explicit content type replaces the header, a header beats the guess
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        assert_eq!(
+            body_content_type(
+                "test",
+                &mut headers.clone(),
+                None,
+                json_file,
+                true,
+                &mut warnings
+            ),
+            None
+        );
+        assert_eq!(
+            body_content_type(
+                "test",
+                &mut headers,
+                Some("text/csv".into()),
+                json_file,
+                true,
+                &mut warnings
+            )
+            .as_deref(),
+            Some("text/csv")
+        );
+        assert!(headers.get(CONTENT_TYPE).is_none());
+        assert_eq!(
+            warnings,
+            ["test: the header Content-Type: text/plain is replaced by the content type text/csv"]
+        );
+    }
+
+    #[test]
+    fn test_apply_accept() -> Result<(), UbwError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+        let mut warnings = Vec::new();
+        apply_accept(
+            &mut headers,
+            &["application/json".into(), "text/*;q=0.5".into()],
+            &mut warnings,
+        )?;
+        assert_eq!(
+            headers.get_all(ACCEPT).iter().collect::<Vec<_>>(),
+            vec!["application/json, text/*;q=0.5"]
+        );
+        assert_eq!(warnings.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A fixed number of requests shared by all workers.
+///
+/// Workers claim a ticket before sending, so no more than `total` requests are ever sent. A ticket
+/// counts as completed once the outcome of its request is recorded, a ticket dropped before that is
+/// only released.
+#[derive(Debug)]
+pub struct RequestBudget {
+    total: u64,
+    claimed: AtomicU64,
+    completed: AtomicU64,
+    /// Tickets completed or given up
+    released: AtomicU64,
+    finished: tokio::sync::Notify,
+}
+
+impl RequestBudget {
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            claimed: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            released: AtomicU64::new(0),
+            finished: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Claims the right to send one request, `None` once the budget is used up.
+    pub fn try_claim(&self) -> Option<BudgetTicket<'_>> {
+        self.claimed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |claimed| {
+                (claimed < self.total).then_some(claimed + 1)
+            })
+            .ok()
+            .map(|_| BudgetTicket {
+                budget: self,
+                completed: false,
+            })
+    }
+
+    fn release_one(&self) {
+        if self.released.fetch_add(1, Ordering::AcqRel) + 1 == self.total {
+            self.finished.notify_one();
+        }
+    }
+
+    /// Waits until every ticket of the budget has been completed or given up.
+    pub async fn wait_until_released(&self) {
+        if self.released.load(Ordering::Acquire) >= self.total {
+            return;
+        }
+        self.finished.notified().await;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed() >= self.total
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Acquire)
+    }
+}
+
+/// One claimed request of a [`RequestBudget`].
+///
+/// Dropping it releases the request, so a worker that gives up on a request halfway, or stops with an
+/// error, cannot keep [`RequestBudget::wait_until_released`] waiting forever. Only [`BudgetTicket::complete`]
+/// counts it as completed.
+#[derive(Debug)]
+pub struct BudgetTicket<'a> {
+    budget: &'a RequestBudget,
+    completed: bool,
+}
+
+impl BudgetTicket<'_> {
+    /// Counts the request as completed, its outcome has been recorded.
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for BudgetTicket<'_> {
+    fn drop(&mut self) {
+        if self.completed {
+            self.budget.completed.fetch_add(1, Ordering::AcqRel);
+        }
+        self.budget.release_one();
+    }
+}
+
+/// Whether the whole budget was sent or the run was stopped before, for the end of the run.
+pub fn format_report(budget: &RequestBudget) -> String {
+    if budget.is_complete() {
+        format!("Request budget completed: {} requests", budget.total())
+    } else {
+        format!(
+            "Request budget cut short: {} of {} requests completed",
+            budget.completed(),
+            budget.total()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_claims_stop_at_the_total() -> Result<(), tokio::time::error::Elapsed> {
+        let budget = RequestBudget::new(3);
+        let mut tickets = (0..5)
+            .filter_map(|_| budget.try_claim())
+            .collect::<Vec<_>>();
+        assert_eq!(tickets.len(), 3);
+        tickets.drain(1..).for_each(BudgetTicket::complete);
+        assert!(!budget.is_complete());
+        tickets.into_iter().for_each(BudgetTicket::complete);
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            budget.wait_until_released(),
+        )
+        .await?;
+        assert_eq!(budget.completed(), 3);
+        assert!(budget.is_complete());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dropped_tickets_release_the_budget() -> Result<(), Box<dyn std::error::Error>> {
+        let budget = RequestBudget::new(2);
+        let first = budget.try_claim().ok_or("no ticket")?;
+        let second = budget.try_claim().ok_or("no ticket")?;
+        assert!(budget.try_claim().is_none());
+        first.complete();
+        assert!(!budget.is_complete());
+        // Given up, as when the run is stopped with the request in flight
+        drop(second);
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            budget.wait_until_released(),
+        )
+        .await?;
+        assert_eq!(budget.completed(), 1);
+        assert!(!budget.is_complete());
+        assert_eq!(
+            format_report(&budget),
+            "Request budget cut short: 1 of 2 requests completed"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,500 @@
+use crate::opts::{REDACTED, is_sensitive_header};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::http;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Which check a response failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckKind {
+    /// The status code is not one of the expected ones
+    Status,
+
+    /// A header is missing or has another value
+    Header,
+
+    /// The body does not contain the text or match the regex
+    Body,
+
+    /// The body is not JSON or the value at the pointer differs
+    Json,
+
+    /// The body is larger than allowed
+    BodySize,
+
+    /// The response took longer than allowed
+    Latency,
+}
+
+impl CheckKind {
+    pub const ALL: [CheckKind; 6] = [
+        CheckKind::Status,
+        CheckKind::Header,
+        CheckKind::Body,
+        CheckKind::Json,
+        CheckKind::BodySize,
+        CheckKind::Latency,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CheckKind::Status => "status",
+            CheckKind::Header => "header",
+            CheckKind::Body => "body",
+            CheckKind::Json => "json",
+            CheckKind::BodySize => "body_size",
+            CheckKind::Latency => "latency",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseCheckError {
+    #[error(
+        "Invalid status {0:?}, expected a code like 204, a class from 1xx to 5xx or a range like 200-299"
+    )]
+    InvalidStatus(String),
+
+    #[error("Invalid header check {0:?}, expected <name> or <name>: <value>")]
+    InvalidHeader(String),
+
+    #[error("Invalid JSON check {0:?}, expected <pointer>=<value> like /status=\"ok\"")]
+    InvalidJson(String),
+}
+
+/// The status codes a response may have, e.g. `200,204` or `2xx,304`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusSet(Vec<(u16, u16)>);
+
+impl StatusSet {
+    pub fn contains(&self, status: http::StatusCode) -> bool {
+        let status = status.as_u16();
+        self.0
+            .iter()
+            .any(|(low, high)| (*low..=*high).contains(&status))
+    }
+}
+
+impl FromStr for StatusSet {
+    type Err = ParseCheckError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseCheckError::InvalidStatus(s.to_string());
+        let parse = |code: &str| code.trim().parse::<u16>().map_err(|_| invalid());
+        s.split(',')
+            .map(|item| {
+                let item = item.trim();
+                if let Some(class) = item.strip_suffix("xx") {
+                    let class = parse(class)?;
+                    if !(1..=5).contains(&class) {
+                        return Err(invalid());
+                    }
+                    Ok((class * 100, class * 100 + 99))
+                } else if let Some((low, high)) = item.split_once('-') {
+                    let (low, high) = (parse(low)?, parse(high)?);
+                    if low > high {
+                        return Err(invalid());
+                    }
+                    Ok((low, high))
+                } else {
+                    let code = parse(item)?;
+                    Ok((code, code))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(StatusSet)
+    }
+}
+
+impl std::fmt::Display for StatusSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, (low, high)) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            if low == high {
+                write!(f, "{low}")?;
+            } else if low % 100 == 0 && *high == low + 99 {
+                write!(f, "{}xx", low / 100)?;
+            } else {
+                write!(f, "{low}-{high}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A header the response must have, optionally with an exact value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderCheck {
+    pub name: http::HeaderName,
+    pub value: Option<String>,
+}
+
+impl FromStr for HeaderCheck {
+    type Err = ParseCheckError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value.trim().to_string())),
+            None => (s, None),
+        };
+        let name = http::HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| ParseCheckError::InvalidHeader(s.to_string()))?;
+        Ok(Self { name, value })
+    }
+}
+
+impl std::fmt::Display for HeaderCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}: {}", self.name, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A value the JSON body must have at a JSON pointer.
+///
+/// The expected value is parsed as JSON, falling back to a string, so `/ok=true` and `/name=alice` both work.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonCheck {
+    pub pointer: String,
+    pub value: serde_json::Value,
+}
+
+impl FromStr for JsonCheck {
+    type Err = ParseCheckError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pointer, value) = s
+            .split_once('=')
+            .filter(|(pointer, _)| pointer.is_empty() || pointer.starts_with('/'))
+            .ok_or_else(|| ParseCheckError::InvalidJson(s.to_string()))?;
+        Ok(Self {
+            pointer: pointer.to_string(),
+            value: serde_json::from_str(value)
+                .unwrap_or_else(|_| serde_json::Value::String(value.to_string())),
+        })
+    }
+}
+
+impl std::fmt::Display for JsonCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.pointer, self.value)
+    }
+}
+
+/// Why a response failed a check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckFailure {
+    pub kind: CheckKind,
+    pub message: String,
+}
+
+impl CheckFailure {
+    fn new(kind: CheckKind, message: String) -> Self {
+        Self { kind, message }
+    }
+}
+
+/// Checks every response has to pass to count as a success, the first failed one is reported.
+#[derive(Debug, Default)]
+pub struct ResponseChecks {
+    pub status: Option<StatusSet>,
+    pub headers: Vec<HeaderCheck>,
+    pub body_contains: Vec<String>,
+    pub body_regex: Vec<regex::Regex>,
+    pub json: Vec<JsonCheck>,
+    pub max_body_size: Option<u64>,
+    pub max_latency: Option<Duration>,
+    /// Where responses that failed a check are saved
+    pub samples: Option<FailureSamples>,
+}
+
+impl ResponseChecks {
+    /// `true` if there is nothing to check.
+    pub fn is_empty(&self) -> bool {
+        self.status.is_none()
+            && self.headers.is_empty()
+            && self.body_contains.is_empty()
+            && self.body_regex.is_empty()
+            && self.json.is_empty()
+            && self.max_body_size.is_none()
+            && self.max_latency.is_none()
+    }
+
+    pub fn check(
+        &self,
+        response: &http::response::Parts,
+        body: &[u8],
+        latency: Duration,
+    ) -> Result<(), CheckFailure> {
+        if let Some(status) = &self.status
+            && !status.contains(response.status)
+        {
+            return Err(CheckFailure::new(
+                CheckKind::Status,
+                format!("unexpected status {}", response.status),
+            ));
+        }
+        for check in &self.headers {
+            let value = response.headers.get(&check.name);
+            match (value, &check.value) {
+                (None, _) => {
+                    return Err(CheckFailure::new(
+                        CheckKind::Header,
+                        format!("missing header {}", check.name),
+                    ));
+                }
+                (Some(value), Some(expected)) if value.as_bytes() != expected.as_bytes() => {
+                    return Err(CheckFailure::new(
+                        CheckKind::Header,
+                        format!(
+                            "header {} is {:?}, expected {:?}",
+                            check.name, value, expected
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        if let Some(max) = self.max_body_size
+            && body.len() as u64 > max
+        {
+            return Err(CheckFailure::new(
+                CheckKind::BodySize,
+                format!("body of {} bytes, at most {} allowed", body.len(), max),
+            ));
+        }
+        if !self.body_contains.is_empty() || !self.body_regex.is_empty() {
+            let text = String::from_utf8_lossy(body);
+            if let Some(missing) = self
+                .body_contains
+                .iter()
+                .find(|s| !text.contains(s.as_str()))
+            {
+                return Err(CheckFailure::new(
+                    CheckKind::Body,
+                    format!("body does not contain {:?}", missing),
+                ));
+            }
+            if let Some(regex) = self.body_regex.iter().find(|regex| !regex.is_match(&text)) {
+                return Err(CheckFailure::new(
+                    CheckKind::Body,
+                    format!("body does not match /{}/", regex),
+                ));
+            }
+        }
+        if !self.json.is_empty() {
+            let value: serde_json::Value = serde_json::from_slice(body).map_err(|e| {
+                CheckFailure::new(CheckKind::Json, format!("body is not JSON: {}", e))
+            })?;
+            for check in &self.json {
+                let actual = value.pointer(&check.pointer);
+                if actual != Some(&check.value) {
+                    return Err(CheckFailure::new(
+                        CheckKind::Json,
+                        format!(
+                            "{} is {}, expected {}",
+                            check.pointer,
+                            actual.map_or("missing".to_string(), ToString::to_string),
+                            check.value
+                        ),
+                    ));
+                }
+            }
+        }
+        if let Some(max) = self.max_latency
+            && latency > max
+        {
+            return Err(CheckFailure::new(
+                CheckKind::Latency,
+                format!(
+                    "took {}, at most {} allowed",
+                    humantime::format_duration(latency),
+                    humantime::format_duration(max)
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Saves the first few responses that failed a check to a directory, one text file each.
+#[derive(Debug)]
+pub struct FailureSamples {
+    directory: PathBuf,
+    max: usize,
+    taken: AtomicUsize,
+    /// The first sample that could not be written, told at the end of the run
+    save_error: Mutex<Option<String>>,
+}
+
+impl FailureSamples {
+    pub fn new(directory: &Path, max: usize) -> std::io::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            max,
+            taken: AtomicUsize::new(0),
+            save_error: Mutex::new(None),
+        })
+    }
+
+    /// Why a sample could not be written, the first time it happened.
+    pub fn save_error(&self) -> Option<String> {
+        self.save_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Writes the request, the response and why it failed, unless enough samples were saved already.
+    /// The first error is also kept for [`FailureSamples::save_error`].
+    pub async fn save(
+        &self,
+        request: &http::Request<Full<Bytes>>,
+        response: &http::response::Parts,
+        body: &[u8],
+        failure: &CheckFailure,
+    ) -> std::io::Result<()> {
+        let index = self.taken.fetch_add(1, Ordering::Relaxed);
+        if index >= self.max {
+            return Ok(());
+        }
+        let mut sample = format!(
+            "# {} check failed: {}\n\n{} {} {:?}\n",
+            failure.kind.name(),
+            failure.message,
+            request.method(),
+            request.uri(),
+            request.version()
+        );
+        push_headers(&mut sample, request.headers());
+        let Ok(request_body) = request.body().clone().collect().await;
+        sample.push_str(&String::from_utf8_lossy(&request_body.to_bytes()));
+        sample.push_str(&format!("\n\n{:?} {}\n", response.version, response.status));
+        push_headers(&mut sample, &response.headers);
+        sample.push_str(&String::from_utf8_lossy(body));
+        let result = tokio::fs::write(
+            self.directory.join(format!("failure-{:04}.txt", index + 1)),
+            sample,
+        )
+        .await;
+        if let Err(e) = &result {
+            self.save_error
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get_or_insert_with(|| e.to_string());
+        }
+        result
+    }
+}
+
+/// Writes the headers one per line, leaving out the values of those that carry credentials.
+fn push_headers(out: &mut String, headers: &http::HeaderMap) {
+    for (name, value) in headers {
+        let value = if is_sensitive_header(name.as_str()) {
+            REDACTED.into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        out.push_str(&format!("{}: {}\n", name, value));
+    }
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks() -> Result<(), Box<dyn std::error::Error>> {
+        let checks = ResponseChecks {
+            status: Some("2xx,304".parse()?),
+            headers: vec!["content-type: application/json".parse()?],
+            body_contains: vec!["ok".to_string()],
+            json: vec!["/status=\"ok\"".parse()?, "/count=2".parse()?],
+            max_latency: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let response = |status: u16| -> Result<http::response::Parts, http::Error> {
+            Ok(http::Response::builder()
+                .status(status)
+                .header("content-type", "application/json")
+                .body(())?
+                .into_parts()
+                .0)
+        };
+        let body = br#"{"status": "ok", "count": 2}"#;
+        let fast = Duration::from_millis(10);
+        assert_eq!(checks.check(&response(200)?, body, fast), Ok(()));
+        let kind = |result: Result<(), CheckFailure>| result.err().map(|failure| failure.kind);
+        assert_eq!(
+            kind(checks.check(&response(500)?, body, fast)),
+            Some(CheckKind::Status)
+        );
+        assert_eq!(
+            kind(checks.check(&response(200)?, br#"{"status": "ok", "count": 3}"#, fast)),
+            Some(CheckKind::Json)
+        );
+        assert_eq!(
+            kind(checks.check(&response(304)?, body, Duration::from_secs(2))),
+            Some(CheckKind::Latency)
+        );
+        assert!("abc".parse::<StatusSet>().is_err());
+        assert!("700xx".parse::<StatusSet>().is_err());
+        assert!("0xx".parse::<StatusSet>().is_err());
+        assert!("500-200".parse::<StatusSet>().is_err());
+        assert!(
+            "200-299,5xx"
+                .parse::<StatusSet>()?
+                .contains(http::StatusCode::BAD_GATEWAY)
+        );
+        assert!("status=ok".parse::<JsonCheck>().is_err());
+        // Printed the way they are given, for the report
+        assert_eq!(
+            "200-204, 5xx,304".parse::<StatusSet>()?.to_string(),
+            "200-204,5xx,304"
+        );
+        assert_eq!(
+            checks.headers[0].to_string(),
+            "content-type: application/json"
+        );
+        assert_eq!(checks.json[0].to_string(), "/status=\"ok\"");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_saved_samples_leave_out_credentials() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = std::env::temp_dir().join(format!("ubw-{}-samples", std::process::id()));
+        let samples = FailureSamples::new(&directory, 1)?;
+        let request = http::Request::builder()
+            .uri("http://localhost/")
+            .header("authorization", "Bearer secret")
+            .header("x-api-key", "secret")
+            .header("x-mode", "a")
+            .body(Full::new(Bytes::from_static(b"body")))?;
+        let response = http::Response::builder()
+            .status(500)
+            .header("set-cookie", "session=secret")
+            .body(())?
+            .into_parts()
+            .0;
+        let failure = CheckFailure::new(CheckKind::Status, "500".to_string());
+        samples.save(&request, &response, b"", &failure).await?;
+        let sample = std::fs::read_to_string(directory.join("failure-0001.txt"));
+        std::fs::remove_dir_all(&directory)?;
+        let sample = sample?;
+        assert!(!sample.contains("secret"));
+        assert!(sample.contains("authorization: [redacted]\n"));
+        assert!(sample.contains("set-cookie: [redacted]\n"));
+        assert!(sample.contains("x-mode: a\n"));
+        Ok(())
+    }
+}
@@ -1,22 +1,58 @@
-use crate::work_mode::{ClientResponseCodeType, RequestCounter, WorkMode};
+use crate::budget::RequestBudget;
+use crate::check::ResponseChecks;
+use crate::feeder::{Feeder, Row};
+use crate::http2::{self, Http2Conn, Http2ConnectionPool, Http2Stream};
+use crate::http3::{self, Http3Body, Http3ConnectionPool, Http3Stream};
+use crate::latency::{LatencyRecorder, RequestTiming};
+use crate::mix::{Endpoint, RequestMix};
+use crate::pcg64si::Pcg64Si;
+use crate::profile::ProfileRunner;
+use crate::proxy::{Proxy, ProxyKind};
+use crate::rate::RateLimiter;
+use crate::scenario::{Scenario, Session};
+use crate::template::RequestTemplate;
+use crate::timeseries::TimeSeries;
+use crate::tls::{self, Alpn, TlsConfig, TlsStream};
+use crate::work_mode::{FailureKind, RequestCounter};
 use bytes::Bytes;
 use http_body_util::{BodyExt, Full};
+use hyper::body::Body;
 use hyper::client::conn::http1;
-use hyper::{HeaderMap, StatusCode, http};
+use hyper::{HeaderMap, http};
 use hyper_util::rt::TokioIo;
+use rand::SeedableRng;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use crossbeam::queue::ArrayQueue;
 use tokio::net::TcpStream;
-use tokio_native_tls::{TlsStream, native_tls};
+use tokio::task::JoinHandle;
 use url::Url;
 
 type Http1Conn = http1::SendRequest<Full<Bytes>>;
 
+/// HTTP version to speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HttpVersion {
+    #[value(name = "1.1")]
+    Http1,
+    /// HTTP/2 over TLS, or cleartext h2c with prior knowledge for http URLs
+    #[value(name = "2")]
+    Http2,
+    /// Cleartext HTTP/2 reached through an HTTP/1.1 `Upgrade: h2c` request on every connection
+    #[value(name = "h2c-upgrade")]
+    H2cUpgrade,
+    /// HTTP/3 over QUIC
+    #[value(name = "3")]
+    Http3,
+    /// Asks the server through TLS ALPN, HTTP/1.1 for http URLs
+    Auto,
+}
+
 pub enum Stream {
     Tcp(TcpStream),
-    Tls(TlsStream<TcpStream>),
+    Tls(TlsStream),
 }
 
 impl Stream {
@@ -45,16 +81,305 @@ impl Stream {
             }
         }
     }
+
+    async fn handshake_http2(self) -> Result<(Http2Conn, JoinHandle<()>), h2::Error> {
+        match self {
+            Stream::Tcp(stream) => http2::handshake(stream, 1).await,
+            Stream::Tls(stream) => http2::handshake(stream, 1).await,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("TCP connect failed {0}")]
+    Tcp(std::io::Error),
+
+    #[error("TLS handshake failed {0}")]
+    Tls(tls::HandshakeError),
+
+    #[error("HTTP handshake failed {0}")]
+    Handshake(hyper::Error),
+
+    #[error("HTTP/2 handshake failed {0}")]
+    Http2Handshake(h2::Error),
+
+    #[error("The server did not switch to h2c, it answered {0}")]
+    UpgradeRefused(http::StatusCode),
+
+    #[error("Failed to build the upgrade request {0}")]
+    UpgradeRequest(http::Error),
+
+    #[error("QUIC connect failed {0}")]
+    Quic(quinn::ConnectError),
+
+    #[error("QUIC handshake failed {0}")]
+    QuicHandshake(quinn::ConnectionError),
+
+    #[error("HTTP/3 handshake failed {0}")]
+    Http3Handshake(h3::error::ConnectionError),
+
+    #[error("Failed to connect to the proxy {0}")]
+    ProxyConnect(std::io::Error),
+
+    #[error("Failed to talk to the proxy {0}")]
+    ProxyIo(std::io::Error),
+
+    #[error("The proxy refused the tunnel with {0}")]
+    ProxyRefused(http::StatusCode),
+
+    #[error("The proxy sent an invalid answer: {0}")]
+    ProxyResponse(String),
+
+    #[error("The SOCKS5 proxy did not accept the login")]
+    SocksAuthFailed,
+
+    #[error("The SOCKS5 proxy refused the tunnel: {}", crate::proxy::socks_reply_message(*.0))]
+    SocksRefused(u8),
+}
+
+impl ConnectError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            ConnectError::Tcp(e) => match classify_io_error(e) {
+                FailureKind::Timeout => FailureKind::Timeout,
+                _ => FailureKind::Connect,
+            },
+            ConnectError::Tls(_) => FailureKind::Tls,
+            ConnectError::Handshake(e) => classify_hyper_error(e),
+            ConnectError::Http2Handshake(e) => classify_h2_error(e),
+            ConnectError::UpgradeRefused(_) => FailureKind::Protocol,
+            ConnectError::UpgradeRequest(_) => FailureKind::Other,
+            ConnectError::Quic(_) => FailureKind::Connect,
+            ConnectError::QuicHandshake(e) => http3::classify_connection_error(e),
+            ConnectError::Http3Handshake(_) => FailureKind::Protocol,
+            ConnectError::ProxyConnect(_)
+            | ConnectError::ProxyIo(_)
+            | ConnectError::ProxyRefused(_)
+            | ConnectError::ProxyResponse(_)
+            | ConnectError::SocksAuthFailed
+            | ConnectError::SocksRefused(_) => FailureKind::Proxy,
+        }
+    }
+}
+
+/// Time spent paused so far.
+#[derive(Debug, Default)]
+pub struct PausedTime {
+    total: Duration,
+    /// When the current pause began
+    since: Option<Instant>,
+}
+
+impl PausedTime {
+    fn get(&self) -> Duration {
+        self.total + self.since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+/// Measures time while the run is not paused.
+pub struct ActiveClock<'a> {
+    work_instance: &'a WorkInstance,
+    start: Instant,
+    paused_before: Duration,
+}
+
+impl ActiveClock<'_> {
+    pub fn elapsed(&self) -> Duration {
+        let paused = self
+            .work_instance
+            .paused_time()
+            .saturating_sub(self.paused_before);
+        self.start.elapsed().saturating_sub(paused)
+    }
 }
 
 #[derive(Debug)]
 pub struct WorkInstance {
+    /// Where the requests go, the `-u` URL first
+    pub origins: Vec<Origin>,
+    /// The requests to send
+    pub mix: RequestMix,
+    /// Each worker draws its random numbers from its own generator, seeded from this and the worker id
+    pub seed: u64,
+    pub request_counter: RequestCounter,
+    pub latency: LatencyRecorder,
+    /// Give up on a request after this long, connecting included
+    pub timeout: Option<Duration>,
+    /// Present in constant-rate mode
+    pub rate_limiter: Option<RateLimiter>,
+    /// Present when a load profile drives the run
+    pub profile: Option<ProfileRunner>,
+    /// Present when a fixed number of requests is sent
+    pub budget: Option<RequestBudget>,
+    pub time_series: TimeSeries,
+    /// Number of request loops to spawn
+    pub worker_count: usize,
+    /// Workers hold off sending while this is `true`
+    pub paused: tokio::sync::watch::Sender<bool>,
+    /// How long the run has been paused, so the time limit and the load profile stand still meanwhile
+    pub paused_time: std::sync::Mutex<PausedTime>,
+    /// Nothing may print while the run is going, the live dashboard or the embedding program shows the progress
+    pub quiet: bool,
+    /// Present when the template variables are filled from a feed file
+    pub feeder: Option<Feeder>,
+    /// Present when every worker goes through the steps of a scenario instead of picking endpoints from the mix
+    pub scenario: Option<Scenario>,
+    /// Present when responses have to pass checks to count as successes
+    pub checks: Option<ResponseChecks>,
+    /// Present when every connection goes through an HTTP proxy
+    pub proxy: Option<Arc<Proxy>>,
+    /// Options that override each other, found while the run was set up and left to the caller to print
+    pub warnings: Vec<String>,
+    /// Why workers stopped before the end of the run, left to the caller to print
+    pub worker_errors: std::sync::Mutex<Vec<String>>,
+}
+
+/// A scheme, host and port the run sends requests to, with its own connections.
+#[derive(Debug)]
+pub struct Origin {
     pub url: Url,
+    /// The resolved host, or the proxy if it resolves the host itself
     pub address: SocketAddr,
-    pub mode: WorkMode,
-    pub header_map: HeaderMap,
-    pub request_counter: RequestCounter,
-    pub connection_pool: Http1ConnectionPool,
+    pub connection_pool: ConnectionPool,
+    pub proxy: Option<Arc<Proxy>>,
+    pub tls: Arc<TlsConfig>,
+}
+
+/// Connections of the HTTP version the run speaks.
+#[derive(Debug)]
+pub enum ConnectionPool {
+    Http1(Http1ConnectionPool),
+    Http2(Http2ConnectionPool),
+    Http3(Http3ConnectionPool),
+}
+
+/// A connection taken from the pool for one request.
+pub enum PooledConnection {
+    Http1(Http1Conn),
+    Http2(Http2Stream),
+    Http3(Http3Stream),
+}
+
+impl PooledConnection {
+    async fn send_request(
+        &mut self,
+        request: http::Request<Full<Bytes>>,
+    ) -> Result<http::Response<ResponseBody>, SendError> {
+        match self {
+            PooledConnection::Http1(conn) => {
+                Ok(conn.send_request(request).await?.map(ResponseBody::Http1))
+            }
+            PooledConnection::Http2(stream) => Ok(http2::send_request(&stream.conn, request)
+                .await?
+                .map(ResponseBody::Http2)),
+            PooledConnection::Http3(stream) => {
+                let (response, body) = http3::send_request(&stream.conn, request).await?;
+                Ok(response.map(|()| ResponseBody::Http3(Box::new(body))))
+            }
+        }
+    }
+}
+
+/// A response body that has not been read yet.
+pub enum ResponseBody {
+    Http1(hyper::body::Incoming),
+    Http2(h2::RecvStream),
+    Http3(Box<Http3Body>),
+}
+
+impl ResponseBody {
+    async fn read(self) -> Result<Bytes, SendError> {
+        match self {
+            ResponseBody::Http1(body) => Ok(body.collect().await?.to_bytes()),
+            ResponseBody::Http2(body) => Ok(http2::read_body(body).await?),
+            ResponseBody::Http3(body) => Ok(http3::read_body(*body).await?),
+        }
+    }
+}
+
+/// Why a request on an open connection failed.
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error("{0}")]
+    Http1(#[from] hyper::Error),
+
+    #[error("{0}")]
+    Http2(#[from] h2::Error),
+
+    #[error("{0}")]
+    Http3(#[from] h3::error::StreamError),
+}
+
+impl SendError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            SendError::Http1(e) => classify_hyper_error(e),
+            SendError::Http2(e) => classify_h2_error(e),
+            SendError::Http3(e) => http3::classify_stream_error(e).0,
+        }
+    }
+}
+
+impl ConnectionPool {
+    pub fn is_http2(&self) -> bool {
+        matches!(self, ConnectionPool::Http2(_))
+    }
+
+    /// The HTTP version of the requests sent on these connections.
+    pub fn version(&self) -> http::Version {
+        match self {
+            ConnectionPool::Http1(_) => http::Version::HTTP_11,
+            ConnectionPool::Http2(_) => http::Version::HTTP_2,
+            ConnectionPool::Http3(_) => http::Version::HTTP_3,
+        }
+    }
+
+    /// Takes a connection, or a stream on one in HTTP/2 and HTTP/3, opening a new connection if needed.
+    /// The returned duration is the connect time, `None` if an open connection was reused.
+    pub async fn get_or_connect(
+        &self,
+        origin: &Origin,
+    ) -> Result<(PooledConnection, Option<Duration>), ConnectError> {
+        match self {
+            ConnectionPool::Http1(pool) => pool
+                .get_or_connect(origin)
+                .await
+                .map(|(conn, connect)| (PooledConnection::Http1(conn), connect)),
+            ConnectionPool::Http2(pool) => pool
+                .get_or_connect(origin)
+                .await
+                .map(|(stream, connect)| (PooledConnection::Http2(stream), connect)),
+            ConnectionPool::Http3(pool) => pool
+                .get_or_connect(origin.address, origin.url.host_str().unwrap_or_default())
+                .await
+                .map(|(stream, connect)| (PooledConnection::Http3(stream), connect)),
+        }
+    }
+
+    /// Gives back a connection whose response was read in full.
+    pub fn release(&self, conn: PooledConnection) {
+        if let (ConnectionPool::Http1(pool), PooledConnection::Http1(conn)) = (self, conn) {
+            pool.put(conn);
+        }
+    }
+
+    /// Drops a connection that failed a request.
+    pub async fn discard(&self, conn: PooledConnection, error: &SendError) {
+        match (self, conn, error) {
+            (
+                ConnectionPool::Http2(pool),
+                PooledConnection::Http2(stream),
+                SendError::Http2(error),
+            ) => pool.discard(stream, error).await,
+            (
+                ConnectionPool::Http3(pool),
+                PooledConnection::Http3(stream),
+                SendError::Http3(error),
+            ) => pool.discard(stream, error).await,
+            _ => {}
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -77,54 +402,154 @@ impl Http1ConnectionPool {
         let _ = self.queue.push(conn); // drop if full
     }
 
-    pub async fn get_or_connect(&self, work_instance: &WorkInstance) -> anyhow::Result<Http1Conn> {
+    /// Takes a pooled connection or opens a new one.
+    /// The returned duration is the connect time, `None` if a pooled connection was reused.
+    pub async fn get_or_connect(
+        &self,
+        origin: &Origin,
+    ) -> Result<(Http1Conn, Option<Duration>), ConnectError> {
         if let Some(conn) = self.try_get() {
-            Ok(conn)
+            Ok((conn, None))
         } else {
-            work_instance.connect().await
+            let start = Instant::now();
+            let conn = origin.connect().await?;
+            Ok((conn, Some(start.elapsed())))
         }
     }
 }
 
 
 impl WorkInstance {
-    /// Connect to the socket, if TLS is needed, perform a TLS handshake.
-    async fn connect_socket(&self) -> anyhow::Result<Stream> {
-        let stream = TcpStream::connect(&self.address).await?;
-        if self.url.scheme() == "https" {
-            return Ok(self.tls(stream).await.map(Stream::Tls)?);
+    /// The random number generator of one worker.
+    pub fn worker_rng(&self, worker_id: usize) -> Pcg64Si {
+        Pcg64Si::seed_from_u64(self.seed.wrapping_add(worker_id as u64))
+    }
+
+    /// Pauses or resumes every worker.
+    pub fn set_paused(&self, paused: bool) {
+        let was_paused = self.paused.send_replace(paused);
+        {
+            let mut paused_time = self.paused_time.lock().unwrap_or_else(|e| e.into_inner());
+            match (was_paused, paused) {
+                (false, true) => paused_time.since = Some(Instant::now()),
+                (true, false) => {
+                    if let Some(since) = paused_time.since.take() {
+                        paused_time.total += since.elapsed();
+                    }
+                }
+                _ => {}
+            }
+        }
+        if was_paused && !paused {
+            // The slots missed while paused were skipped on purpose, they are not lag
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.restart();
+            }
         }
-        Ok(Stream::Tcp(stream))
     }
 
-    pub async fn tls(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>, native_tls::Error> {
-        let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
-        let Some(domain) = self.url.host_str() else {
-            unreachable!(
-                "If the URL has no host, it's not a valid URL. And the check must have failed before."
-            );
-        };
-        let stream = connector.connect(domain, stream).await?;
-        Ok(stream)
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    fn paused_time(&self) -> Duration {
+        self.paused_time
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get()
+    }
+
+    /// A clock starting now that stands still while the run is paused.
+    pub fn active_clock(&self) -> ActiveClock<'_> {
+        ActiveClock {
+            work_instance: self,
+            start: Instant::now(),
+            paused_before: self.paused_time(),
+        }
+    }
+
+    /// Sleeps until `duration` has passed with the run not paused.
+    pub async fn sleep_unpaused(&self, duration: Duration) {
+        let clock = self.active_clock();
+        let mut paused = self.paused.subscribe();
+        loop {
+            let remaining = duration.saturating_sub(clock.elapsed());
+            if remaining.is_zero() {
+                return;
+            }
+            if *paused.borrow_and_update() {
+                let _ = paused.wait_for(|paused| !*paused).await;
+                continue;
+            }
+            tokio::time::sleep(remaining).await;
+        }
+    }
+
+    pub async fn build_request(
+        &self,
+        endpoint: &Endpoint,
+    ) -> Result<http::Request<Full<Bytes>>, http::Error> {
+        let builder = self.request_builder(endpoint, path_and_query(&endpoint.url));
+        with_body(
+            builder,
+            endpoint,
+            endpoint.mode.body.as_ref().map(|spec| spec.body.clone()),
+        )
     }
 
-    pub async fn build_request(&self) -> Result<http::Request<Full<Bytes>>, http::Error> {
-        // Get path and query from URL
-        let path = self.url.path();
-        let path_and_query = if let Some(query) = self.url.query() {
-            format!("{}?{}", path, query)
+    /// Builds a request with the variables of the template evaluated anew.
+    pub fn render_request(
+        &self,
+        endpoint: &Endpoint,
+        template: &RequestTemplate,
+        rng: &mut Pcg64Si,
+        row: Option<&Row>,
+        session: Option<&HashMap<String, String>>,
+    ) -> Result<http::Request<Full<Bytes>>, http::Error> {
+        let rendered = template.render(rng, row, session);
+        let mut builder = self.request_builder(
+            endpoint,
+            rendered
+                .path_and_query
+                .as_deref()
+                .unwrap_or(path_and_query(&endpoint.url)),
+        );
+        for (name, value) in rendered.headers {
+            // Feed and session values can hold anything, a line break must not end up on the wire
+            builder = builder.header(name, http::HeaderValue::try_from(value)?);
+        }
+        let body = rendered
+            .body
+            .or_else(|| endpoint.mode.body.as_ref().map(|spec| spec.body.clone()));
+        with_body(builder, endpoint, body)
+    }
+
+    /// Method, URI and headers of a request.
+    fn request_builder(&self, endpoint: &Endpoint, path_and_query: &str) -> http::request::Builder {
+        let origin = &self.origins[endpoint.origin];
+        let version = origin.connection_pool.version();
+        let url = &endpoint.url;
+        let builder = http::Request::builder()
+            .method(endpoint.mode.method())
+            .version(version);
+        let forwarded = origin.forwards_through_proxy();
+        let mut builder = if version == http::Version::HTTP_11 && !forwarded {
+            builder.uri(path_and_query)
         } else {
-            path.to_string()
+            // HTTP/2 and HTTP/3 carry the scheme and authority as pseudo-headers instead of a Host header,
+            // a proxy forwarding plain HTTP/1.1 needs the absolute-form to know where to go
+            builder.uri(format!(
+                "{}{}",
+                &url[..url::Position::BeforePath],
+                path_and_query
+            ))
         };
 
-        let mut builder = http::Request::builder()
-            .uri(path_and_query)
-            .method(self.mode.method())
-            .version(http::Version::HTTP_11);
-
         // Add Host header if not already present
-        if let Some(host) = self.url.host_str() {
-            let host_value = if let Some(port) = self.url.port() {
+        if version == http::Version::HTTP_11
+            && let Some(host) = url.host_str()
+        {
+            let host_value = if let Some(port) = url.port() {
                 format!("{}:{}", host, port)
             } else {
                 host.to_string()
@@ -132,67 +557,153 @@ impl WorkInstance {
             builder = builder.header("Host", host_value);
         }
 
-        for header in &self.header_map {
+        if forwarded && let Some(proxy) = &origin.proxy {
+            for header in &proxy.headers {
+                builder = builder.header(header.0, header.1);
+            }
+        }
+
+        for header in &endpoint.header_map {
             builder = builder.header(header.0, header.1);
         }
+        builder
+    }
 
-        match &self.mode {
-            WorkMode::Get => builder.body(Full::new(Bytes::new())),
-            WorkMode::Post(spec) => {
-                builder = builder.header("Content-Length", spec.body.len().to_string());
-                if let Some(content_type) = &spec.content_type {
-                    builder = builder.header("Content-Type", content_type.as_str());
+    /// Sends the request, retrying on connection errors, and records the outcome.
+    ///
+    /// Returns the response head and body, `None` if there was no response.
+    ///
+    /// `intended_start` is the time the request was scheduled for in constant-rate mode. Latency measured from it
+    /// is not hidden when the server stalls and delays the send itself (coordinated omission). Requests that fail
+    /// are part of it too, up to the time they failed.
+    pub async fn send(
+        &self,
+        endpoint: &Endpoint,
+        request: http::Request<Full<Bytes>>,
+        intended_start: Option<Instant>,
+    ) -> Option<(http::response::Parts, Bytes)> {
+        let outcome = match self.timeout {
+            Some(timeout) => {
+                tokio::time::timeout(timeout, self.try_send(endpoint, &request, intended_start))
+                    .await
+                    .unwrap_or(Err(FailureKind::Timeout))
+            }
+            None => self.try_send(endpoint, &request, intended_start).await,
+        };
+        match outcome {
+            Ok(response) => Some(response),
+            Err(kind) => {
+                self.record_failure(endpoint, kind);
+                if let Some(intended) = intended_start {
+                    let corrected = intended.elapsed();
+                    self.latency.record_failure(corrected);
+                    if let Some(stats) = &endpoint.stats {
+                        stats.latency.record_failure(corrected);
+                    }
                 }
-                builder.body(Full::new(spec.body.clone()))
+                None
             }
         }
     }
 
-    /// Determines the ClientResponseCodeType from an HTTP status code
-    fn status_to_code_type(status: StatusCode) -> ClientResponseCodeType {
-        match status.as_u16() {
-            200..=299 => ClientResponseCodeType::Code2,
-            300..=399 => ClientResponseCodeType::Code3,
-            400..=499 => ClientResponseCodeType::Code4,
-            500..=599 => ClientResponseCodeType::Code5,
-            _ => ClientResponseCodeType::Failure,
+    /// Counts a request that got no response, for the run and its endpoint.
+    pub fn record_failure(&self, endpoint: &Endpoint, kind: FailureKind) {
+        self.request_counter.record_failure(kind);
+        if let Some(stats) = &endpoint.stats {
+            stats.counter.record_failure(kind);
         }
     }
 
-    /// Initializes the worker state by connecting to the server and performing a TLS handshake if needed.
-    pub async fn connect(&self) -> anyhow::Result<Http1Conn> {
-        let stream = self.connect_socket().await?;
-        let send_request = stream.handshake_http1(false).await?;
-        Ok(send_request)
-    }
-
-    pub async fn send(
+    /// Sends the request and records the response, or returns why there was none.
+    async fn try_send(
         &self,
-        request: http::Request<Full<Bytes>>,
-    ) {
+        endpoint: &Endpoint,
+        request: &http::Request<Full<Bytes>>,
+        intended_start: Option<Instant>,
+    ) -> Result<(http::response::Parts, Bytes), FailureKind> {
         const MAX_RETRIES: usize = 10;
         let mut retries = 0;
+        let origin = &self.origins[endpoint.origin];
+        // Started once, failed attempts and the backoff between them are part of the latency
+        let start = Instant::now();
 
         loop {
-            let Ok(mut conn) = self.connection_pool.get_or_connect(self).await else {
-                self.request_counter.inc(ClientResponseCodeType::Failure);
-                return 
-            };
-            
+            let (mut conn, connect) = origin
+                .connection_pool
+                .get_or_connect(origin)
+                .await
+                .map_err(|e| e.kind())?;
+
             match conn.send_request(request.clone()).await {
                 Ok(response) => {
-                    let status = response.status();
-                    let code_type = WorkInstance::status_to_code_type(status);
-                    self.request_counter.inc(code_type);
+                    let ttfb = start.elapsed();
+                    let (parts, body) = response.into_parts();
                     // Consume the response body to free up the connection for reuse
-                    let _ = response.collect().await;
-                    return 
+                    let body = if !endpoint.mode.expects_body() {
+                        Bytes::new()
+                    } else {
+                        match body.read().await {
+                            Ok(body) => body,
+                            Err(e) => {
+                                origin.connection_pool.discard(conn, &e).await;
+                                return Err(e.kind());
+                            }
+                        }
+                    };
+                    origin.connection_pool.release(conn);
+                    if parts.status == http::StatusCode::PROXY_AUTHENTICATION_REQUIRED
+                        && origin.forwards_through_proxy()
+                        && let Some(proxy) = &origin.proxy
+                    {
+                        proxy.stats.record_refusal(parts.status);
+                    }
+                    self.request_counter.record_bytes(
+                        request_size(request),
+                        head_size(&parts.headers) + body.len() as u64,
+                    );
+                    let timing = RequestTiming {
+                        connect,
+                        ttfb,
+                        full: start.elapsed(),
+                        corrected: intended_start.map(|intended| intended.elapsed()),
+                    };
+                    self.latency.record(&timing);
+                    if let Some(stats) = &endpoint.stats {
+                        stats.latency.record(&timing);
+                    }
+                    let check = self
+                        .checks
+                        .as_ref()
+                        .map_or(Ok(()), |checks| checks.check(&parts, &body, timing.full));
+                    match check {
+                        Ok(()) => {
+                            self.request_counter.record_status(parts.status);
+                            if let Some(stats) = &endpoint.stats {
+                                stats.counter.record_status(parts.status);
+                            }
+                        }
+                        Err(failure) => {
+                            self.request_counter.record_check_failure(failure.kind);
+                            if let Some(stats) = &endpoint.stats {
+                                stats.counter.record_check_failure(failure.kind);
+                            }
+                            if let Some(samples) = self
+                                .checks
+                                .as_ref()
+                                .and_then(|checks| checks.samples.as_ref())
+                            {
+                                // A failed save is kept by the samples and told at the end of the run
+                                let _ = samples.save(request, &parts, &body, &failure).await;
+                            }
+                        }
+                    }
+                    return Ok((parts, body));
                 }
-                Err(_) => {
+                Err(e) => {
+                    origin.connection_pool.discard(conn, &e).await;
                     retries += 1;
                     if retries >= MAX_RETRIES {
-                        self.request_counter.inc(ClientResponseCodeType::Failure);
-                        return 
+                        return Err(e.kind());
                     }
                     tokio::time::sleep(Duration::from_millis(2u64.pow(retries as u32))).await;
                 }
@@ -201,17 +712,382 @@ impl WorkInstance {
     }
 }
 
+impl Origin {
+    /// Connect to the socket, if TLS is needed, perform a TLS handshake.
+    async fn connect_socket(&self) -> Result<Stream, ConnectError> {
+        let stream = match &self.proxy {
+            Some(proxy) if self.forwards_through_proxy() => proxy.connect().await?,
+            Some(proxy) => proxy.tunnel(&self.url, self.address).await?,
+            None => TcpStream::connect(&self.address)
+                .await
+                .map_err(ConnectError::Tcp)?,
+        };
+        if self.url.scheme() == "https" {
+            return self
+                .tls(stream)
+                .await
+                .map(Stream::Tls)
+                .map_err(ConnectError::Tls);
+        }
+        Ok(Stream::Tcp(stream))
+    }
+
+    /// `true` if requests are sent to the proxy in absolute-form instead of through a tunnel.
+    ///
+    /// Only plain HTTP/1.1 through an HTTP proxy is forwarded, h2c has to be tunneled like HTTPS.
+    pub fn forwards_through_proxy(&self) -> bool {
+        self.proxy
+            .as_ref()
+            .is_some_and(|proxy| proxy.kind == ProxyKind::Http)
+            && self.url.scheme() == "http"
+            && matches!(self.connection_pool, ConnectionPool::Http1(_))
+    }
+
+    pub async fn tls(&self, stream: TcpStream) -> Result<TlsStream, tls::HandshakeError> {
+        let alpn = if self.connection_pool.is_http2() {
+            Alpn::Http2
+        } else {
+            Alpn::None
+        };
+        let Some(domain) = self.url.host_str() else {
+            unreachable!(
+                "If the URL has no host, it's not a valid URL. And the check must have failed before."
+            );
+        };
+        self.tls.connect(domain, stream, alpn).await
+    }
+
+    /// Initializes the worker state by connecting to the server and performing a TLS handshake if needed.
+    pub async fn connect(&self) -> Result<Http1Conn, ConnectError> {
+        let stream = self.connect_socket().await?;
+        let send_request = stream
+            .handshake_http1(false)
+            .await
+            .map_err(ConnectError::Handshake)?;
+        Ok(send_request)
+    }
+
+    /// Connects and performs an HTTP/2 handshake, the connection then carries many concurrent streams.
+    ///
+    /// With `upgrade` the connection starts as HTTP/1.1 and switches to h2c after an `Upgrade: h2c` request.
+    pub async fn connect_http2(
+        &self,
+        upgrade: bool,
+    ) -> Result<(Http2Conn, JoinHandle<()>), ConnectError> {
+        let stream = self.connect_socket().await?;
+        if !upgrade {
+            return stream
+                .handshake_http2()
+                .await
+                .map_err(ConnectError::Http2Handshake);
+        }
+        let mut send_request = stream
+            .handshake_http1(true)
+            .await
+            .map_err(ConnectError::Handshake)?;
+        let response = send_request
+            .send_request(
+                self.build_upgrade_request()
+                    .map_err(ConnectError::UpgradeRequest)?,
+            )
+            .await
+            .map_err(ConnectError::Handshake)?;
+        if response.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+            return Err(ConnectError::UpgradeRefused(response.status()));
+        }
+        let upgraded = hyper::upgrade::on(response)
+            .await
+            .map_err(ConnectError::Handshake)?;
+        // The server answers the upgrade request on stream 1, our own streams start at 3
+        http2::handshake(TokioIo::new(upgraded), 3)
+            .await
+            .map_err(ConnectError::Http2Handshake)
+    }
+
+    /// `OPTIONS` request asking to switch the connection to h2c, its response is not counted.
+    fn build_upgrade_request(&self) -> Result<http::Request<Full<Bytes>>, http::Error> {
+        let host = match (self.url.host_str(), self.url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => String::new(),
+        };
+        http::Request::builder()
+            .method(http::Method::OPTIONS)
+            .uri(self.url.path())
+            .version(http::Version::HTTP_11)
+            .header("Host", host)
+            .header("Connection", "Upgrade, HTTP2-Settings")
+            .header("Upgrade", "h2c")
+            .header("HTTP2-Settings", http2::UPGRADE_SETTINGS)
+            .body(Full::new(Bytes::new()))
+    }
+}
+
+fn path_and_query(url: &Url) -> &str {
+    &url[url::Position::BeforePath..url::Position::AfterQuery]
+}
+
+/// Adds the body, with its length and content type, to a request.
+fn with_body(
+    mut builder: http::request::Builder,
+    endpoint: &Endpoint,
+    body: Option<Bytes>,
+) -> Result<http::Request<Full<Bytes>>, http::Error> {
+    match body {
+        None => builder.body(Full::new(Bytes::new())),
+        Some(body) => {
+            builder = builder.header("Content-Length", body.len().to_string());
+            if let Some(content_type) = endpoint
+                .mode
+                .body
+                .as_ref()
+                .and_then(|spec| spec.content_type.as_ref())
+            {
+                builder = builder.header("Content-Type", content_type.as_str());
+            }
+            builder.body(Full::new(body))
+        }
+    }
+}
+
+/// Asks the server whether it speaks HTTP/2 through TLS ALPN. Plain HTTP URLs always get HTTP/1.1.
+pub async fn negotiate_http_version(
+    url: &Url,
+    address: SocketAddr,
+    proxy: Option<&Proxy>,
+    tls: &TlsConfig,
+) -> Result<HttpVersion, ConnectError> {
+    let Some(domain) = url.host_str().filter(|_| url.scheme() == "https") else {
+        return Ok(HttpVersion::Http1);
+    };
+    let stream = match proxy {
+        Some(proxy) => proxy.tunnel(url, address).await?,
+        None => TcpStream::connect(address)
+            .await
+            .map_err(ConnectError::Tcp)?,
+    };
+    let stream = tls
+        .connect(domain, stream, Alpn::Negotiate)
+        .await
+        .map_err(ConnectError::Tls)?;
+    let protocol = tls::negotiated_alpn(&stream).map_err(ConnectError::Tls)?;
+    Ok(match protocol.as_deref() {
+        Some(b"h2") => HttpVersion::Http2,
+        _ => HttpVersion::Http1,
+    })
+}
+
+/// Maps an I/O error to the failure it stands for.
+fn classify_io_error(e: &std::io::Error) -> FailureKind {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof => FailureKind::Reset,
+        ErrorKind::TimedOut => FailureKind::Timeout,
+        ErrorKind::ConnectionRefused
+        | ErrorKind::AddrNotAvailable
+        | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::NetworkDown => FailureKind::Connect,
+        ErrorKind::InvalidData => FailureKind::Protocol,
+        _ => FailureKind::Other,
+    }
+}
+
+/// Maps a hyper error to the failure it stands for, looking at the underlying I/O error if there is one.
+fn classify_hyper_error(e: &hyper::Error) -> FailureKind {
+    if e.is_timeout() {
+        return FailureKind::Timeout;
+    }
+    if e.is_parse() || e.is_parse_status() {
+        return FailureKind::Protocol;
+    }
+    let mut source = std::error::Error::source(e);
+    while let Some(inner) = source {
+        if let Some(io) = inner.downcast_ref::<std::io::Error>() {
+            return classify_io_error(io);
+        }
+        source = inner.source();
+    }
+    if e.is_incomplete_message() || e.is_closed() || e.is_canceled() || e.is_body_write_aborted() {
+        return FailureKind::Reset;
+    }
+    FailureKind::Other
+}
+
+/// Maps an HTTP/2 error to the failure it stands for.
+fn classify_h2_error(e: &h2::Error) -> FailureKind {
+    if let Some(io) = e.get_io() {
+        return classify_io_error(io);
+    }
+    if e.is_go_away() || e.is_reset() {
+        return FailureKind::Reset;
+    }
+    if e.is_library() {
+        return FailureKind::Protocol;
+    }
+    FailureKind::Other
+}
+
+/// Approximate size of the header block on the wire, including the request or status line.
+fn head_size(headers: &HeaderMap) -> u64 {
+    const START_LINE: u64 = 16;
+    headers
+        .iter()
+        // "name: value\r\n"
+        .map(|(name, value)| (name.as_str().len() + value.len() + 4) as u64)
+        .sum::<u64>()
+        + START_LINE
+        + 2
+}
+
+/// Approximate size of a request on the wire.
+fn request_size(request: &http::Request<Full<Bytes>>) -> u64 {
+    let body_len = request.body().size_hint().exact().unwrap_or(0);
+    head_size(request.headers()) + uri_len(request.uri()) + body_len
+}
+
+/// Length of the URI as it is displayed, without formatting it.
+fn uri_len(uri: &http::Uri) -> u64 {
+    let scheme = uri
+        .scheme_str()
+        .map_or(0, |scheme| scheme.len() + "://".len());
+    let authority = uri
+        .authority()
+        .map_or(0, |authority| authority.as_str().len());
+    let query = uri.query().map_or(0, |query| "?".len() + query.len());
+    (scheme + authority + uri.path().len() + query) as u64
+}
+
 pub async fn request_loop(
     work_instance: Arc<WorkInstance>,
+    worker_id: usize,
     shutdown_signal: &mut tokio::sync::watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
-    let request = work_instance.build_request().await?;
+    let mut requests = Vec::with_capacity(work_instance.mix.endpoints.len());
+    for endpoint in &work_instance.mix.endpoints {
+        requests.push(work_instance.build_request(endpoint).await?);
+    }
+    let mut rng = work_instance.worker_rng(worker_id);
+    let mut feed = work_instance
+        .feeder
+        .as_ref()
+        .map(|feeder| feeder.cursor(worker_id, work_instance.worker_count));
+    let mut session = work_instance.scenario.as_ref().map(Session::new);
+    // One receiver for the whole loop rather than one per request
+    let mut paused = work_instance.paused.subscribe();
     loop {
         tokio::select! {
             _ = shutdown_signal.changed() => {
                 break Ok(());
             }
-            _ = work_instance.send(request.clone()) => {}
+            _ = paused.wait_for(|paused| !*paused) => {}
+        }
+        if let Some(profile) = &work_instance.profile {
+            tokio::select! {
+                _ = shutdown_signal.changed() => {
+                    break Ok(());
+                }
+                _ = profile.wait_until_active(worker_id) => {}
+            }
+        }
+        let ticket = match &work_instance.budget {
+            Some(budget) => match budget.try_claim() {
+                Some(ticket) => Some(ticket),
+                None => break Ok(()),
+            },
+            None => None,
+        };
+        let mut intended_start = None;
+        if let Some(rate_limiter) = &work_instance.rate_limiter {
+            let send_time = tokio::select! {
+                _ = shutdown_signal.changed() => {
+                    break Ok(());
+                }
+                send_time = rate_limiter.wait_for_slot() => send_time,
+            };
+            rate_limiter.record_lag(send_time.elapsed());
+            intended_start = Some(send_time);
+        }
+        let (index, endpoint) = match &session {
+            Some(session) => {
+                let index = session.endpoint();
+                (index, &work_instance.mix.endpoints[index])
+            }
+            None => work_instance.mix.pick(&mut rng),
+        };
+        let request = match &endpoint.template {
+            Some(template) => {
+                let row = match &mut feed {
+                    Some(feed) => feed.next_row().await.map(Some),
+                    None => Ok(None),
+                };
+                let vars = session.as_ref().map(|session| &session.vars);
+                match row {
+                    Ok(row) => work_instance
+                        .render_request(endpoint, template, &mut rng, row.as_ref(), vars)
+                        .map_err(|_| FailureKind::Request),
+                    Err(e) => {
+                        if let Some(feeder) = &work_instance.feeder {
+                            feeder.record_error(&e);
+                        }
+                        Err(FailureKind::Feed)
+                    }
+                }
+            }
+            None => Ok(requests[index].clone()),
+        };
+        let response = match request {
+            Ok(request) => tokio::select! {
+                _ = shutdown_signal.changed() => {
+                    break Ok(());
+                }
+                response = work_instance.send(endpoint, request, intended_start) => response,
+            },
+            Err(kind) => {
+                work_instance.record_failure(endpoint, kind);
+                None
+            }
+        };
+        if let Some(ticket) = ticket {
+            ticket.complete();
+        }
+        if let Some(session) = &mut session {
+            let (think_time, missing) = session.complete(response.as_ref());
+            if let Some(stats) = &endpoint.stats {
+                stats.record_missing_vars(missing);
+            }
+            if let Some(think_time) = think_time {
+                tokio::select! {
+                    _ = shutdown_signal.changed() => {
+                        break Ok(());
+                    }
+                    _ = work_instance.sleep_unpaused(think_time) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uri_len() -> Result<(), http::uri::InvalidUri> {
+        let uris = [
+            "/",
+            "/search?q=a%20b",
+            "http://example.com",
+            "https://example.com:8443/a/b?c=d",
+            "*",
+        ];
+        for uri in uris {
+            let uri: http::Uri = uri.parse()?;
+            assert_eq!(uri_len(&uri), uri.to_string().len() as u64);
         }
+        Ok(())
     }
 }
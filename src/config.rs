@@ -0,0 +1,269 @@
+use crate::opts::Opts;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// IDs of the options that only make sense on the command line.
+const CLI_ONLY: [&str; 2] = ["config", "print_config"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid config file {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Unknown option {0:?} in the config file")]
+    UnknownOption(String),
+
+    #[error(
+        "Invalid value for {0:?} in the config file, expected a string, number, boolean or an array of them"
+    )]
+    InvalidValue(String),
+}
+
+/// Parses the options from the command line and the `--config` file it names.
+///
+/// Exits with a usage message on invalid arguments, like [`clap::Parser::parse`]. Also returns the
+/// effective configuration, which `--print-config` dumps.
+pub fn parse_opts() -> Result<(Opts, toml::Table), ConfigError> {
+    let cli = std::env::args_os().collect::<Vec<_>>();
+    // A first pass only to find the config file and what the command line sets itself
+    let relaxed = Opts::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&cli)
+        .unwrap_or_else(|e| e.exit());
+    let file = match relaxed.get_one::<PathBuf>("config") {
+        Some(path) => read_config_file(path)?,
+        None => toml::Table::new(),
+    };
+    let matches = merge(cli, &file, &relaxed)?.unwrap_or_else(|e| e.exit());
+    let opts = Opts::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    Ok((opts, effective_config(&matches)))
+}
+
+/// Reads a configuration file, a TOML table with one key per option.
+///
+/// Keys are the field names of [`Opts`] or their long flags, `-` and `_` alike. Options that can be
+/// given several times take an array, flags take a boolean and everything else a string or a number.
+///
+/// ```toml
+/// url = "https://example.com/api"
+/// concurrent = 64
+/// max_time = "5m"
+/// header = ["Authorization: Bearer secret", "X-Request-Source: ubw"]
+/// http_version = "auto"
+/// tui = true
+/// ```
+///
+/// Options given on the command line replace the ones from the file, lists included.
+pub fn read_config_file(path: &Path) -> Result<toml::Table, ConfigError> {
+    Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// Parses the command line with the file's options in front of it, leaving out the ones the command line sets
+/// or conflicts with.
+fn merge(
+    cli: Vec<OsString>,
+    file: &toml::Table,
+    relaxed: &ArgMatches,
+) -> Result<Result<ArgMatches, clap::Error>, ConfigError> {
+    let mut command = Opts::command();
+    command.build();
+    let mut cli = cli.into_iter();
+    let mut args = cli.next().into_iter().collect::<Vec<_>>();
+    for (key, value) in file {
+        let normalized = key.replace('-', "_");
+        let arg = command
+            .get_arguments()
+            .filter(|arg| !CLI_ONLY.contains(&arg.get_id().as_str()))
+            .find(|arg| {
+                arg.get_id().as_str() == normalized
+                    || arg
+                        .get_long()
+                        .is_some_and(|long| long.replace('-', "_") == normalized)
+            })
+            .ok_or_else(|| ConfigError::UnknownOption(key.clone()))?;
+        // The command line wins, also over file keys that cannot be used together with what it sets
+        let on_command_line = |id: &str| relaxed.value_source(id) == Some(ValueSource::CommandLine);
+        let conflicts_with_command_line = command.get_arguments().any(|other| {
+            on_command_line(other.get_id().as_str())
+                && (command.get_arg_conflicts_with(arg).contains(&other)
+                    || command.get_arg_conflicts_with(other).contains(&arg))
+        });
+        if on_command_line(arg.get_id().as_str()) || conflicts_with_command_line {
+            continue;
+        }
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => format!("--{}", long),
+            (None, Some(short)) => format!("-{}", short),
+            (None, None) => return Err(ConfigError::UnknownOption(key.clone())),
+        };
+        let invalid = || ConfigError::InvalidValue(key.clone());
+        if !arg.get_action().takes_values() {
+            match value {
+                toml::Value::Boolean(true) => args.push(flag.into()),
+                toml::Value::Boolean(false) => {}
+                _ => return Err(invalid()),
+            }
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                _ => return Err(invalid()),
+            };
+            // The `=` keeps values that start with a dash from being taken for flags
+            args.push(format!("{}={}", flag, value).into());
+        }
+    }
+    args.extend(cli);
+    Ok(command.try_get_matches_from(args))
+}
+
+/// Every option set by the file or the command line after merging, in the config file format.
+///
+/// Defaults are left out, the output reads back as a config file giving the same options.
+fn effective_config(matches: &ArgMatches) -> toml::Table {
+    let mut config = toml::Table::new();
+    for arg in Opts::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        if CLI_ONLY.contains(&id)
+            || matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+        {
+            continue;
+        }
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        if !arg.get_action().takes_values() {
+            config.insert(id.to_string(), toml::Value::Boolean(matches.get_flag(id)));
+            continue;
+        }
+        let Some(raw) = matches.get_raw(id) else {
+            continue;
+        };
+        let integer = takes_integer(arg);
+        let mut values = raw
+            .map(|value| {
+                let value = value.to_string_lossy();
+                match value.parse::<i64>() {
+                    Ok(i) if integer => toml::Value::Integer(i),
+                    _ => toml::Value::String(value.into_owned()),
+                }
+            })
+            .collect::<Vec<_>>();
+        let value = if matches!(arg.get_action(), ArgAction::Append) {
+            toml::Value::Array(values)
+        } else if let Some(value) = values.pop() {
+            value
+        } else {
+            continue;
+        };
+        config.insert(id.to_string(), value);
+    }
+    config
+}
+
+/// `true` if the option parses its value as an integer, every other value is written back as a string.
+fn takes_integer(arg: &clap::Arg) -> bool {
+    use std::any::TypeId;
+    use std::num::{NonZeroU32, NonZeroU64};
+    let integers = [
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<NonZeroU32>(),
+        TypeId::of::<NonZeroU64>(),
+    ];
+    let type_id = arg.get_value_parser().type_id();
+    integers.iter().any(|integer| type_id == *integer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cli: &[&str], file: &str) -> Result<(Opts, toml::Table), Box<dyn std::error::Error>> {
+        let cli = cli.iter().map(OsString::from).collect::<Vec<_>>();
+        let relaxed = Opts::command()
+            .ignore_errors(true)
+            .try_get_matches_from(&cli)?;
+        let matches = merge(cli, &toml::from_str(file)?, &relaxed)??;
+        Ok((
+            Opts::from_arg_matches(&matches)?,
+            effective_config(&matches),
+        ))
+    }
+
+    #[test]
+    fn test_command_line_overrides_file() -> Result<(), Box<dyn std::error::Error>> {
+        let file = r#"
+            url = "http://localhost:8080/"
+            concurrent = 4
+            header = ["X-A: 1", "X-B: 2"]
+            max-time = "10s"
+            data = "-1"
+            tui = true
+        "#;
+        let (opts, config) = parse(&["ubw", "-c", "8", "-H", "X-C: 3", "--data", "007"], file)?;
+        assert_eq!(opts.url.as_str(), "http://localhost:8080/");
+        assert_eq!(opts.concurrent, 8);
+        assert_eq!(opts.header.len(), 1);
+        assert_eq!(
+            opts.max_time.map(|t| t.to_string()),
+            Some("10s".to_string())
+        );
+        assert_eq!(opts.body_string.as_deref(), Some("007"));
+        assert!(opts.dashboard);
+        assert_eq!(config.get("concurrent"), Some(&toml::Value::Integer(8)));
+        assert_eq!(
+            config.get("body_string"),
+            Some(&toml::Value::String("007".to_string()))
+        );
+        assert!(!config.contains_key("config"));
+        let (from_file, _) = parse(&["ubw"], file)?;
+        assert_eq!(from_file.body_string.as_deref(), Some("-1"));
+
+        // The effective configuration reads back as the same options
+        let (again, _) = parse(&["ubw"], &toml::to_string(&config)?)?;
+        assert_eq!(again.concurrent, 8);
+        assert_eq!(again.header, opts.header);
+        assert_eq!(again.body_string, opts.body_string);
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_line_overrides_conflicting_key() -> Result<(), Box<dyn std::error::Error>> {
+        let file = r#"
+            url = "http://localhost/"
+            rate = 100
+        "#;
+        let (opts, config) = parse(&["ubw", "--profile", "hold:10:1s"], file)?;
+        assert!(opts.profile.is_some());
+        assert_eq!(opts.rate, None);
+        assert!(!config.contains_key("rate"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_option() {
+        assert!(matches!(
+            parse(&["ubw"], "concurency = 4")
+                .err()
+                .and_then(|e| e.downcast::<ConfigError>().ok())
+                .as_deref(),
+            Some(ConfigError::UnknownOption(_))
+        ));
+    }
+}
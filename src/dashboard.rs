@@ -0,0 +1,364 @@
+use crate::client::WorkInstance;
+use crate::latency::{format_micros, format_percentiles};
+use ratatui::backend::Backend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{BarChart, Block, Gauge, List, Paragraph, Sparkline};
+use ratatui::{Frame, Terminal};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the dashboard is redrawn and the keyboard is checked.
+const REFRESH: Duration = Duration::from_millis(100);
+
+/// Takes over the terminal and draws the run until it shuts down.
+///
+/// `p` pauses, `r` resumes, space toggles, `q`, `Esc` or `Ctrl+C` shut the run down gracefully.
+/// If the terminal cannot be used the run is shut down as well.
+pub async fn run(
+    work_instance: Arc<WorkInstance>,
+    shutdown_tx: tokio::sync::watch::Sender<bool>,
+    time_limit: Option<Duration>,
+) -> std::io::Result<()> {
+    let result = match ratatui::try_init() {
+        Ok(mut terminal) => {
+            let result = event_loop(&mut terminal, &work_instance, &shutdown_tx, time_limit).await;
+            ratatui::try_restore().and(result)
+        }
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = shutdown_tx.send(true);
+    }
+    result
+}
+
+async fn event_loop<B: Backend>(
+    terminal: &mut Terminal<B>,
+    work_instance: &WorkInstance,
+    shutdown_tx: &tokio::sync::watch::Sender<bool>,
+    time_limit: Option<Duration>,
+) -> std::io::Result<()> {
+    let clock = work_instance.active_clock();
+    let mut shutdown_rx = shutdown_tx.subscribe();
+    let mut ticker = tokio::time::interval(REFRESH);
+    loop {
+        tokio::select! {
+            biased;
+            // Looks at the current value too, the run may have ended before the terminal was ready
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown) => {
+                return Ok(());
+            }
+            _ = ticker.tick() => {}
+        }
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()?
+                && handle_key(key, work_instance, shutdown_tx)
+            {
+                return Ok(());
+            }
+        }
+        terminal.draw(|frame| draw(frame, work_instance, clock.elapsed(), time_limit))?;
+    }
+}
+
+/// Pauses or resumes the run, or shuts it down. Returns whether the dashboard should close.
+fn handle_key(
+    key: KeyEvent,
+    work_instance: &WorkInstance,
+    shutdown_tx: &tokio::sync::watch::Sender<bool>,
+) -> bool {
+    if key.kind != KeyEventKind::Press {
+        return false;
+    }
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc => {
+            let _ = shutdown_tx.send(true);
+            return true;
+        }
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            let _ = shutdown_tx.send(true);
+            return true;
+        }
+        KeyCode::Char('p') => work_instance.set_paused(true),
+        KeyCode::Char('r') => work_instance.set_paused(false),
+        KeyCode::Char(' ') => work_instance.set_paused(!work_instance.is_paused()),
+        _ => {}
+    }
+    false
+}
+
+/// How far the run is, as a ratio and a label.
+fn progress(
+    work_instance: &WorkInstance,
+    elapsed: Duration,
+    time_limit: Option<Duration>,
+) -> (f64, String) {
+    if let Some(budget) = &work_instance.budget {
+        let ratio = budget.completed() as f64 / budget.total().max(1) as f64;
+        return (
+            ratio,
+            format!("{} / {} requests", budget.completed(), budget.total()),
+        );
+    }
+    let limit = time_limit.or_else(|| {
+        work_instance
+            .profile
+            .as_ref()
+            .map(|profile| profile.profile.duration())
+    });
+    match limit {
+        Some(limit) => (
+            elapsed.as_secs_f64() / limit.as_secs_f64().max(f64::EPSILON),
+            format!("{}s / {}s", elapsed.as_secs(), limit.as_secs()),
+        ),
+        None => (0.0, format!("{}s, no limit", elapsed.as_secs())),
+    }
+}
+
+fn draw(
+    frame: &mut Frame,
+    work_instance: &WorkInstance,
+    elapsed: Duration,
+    time_limit: Option<Duration>,
+) {
+    let [header, gauge, charts, breakdown, footer] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(6),
+        Constraint::Min(6),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let counter = &work_instance.request_counter;
+    let state = if work_instance.is_paused() {
+        "PAUSED"
+    } else {
+        "RUNNING"
+    };
+    let stage = work_instance
+        .profile
+        .as_ref()
+        .map(|p| format!(" · {}", p.describe_current()))
+        .unwrap_or_default();
+    frame.render_widget(
+        Paragraph::new(format!(
+            "{} · {} · {} requests{}",
+            work_instance.mix.describe(),
+            state,
+            counter.get_total(),
+            stage
+        ))
+        .block(Block::bordered().title("Unlimited Blade Works")),
+        header,
+    );
+
+    let (ratio, label) = progress(work_instance, elapsed, time_limit);
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title("Progress"))
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio.clamp(0.0, 1.0))
+            .label(label),
+        gauge,
+    );
+
+    draw_charts(frame, work_instance, charts);
+    draw_breakdown(frame, work_instance, breakdown);
+
+    frame.render_widget(
+        Paragraph::new("p pause · r resume · space toggle · q quit"),
+        footer,
+    );
+}
+
+fn draw_charts(frame: &mut Frame, work_instance: &WorkInstance, area: Rect) {
+    let [rps_area, latency_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(area);
+    let samples = work_instance
+        .time_series
+        .recent(usize::from(rps_area.width.saturating_sub(2)));
+
+    let rps = samples
+        .iter()
+        .map(|s| s.requests_per_second.round() as u64)
+        .collect::<Vec<_>>();
+    let current_rps = rps.last().copied().unwrap_or_default();
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(format!("Requests/s: {}", current_rps)))
+            .style(Style::default().fg(Color::Green))
+            .data(&rps),
+        rps_area,
+    );
+
+    let p99 = samples.iter().map(|s| s.p99_us).collect::<Vec<_>>();
+    let latency = work_instance.latency.total();
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(format!(
+                "p99 latency: {} · overall {}",
+                format_micros(p99.last().copied().unwrap_or_default()),
+                format_percentiles(&latency.full)
+            )))
+            .style(Style::default().fg(Color::Yellow))
+            .data(&p99),
+        latency_area,
+    );
+}
+
+fn draw_breakdown(frame: &mut Frame, work_instance: &WorkInstance, area: Rect) {
+    let [status_area, error_area] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(area);
+    let counter = &work_instance.request_counter;
+
+    let status_codes = counter
+        .status_codes()
+        .into_iter()
+        .map(|(status, count)| (status.to_string(), count))
+        .collect::<Vec<_>>();
+    let bars = status_codes
+        .iter()
+        .map(|(status, count)| (status.as_str(), *count))
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        BarChart::default()
+            .block(Block::bordered().title("Status codes"))
+            .bar_width(5)
+            .bar_gap(1)
+            .bar_style(Style::default().fg(Color::Blue))
+            .data(&bars),
+        status_area,
+    );
+
+    let errors = counter
+        .failures()
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(kind, count)| format!("{}: {}", kind.name(), count))
+        .chain(
+            counter
+                .check_failures()
+                .into_iter()
+                .filter(|(_, count)| *count > 0)
+                .map(|(kind, count)| format!("check {}: {}", kind.name(), count)),
+        )
+        .collect::<Vec<_>>();
+    frame.render_widget(
+        List::new(errors)
+            .style(Style::default().fg(Color::Red))
+            .block(Block::bordered().title("Errors")),
+        error_area,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::WorkInstanceBuilder;
+    use crate::test_util::TestResult;
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::KeyEventState;
+    use std::num::NonZeroU64;
+
+    async fn work_instance(requests: Option<u64>) -> TestResult<Arc<WorkInstance>> {
+        let mut builder = WorkInstanceBuilder::new("http://127.0.0.1:1/".parse()?)?.quiet(true);
+        if let Some(requests) = requests {
+            builder = builder.requests(NonZeroU64::new(requests).ok_or("zero")?);
+        }
+        Ok(builder.build().await?.work_instance)
+    }
+
+    fn press(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[tokio::test]
+    async fn test_progress() -> TestResult {
+        let work_instance = work_instance(Some(4)).await?;
+        let budget = work_instance.budget.as_ref().ok_or("no budget")?;
+        budget.try_claim().ok_or("budget exhausted")?.complete();
+        // The budget wins over the time limit
+        let (ratio, label) = progress(
+            &work_instance,
+            Duration::from_secs(5),
+            Some(Duration::from_secs(20)),
+        );
+        assert_eq!((ratio, label.as_str()), (0.25, "1 / 4 requests"));
+
+        let work_instance = self::work_instance(None).await?;
+        let (ratio, label) = progress(
+            &work_instance,
+            Duration::from_secs(5),
+            Some(Duration::from_secs(20)),
+        );
+        assert_eq!((ratio, label.as_str()), (0.25, "5s / 20s"));
+        let (ratio, label) = progress(&work_instance, Duration::from_secs(5), None);
+        assert_eq!((ratio, label.as_str()), (0.0, "5s, no limit"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keys_pause_resume_and_quit() -> TestResult {
+        let work_instance = work_instance(None).await?;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+
+        assert!(!handle_key(
+            press(KeyCode::Char('p'), KeyModifiers::NONE),
+            &work_instance,
+            &shutdown_tx
+        ));
+        assert!(work_instance.is_paused());
+        assert!(!handle_key(
+            press(KeyCode::Char(' '), KeyModifiers::NONE),
+            &work_instance,
+            &shutdown_tx
+        ));
+        assert!(!work_instance.is_paused());
+        assert!(!handle_key(
+            press(KeyCode::Char(' '), KeyModifiers::NONE),
+            &work_instance,
+            &shutdown_tx
+        ));
+        assert!(!handle_key(
+            press(KeyCode::Char('r'), KeyModifiers::NONE),
+            &work_instance,
+            &shutdown_tx
+        ));
+        assert!(!work_instance.is_paused());
+        // Only presses count
+        let release = KeyEvent::new_with_kind_and_state(
+            KeyCode::Char('q'),
+            KeyModifiers::NONE,
+            KeyEventKind::Release,
+            KeyEventState::NONE,
+        );
+        assert!(!handle_key(release, &work_instance, &shutdown_tx));
+        assert!(!*shutdown_rx.borrow());
+
+        assert!(handle_key(
+            press(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            &work_instance,
+            &shutdown_tx
+        ));
+        assert!(*shutdown_rx.borrow());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_before_the_event_loop_starts() -> TestResult {
+        let work_instance = work_instance(None).await?;
+        let (shutdown_tx, _) = tokio::sync::watch::channel(false);
+        // A fast run can end while the terminal is still being set up
+        shutdown_tx.send_replace(true);
+        let mut terminal = Terminal::new(TestBackend::new(80, 24))?;
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            event_loop(&mut terminal, &work_instance, &shutdown_tx, None),
+        )
+        .await??;
+        Ok(())
+    }
+}
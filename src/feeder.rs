@@ -0,0 +1,526 @@
+use crate::before_request::read_body_from;
+use crate::pcg64si::Pcg64Si;
+use bytes::{Buf, Bytes};
+use rand::{RngCore, SeedableRng};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// One row of the feed file, column name to value.
+pub type Row = HashMap<String, String>;
+
+/// Rows held back in random order, only files up to this many rows are shuffled uniformly.
+const SHUFFLE_BUFFER: usize = 4096;
+
+/// Format of the feed file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FeedFormat {
+    /// Comma separated values with a header row naming the columns
+    Csv,
+    /// One JSON object per line, its keys are the columns
+    Jsonl,
+}
+
+impl FeedFormat {
+    /// Guesses the format from a file extension, JSONL unless it is `.csv`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => FeedFormat::Csv,
+            _ => FeedFormat::Jsonl,
+        }
+    }
+}
+
+/// Which row a request gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FeedOrder {
+    /// Rows in file order, shared by all workers
+    Sequential,
+    /// Rows in random order, shuffled through a buffer of 4096 rows
+    Random,
+    /// Every worker goes through its own share of the rows, row `i` belongs to worker `i % workers`
+    Partitioned,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FeedError {
+    #[error("Failed to read feed file {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to read feed file as CSV {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("Line {0} of the feed file is not valid JSON {1}")]
+    Json(u64, serde_json::Error),
+
+    #[error("Line {0} of the feed file is not a JSON object")]
+    NotAnObject(u64),
+
+    #[error("The feed file has no rows")]
+    Empty,
+
+    #[error("The feed file has no rows for worker {0}, there are more workers than rows")]
+    EmptyPartition(usize),
+
+    #[error(
+        "Only {rows} of the {workers} workers get a row from the feed file, a partitioned feed needs one for each"
+    )]
+    TooFewRows { rows: usize, workers: usize },
+}
+
+/// Feeds up to this size are read whole with [`read_body_from`], larger ones are streamed from disk.
+const IN_MEMORY_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Rows read ahead in one blocking task, workers only wait for the file when these run out.
+const PREFETCH: usize = 256;
+
+/// Where the rows come from, every pass over the feed starts from here again.
+#[derive(Clone)]
+enum FeedData {
+    InMemory(Bytes),
+    File(PathBuf),
+}
+
+impl FeedData {
+    async fn load(path: &Path) -> Result<Self, FeedError> {
+        if tokio::fs::metadata(path).await?.len() <= IN_MEMORY_LIMIT {
+            Ok(FeedData::InMemory(
+                read_body_from(&path.to_path_buf()).await?,
+            ))
+        } else {
+            Ok(FeedData::File(path.to_path_buf()))
+        }
+    }
+
+    fn reader(&self) -> Result<Box<dyn Read + Send>, FeedError> {
+        Ok(match self {
+            FeedData::InMemory(bytes) => Box::new(bytes.clone().reader()),
+            FeedData::File(path) => Box::new(File::open(path)?),
+        })
+    }
+}
+
+enum Source {
+    Csv(csv::Reader<Box<dyn Read + Send>>, csv::StringRecord),
+    Jsonl(BufReader<Box<dyn Read + Send>>, u64),
+}
+
+/// Reads rows from the feed, blocking, so only ever called from [`read_ahead`].
+struct RowReader {
+    data: FeedData,
+    format: FeedFormat,
+    source: Source,
+}
+
+impl RowReader {
+    fn open(data: FeedData, format: FeedFormat) -> Result<Self, FeedError> {
+        let input = data.reader()?;
+        let source = match format {
+            FeedFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(input);
+                let headers = reader.headers()?.clone();
+                Source::Csv(reader, headers)
+            }
+            FeedFormat::Jsonl => Source::Jsonl(BufReader::new(input), 0),
+        };
+        Ok(Self {
+            data,
+            format,
+            source,
+        })
+    }
+
+    /// The CSV header row, `None` for JSONL where every line may have its own keys.
+    fn columns(&self) -> Option<Vec<String>> {
+        match &self.source {
+            Source::Csv(_, headers) => Some(headers.iter().map(str::to_string).collect()),
+            Source::Jsonl(..) => None,
+        }
+    }
+
+    /// The next row, `None` at the end of the file.
+    fn next_row(&mut self) -> Result<Option<Row>, FeedError> {
+        match &mut self.source {
+            Source::Csv(reader, headers) => {
+                let mut record = csv::StringRecord::new();
+                if !reader.read_record(&mut record)? {
+                    return Ok(None);
+                }
+                Ok(Some(
+                    headers
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .collect(),
+                ))
+            }
+            Source::Jsonl(reader, line_number) => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    *line_number += 1;
+                    if !line.trim().is_empty() {
+                        break;
+                    }
+                }
+                let value: serde_json::Value =
+                    serde_json::from_str(&line).map_err(|e| FeedError::Json(*line_number, e))?;
+                let serde_json::Value::Object(object) = value else {
+                    return Err(FeedError::NotAnObject(*line_number));
+                };
+                Ok(Some(
+                    object
+                        .into_iter()
+                        .map(|(key, value)| match value {
+                            serde_json::Value::String(s) => (key, s),
+                            value => (key, value.to_string()),
+                        })
+                        .collect(),
+                ))
+            }
+        }
+    }
+
+    /// Starts over at the first row.
+    fn rewind(&mut self) -> Result<(), FeedError> {
+        *self = Self::open(self.data.clone(), self.format)?;
+        Ok(())
+    }
+
+    /// The next row, starting over at the end of the file.
+    fn next_row_wrapping(&mut self) -> Result<Row, FeedError> {
+        if let Some(row) = self.next_row()? {
+            return Ok(row);
+        }
+        self.rewind()?;
+        self.next_row()?.ok_or(FeedError::Empty)
+    }
+
+    /// Counts the rows, stopping at `limit`.
+    fn count_rows(&mut self, limit: usize) -> Result<usize, FeedError> {
+        let mut rows = 0;
+        while rows < limit && self.next_row()?.is_some() {
+            rows += 1;
+        }
+        Ok(rows)
+    }
+}
+
+/// Rows in random order, drawn from a buffer that is refilled from the file.
+struct ShuffleBuffer {
+    reader: RowReader,
+    rows: Vec<Row>,
+    rng: Pcg64Si,
+}
+
+impl ShuffleBuffer {
+    fn next_row(&mut self) -> Result<Row, FeedError> {
+        while self.rows.len() < SHUFFLE_BUFFER {
+            match self.reader.next_row()? {
+                Some(row) => self.rows.push(row),
+                None if self.rows.is_empty() => self.rows.push(self.reader.next_row_wrapping()?),
+                None => break,
+            }
+        }
+        let index = (self.rng.next_u64() % self.rows.len() as u64) as usize;
+        Ok(self.rows.swap_remove(index))
+    }
+}
+
+/// The rows of one worker when the feed is partitioned.
+struct Partition {
+    data: FeedData,
+    format: FeedFormat,
+    /// Opened with the first row, in a blocking task like every read
+    reader: Option<RowReader>,
+    worker_id: usize,
+    workers: usize,
+    /// Row number in the current pass over the file
+    index: usize,
+    /// Whether this pass had a row for the worker yet
+    found: bool,
+}
+
+impl Partition {
+    fn next_row(&mut self) -> Result<Row, FeedError> {
+        let reader = match &mut self.reader {
+            Some(reader) => reader,
+            None => self
+                .reader
+                .insert(RowReader::open(self.data.clone(), self.format)?),
+        };
+        loop {
+            let Some(row) = reader.next_row()? else {
+                if !self.found {
+                    return Err(FeedError::EmptyPartition(self.worker_id));
+                }
+                reader.rewind()?;
+                self.index = 0;
+                self.found = false;
+                continue;
+            };
+            let mine = self.index % self.workers == self.worker_id;
+            self.index += 1;
+            if mine {
+                self.found = true;
+                return Ok(row);
+            }
+        }
+    }
+}
+
+enum RowSource {
+    Sequential(RowReader),
+    Random(ShuffleBuffer),
+    Partition(Partition),
+}
+
+impl RowSource {
+    fn next_row(&mut self) -> Result<Row, FeedError> {
+        match self {
+            RowSource::Sequential(reader) => reader.next_row_wrapping(),
+            RowSource::Random(buffer) => buffer.next_row(),
+            RowSource::Partition(partition) => partition.next_row(),
+        }
+    }
+}
+
+/// Rows read ahead of the requests that take them.
+struct Prefetched {
+    rows: VecDeque<Result<Row, FeedError>>,
+    source: Arc<Mutex<RowSource>>,
+}
+
+impl Prefetched {
+    fn new(source: RowSource) -> Self {
+        Self {
+            rows: VecDeque::new(),
+            source: Arc::new(Mutex::new(source)),
+        }
+    }
+
+    async fn next_row(&mut self) -> Result<Row, FeedError> {
+        if self.rows.is_empty() {
+            self.rows = read_ahead(&self.source).await?;
+        }
+        self.rows.pop_front().unwrap_or(Err(FeedError::Empty))
+    }
+}
+
+/// Reads the next rows in a blocking task, a failed row ends the batch.
+async fn read_ahead(
+    source: &Arc<Mutex<RowSource>>,
+) -> Result<VecDeque<Result<Row, FeedError>>, FeedError> {
+    let source = source.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut source = source.lock().unwrap_or_else(|e| e.into_inner());
+        let mut rows = VecDeque::with_capacity(PREFETCH);
+        while rows.len() < PREFETCH {
+            let row = source.next_row();
+            let failed = row.is_err();
+            rows.push_back(row);
+            if failed {
+                break;
+            }
+        }
+        rows
+    })
+    .await
+    .map_err(|e| FeedError::Io(std::io::Error::other(e)))
+}
+
+/// Hands out the rows of a CSV or JSONL file to the requests, going through the file again at its end.
+///
+/// Small files are read once with [`read_body_from`] and parsed from memory, larger ones are streamed
+/// and never loaded whole. Rows are parsed in blocking tasks, a batch at a time.
+pub struct Feeder {
+    data: FeedData,
+    format: FeedFormat,
+    columns: Option<Vec<String>>,
+    /// Present unless the rows are partitioned, where every worker reads the file on its own
+    shared: Option<tokio::sync::Mutex<Prefetched>>,
+    /// The first failure to get a row, told at the end of the run
+    first_error: Mutex<Option<String>>,
+}
+
+impl std::fmt::Debug for Feeder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Feeder")
+            .field("format", &self.format)
+            .field("columns", &self.columns)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Feeder {
+    /// Opens the feed for `workers` workers, partitioned feeds need a row for each of them.
+    pub async fn open(
+        path: &Path,
+        format: FeedFormat,
+        order: FeedOrder,
+        seed: u64,
+        workers: usize,
+    ) -> Result<Self, FeedError> {
+        let data = FeedData::load(path).await?;
+        let data_for_check = data.clone();
+        let (reader, columns) = tokio::task::spawn_blocking(move || {
+            let mut reader = RowReader::open(data_for_check, format)?;
+            let columns = reader.columns();
+            let needed = match order {
+                FeedOrder::Partitioned => workers.max(1),
+                FeedOrder::Sequential | FeedOrder::Random => 1,
+            };
+            match reader.count_rows(needed)? {
+                0 => return Err(FeedError::Empty),
+                rows if rows < needed => return Err(FeedError::TooFewRows { rows, workers }),
+                _ => {}
+            }
+            reader.rewind()?;
+            Ok((reader, columns))
+        })
+        .await
+        .map_err(|e| FeedError::Io(std::io::Error::other(e)))??;
+        let shared = match order {
+            FeedOrder::Sequential => Some(RowSource::Sequential(reader)),
+            FeedOrder::Random => Some(RowSource::Random(ShuffleBuffer {
+                reader,
+                rows: Vec::new(),
+                rng: Pcg64Si::seed_from_u64(seed),
+            })),
+            FeedOrder::Partitioned => None,
+        };
+        Ok(Self {
+            data,
+            format,
+            columns,
+            shared: shared.map(|source| tokio::sync::Mutex::new(Prefetched::new(source))),
+            first_error: Mutex::new(None),
+        })
+    }
+
+    /// Keeps the error if it is the first time a worker failed to get a row, it is worth telling only once.
+    pub fn record_error(&self, error: &FeedError) {
+        self.first_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(|| error.to_string());
+    }
+
+    /// The first failure to get a row, if there was one.
+    pub fn first_error(&self) -> Option<String> {
+        self.first_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The column names, known up front for CSV only.
+    pub fn columns(&self) -> Option<&[String]> {
+        self.columns.as_deref()
+    }
+
+    /// Where one worker takes its rows from.
+    pub fn cursor(&self, worker_id: usize, workers: usize) -> FeedCursor<'_> {
+        FeedCursor(match &self.shared {
+            Some(shared) => Cursor::Shared(shared),
+            None => Cursor::Partition(Prefetched::new(RowSource::Partition(Partition {
+                data: self.data.clone(),
+                format: self.format,
+                reader: None,
+                worker_id,
+                workers: workers.max(1),
+                index: 0,
+                found: false,
+            }))),
+        })
+    }
+}
+
+/// The rows of one worker.
+pub struct FeedCursor<'a>(Cursor<'a>);
+
+enum Cursor<'a> {
+    Shared(&'a tokio::sync::Mutex<Prefetched>),
+    Partition(Prefetched),
+}
+
+impl FeedCursor<'_> {
+    pub async fn next_row(&mut self) -> Result<Row, FeedError> {
+        match &mut self.0 {
+            Cursor::Shared(shared) => shared.lock().await.next_row().await,
+            Cursor::Partition(rows) => rows.next_row().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::{TestResult, temp_file};
+
+    #[tokio::test]
+    async fn test_sequential_wraps_around() -> TestResult {
+        let path = temp_file("feed-seq.csv", "id,name\n1,a\n2,b\n")?;
+        let feeder = Feeder::open(&path, FeedFormat::Csv, FeedOrder::Sequential, 0, 4).await?;
+        assert_eq!(
+            feeder.columns(),
+            Some(&["id".to_string(), "name".to_string()][..])
+        );
+        let mut cursor = feeder.cursor(0, 1);
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(cursor.next_row().await?["id"].clone());
+        }
+        assert_eq!(ids, ["1", "2", "1"]);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_rows() -> TestResult {
+        let path = temp_file(
+            "feed-part.jsonl",
+            "{\"id\":1}\n\n{\"id\":2}\n{\"id\":\"3\"}\n",
+        )?;
+        let feeder = Feeder::open(&path, FeedFormat::Jsonl, FeedOrder::Partitioned, 0, 2).await?;
+        let mut first = feeder.cursor(0, 2);
+        let mut second = feeder.cursor(1, 2);
+        assert_eq!(first.next_row().await?["id"], "1");
+        assert_eq!(first.next_row().await?["id"], "3");
+        assert_eq!(first.next_row().await?["id"], "1");
+        assert_eq!(second.next_row().await?["id"], "2");
+        assert_eq!(second.next_row().await?["id"], "2");
+
+        // Every worker needs a row of its own
+        assert!(matches!(
+            Feeder::open(&path, FeedFormat::Jsonl, FeedOrder::Partitioned, 0, 4).await,
+            Err(FeedError::TooFewRows {
+                rows: 3,
+                workers: 4
+            })
+        ));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_row_fails_and_the_next_one_is_read() -> TestResult {
+        let path = temp_file("feed-invalid.jsonl", "{\"id\":1}\nnot json\n{\"id\":2}\n")?;
+        let feeder = Feeder::open(&path, FeedFormat::Jsonl, FeedOrder::Sequential, 0, 1).await?;
+        let mut cursor = feeder.cursor(0, 1);
+        assert_eq!(cursor.next_row().await?["id"], "1");
+        assert!(matches!(
+            cursor.next_row().await,
+            Err(FeedError::Json(2, _))
+        ));
+        assert_eq!(cursor.next_row().await?["id"], "2");
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+use crate::client::{ConnectError, Origin};
+use crate::multiplex::{MultiplexedPool, PooledStream, StreamFailure};
+use bytes::{Bytes, BytesMut};
+use http_body_util::{BodyExt, Full};
+use hyper::http;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
+
+pub type Http2Conn = h2::client::SendRequest<Bytes>;
+pub type Http2Stream = PooledStream<Http2Conn>;
+
+/// Settings sent in the `HTTP2-Settings` header of an h2c upgrade request, base64url encoded.
+/// Only SETTINGS_ENABLE_PUSH = 0, the header may not be empty.
+pub const UPGRADE_SETTINGS: &str = "AAIAAAAA";
+
+/// Performs the HTTP/2 handshake and drives the connection in the background until it closes.
+///
+/// `initial_stream_id` is 3 after an h2c upgrade, stream 1 carries the response to the upgrade request.
+pub async fn handshake<T>(
+    io: T,
+    initial_stream_id: u32,
+) -> Result<(Http2Conn, JoinHandle<()>), h2::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut builder = h2::client::Builder::new();
+    builder.initial_stream_id(initial_stream_id);
+    let (send_request, connection) = builder.handshake(io).await?;
+    let task = tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    Ok((send_request, task))
+}
+
+/// Sends the request on a new stream and waits for the response head.
+pub async fn send_request(
+    conn: &Http2Conn,
+    request: http::Request<Full<Bytes>>,
+) -> Result<http::Response<h2::RecvStream>, h2::Error> {
+    let (parts, body) = request.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(never) => match never {},
+    };
+    let mut conn = conn.clone().ready().await?;
+    let (response, mut send) =
+        conn.send_request(http::Request::from_parts(parts, ()), body.is_empty())?;
+    if !body.is_empty() {
+        send.send_data(body, true)?;
+    }
+    response.await
+}
+
+/// Reads a response body to the end, handing the flow control window back as it goes.
+pub async fn read_body(mut body: h2::RecvStream) -> Result<Bytes, h2::Error> {
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        let _ = body.flow_control().release_capacity(chunk.len());
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.freeze())
+}
+
+/// HTTP/2 connections, each carrying many concurrent streams.
+#[derive(Debug)]
+pub struct Http2ConnectionPool {
+    pub pool: MultiplexedPool<Http2Conn>,
+    /// Connect with HTTP/1.1 and `Upgrade: h2c` instead of speaking HTTP/2 right away
+    upgrade: bool,
+}
+
+impl Http2ConnectionPool {
+    pub fn new(connections: usize, max_streams: usize, upgrade: bool) -> Self {
+        Self {
+            pool: MultiplexedPool::new(connections, max_streams),
+            upgrade,
+        }
+    }
+
+    pub async fn get_or_connect(
+        &self,
+        origin: &Origin,
+    ) -> Result<(Http2Stream, Option<Duration>), ConnectError> {
+        self.pool
+            .get_or_connect(|| origin.connect_http2(self.upgrade))
+            .await
+    }
+
+    pub async fn discard(&self, stream: Http2Stream, error: &h2::Error) {
+        let failure = if error.is_go_away() {
+            StreamFailure::GoAway
+        } else if error.is_reset() {
+            StreamFailure::Reset
+        } else {
+            StreamFailure::Other
+        };
+        self.pool.discard(stream, failure).await;
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ConnectionPool, HttpVersion};
+    use crate::run::WorkInstanceBuilder;
+    use crate::test_util::{TestResult, localhost_cert, read_head, serve, server_config};
+    use crate::tls::{TlsConfig, TlsOptions};
+    use std::net::SocketAddr;
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls;
+
+    /// What the test server expects before the HTTP/2 connection preface.
+    #[derive(Clone)]
+    enum Preface {
+        PriorKnowledge,
+        /// An HTTP/1.1 request with `Upgrade: h2c`, answered with `101 Switching Protocols`
+        Upgrade,
+        /// Like `Upgrade`, but the upgrade request is answered with `200 OK`
+        RefuseUpgrade,
+        Tls(tokio_rustls::TlsAcceptor),
+    }
+
+    /// An HTTP/2 server on a random local port answering every request with `hello h2` after `delay`.
+    /// With `go_away_after`, each connection is shut down gracefully once it got that many requests.
+    async fn serve_h2(
+        preface: Preface,
+        delay: Duration,
+        go_away_after: Option<usize>,
+    ) -> TestResult<SocketAddr> {
+        Ok(serve(move |stream| {
+            let preface = preface.clone();
+            async move {
+                match preface {
+                    Preface::PriorKnowledge => answer(stream, delay, go_away_after).await,
+                    Preface::Upgrade => {
+                        if let Ok(stream) = upgrade(stream, true).await {
+                            answer(stream, delay, go_away_after).await;
+                        }
+                    }
+                    Preface::RefuseUpgrade => {
+                        let _ = upgrade(stream, false).await;
+                    }
+                    Preface::Tls(acceptor) => {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            answer(stream, delay, go_away_after).await;
+                        }
+                    }
+                }
+            }
+        })
+        .await?)
+    }
+
+    /// Reads the upgrade request and switches to HTTP/2 if it asks for h2c and `accept` is set.
+    async fn upgrade(mut stream: TcpStream, accept: bool) -> std::io::Result<TcpStream> {
+        let head = read_head(&mut stream).await?.to_ascii_lowercase();
+        let response: &[u8] =
+            if accept && head.contains("upgrade: h2c") && head.contains("http2-settings:") {
+                b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: h2c\r\n\r\n"
+            } else {
+                b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n"
+            };
+        stream.write_all(response).await?;
+        Ok(stream)
+    }
+
+    async fn answer<T>(io: T, delay: Duration, go_away_after: Option<usize>)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Ok(mut connection) = h2::server::handshake(io).await else {
+            return;
+        };
+        let mut requests = 0;
+        while let Some(Ok((_, mut respond))) = connection.accept().await {
+            requests += 1;
+            if go_away_after == Some(requests) {
+                connection.graceful_shutdown();
+            }
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                if let Ok(mut send) = respond.send_response(http::Response::new(()), false) {
+                    let _ = send.send_data(Bytes::from_static(b"hello h2"), true);
+                }
+            });
+        }
+    }
+
+    /// A TLS acceptor for `localhost` offering only h2.
+    fn tls_acceptor() -> TestResult<tokio_rustls::TlsAcceptor> {
+        let config = server_config(&localhost_cert()?, rustls::DEFAULT_VERSIONS, &[b"h2"])?;
+        Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn origin(address: SocketAddr, max_streams: usize, upgrade: bool) -> TestResult<Origin> {
+        Ok(Origin {
+            url: format!("http://localhost:{}/", address.port()).parse()?,
+            address,
+            connection_pool: ConnectionPool::Http2(Http2ConnectionPool::new(
+                1,
+                max_streams,
+                upgrade,
+            )),
+            proxy: None,
+            tls: Arc::new(TlsConfig::load(TlsOptions::default())?),
+        })
+    }
+
+    fn pool(origin: &Origin) -> TestResult<&Http2ConnectionPool> {
+        match &origin.connection_pool {
+            ConnectionPool::Http2(pool) => Ok(pool),
+            _ => Err("not an HTTP/2 pool".into()),
+        }
+    }
+
+    /// Sends a `GET /` on the pool, the stream is discarded as the workers do if it fails.
+    async fn get(origin: &Origin) -> TestResult<Bytes> {
+        let pool = pool(origin)?;
+        let (stream, _) = pool.get_or_connect(origin).await?;
+        let request = http::Request::builder()
+            .uri(origin.url.as_str())
+            .version(http::Version::HTTP_2)
+            .body(Full::new(Bytes::new()))?;
+        match send_request(&stream.conn, request).await {
+            Ok(response) => Ok(read_body(response.into_body()).await?),
+            Err(e) => {
+                pool.discard(stream, &e).await;
+                Err(e.into())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams_share_a_connection_up_to_the_limit() -> TestResult {
+        let address = serve_h2(Preface::PriorKnowledge, Duration::from_millis(50), None).await?;
+        let origin = Arc::new(origin(address, 2, false)?);
+
+        let mut requests = tokio::task::JoinSet::new();
+        for _ in 0..6 {
+            let origin = origin.clone();
+            requests.spawn(async move { get(&origin).await });
+        }
+        while let Some(body) = requests.join_next().await {
+            assert_eq!(body??, Bytes::from_static(b"hello h2"));
+        }
+        let report = pool(&origin)?.pool.stats.report();
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.streams, 6);
+        assert_eq!(report.max_concurrent_streams, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_go_away() -> TestResult {
+        let address = serve_h2(Preface::PriorKnowledge, Duration::ZERO, Some(2)).await?;
+        let origin = origin(address, 10, false)?;
+
+        let mut answered = 0;
+        for _ in 0..6 {
+            if get(&origin).await.is_ok() {
+                answered += 1;
+            }
+        }
+        // At most one request per connection runs into the GOAWAY
+        assert!(answered >= 3);
+        assert!(pool(&origin)?.pool.stats.report().connections >= 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http2_over_tls() -> TestResult {
+        let address = serve_h2(Preface::Tls(tls_acceptor()?), Duration::ZERO, None).await?;
+
+        // Negotiated through ALPN, then asked for outright
+        for http_version in [HttpVersion::Auto, HttpVersion::Http2] {
+            let run = WorkInstanceBuilder::new(
+                format!("https://localhost:{}/", address.port()).parse()?,
+            )?
+            .http_version(http_version)
+            .requests(NonZeroU64::new(5).ok_or("zero")?)
+            .opts(|opts| opts.insecure = true)
+            .quiet(true)
+            .build()
+            .await?;
+            let report = run.run().await;
+            assert_eq!(report.status_codes.iter().map(|c| c.count).sum::<u64>(), 5);
+            let http2 = report.http2.ok_or("HTTP/2 was not used")?;
+            assert_eq!(http2.connections, 1);
+            assert_eq!(http2.streams, 5);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_h2c_prior_knowledge_and_upgrade() -> TestResult {
+        for (preface, upgrade) in [(Preface::PriorKnowledge, false), (Preface::Upgrade, true)] {
+            let address = serve_h2(preface, Duration::ZERO, None).await?;
+            let origin = origin(address, 10, upgrade)?;
+            let (conn, _) = origin.connect_http2(upgrade).await?;
+            // After an upgrade the streams start at 3, the second request checks they keep counting from there
+            for _ in 0..2 {
+                let request = http::Request::builder()
+                    .uri(origin.url.as_str())
+                    .version(http::Version::HTTP_2)
+                    .body(Full::new(Bytes::new()))?;
+                let response = send_request(&conn, request).await?;
+                assert_eq!(
+                    read_body(response.into_body()).await?,
+                    Bytes::from_static(b"hello h2")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_h2c_upgrade_refused() -> TestResult {
+        let address = serve_h2(Preface::RefuseUpgrade, Duration::ZERO, None).await?;
+        let origin = origin(address, 10, true)?;
+        assert!(matches!(
+            origin.connect_http2(true).await,
+            Err(ConnectError::UpgradeRefused(http::StatusCode::OK))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_head_does_not_wait_for_the_stream_to_end() -> TestResult {
+        // Announces a body for HEAD and keeps every stream open, as a slow server may
+        let address = serve(|stream| async move {
+            let Ok(mut connection) = h2::server::handshake(stream).await else {
+                return;
+            };
+            let mut open = Vec::new();
+            while let Some(Ok((_, mut respond))) = connection.accept().await {
+                let response = http::Response::builder()
+                    .header(http::header::CONTENT_LENGTH, "2")
+                    .body(());
+                if let Ok(response) = response
+                    && let Ok(send) = respond.send_response(response, false)
+                {
+                    open.push(send);
+                }
+            }
+        })
+        .await?;
+
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .http_version(HttpVersion::Http2)
+            .method(http::Method::HEAD)
+            .timeout(Duration::from_secs(2))
+            .requests(NonZeroU64::new(3).ok_or("zero")?)
+            .quiet(true)
+            .build()
+            .await?;
+        let start = std::time::Instant::now();
+        let report = run.run().await;
+        assert_eq!(report.status_codes.iter().map(|c| c.count).sum::<u64>(), 3);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use crate::client::ConnectError;
+use crate::multiplex::{MultiplexedPool, PooledStream, StreamFailure};
+use crate::work_mode::FailureKind;
+use bytes::{Buf, Bytes, BytesMut};
+use http_body_util::{BodyExt, Full};
+use hyper::http;
+use quinn::rustls;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+pub type Http3Conn = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+pub type Http3Stream = PooledStream<Http3Conn>;
+pub type Http3Body = h3::client::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Http3SetupError {
+    #[error("Failed to open a UDP socket {0}")]
+    Socket(std::io::Error),
+
+    #[error("TLS configuration has no cipher suite usable for QUIC {0}")]
+    CipherSuite(#[from] quinn::crypto::rustls::NoInitialCipherSuite),
+}
+
+/// Sends the request on a new stream and waits for the response head.
+pub async fn send_request(
+    conn: &Http3Conn,
+    request: http::Request<Full<Bytes>>,
+) -> Result<(http::Response<()>, Http3Body), h3::error::StreamError> {
+    let (parts, body) = request.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(never) => match never {},
+    };
+    let mut stream = conn
+        .clone()
+        .send_request(http::Request::from_parts(parts, ()))
+        .await?;
+    if !body.is_empty() {
+        stream.send_data(body).await?;
+    }
+    stream.finish().await?;
+    let response = stream.recv_response().await?;
+    Ok((response, stream))
+}
+
+/// Reads a response body to the end.
+pub async fn read_body(mut stream: Http3Body) -> Result<Bytes, h3::error::StreamError> {
+    let mut bytes = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            bytes.extend_from_slice(part);
+            let len = part.len();
+            chunk.advance(len);
+        }
+    }
+    Ok(bytes.freeze())
+}
+
+/// Maps an HTTP/3 stream error to the failure it stands for and what it means for the connection.
+pub fn classify_stream_error(e: &h3::error::StreamError) -> (FailureKind, StreamFailure) {
+    use h3::error::{ConnectionError, StreamError};
+    match e {
+        StreamError::RemoteTerminate { .. } => (FailureKind::Reset, StreamFailure::Reset),
+        StreamError::RemoteClosing { .. } => (FailureKind::Reset, StreamFailure::GoAway),
+        StreamError::ConnectionError {
+            0: ConnectionError::Timeout { .. },
+            ..
+        } => (FailureKind::Timeout, StreamFailure::Other),
+        StreamError::ConnectionError {
+            0: ConnectionError::Remote { .. },
+            ..
+        } => (FailureKind::Reset, StreamFailure::Other),
+        StreamError::StreamError { .. } | StreamError::HeaderTooBig { .. } => {
+            (FailureKind::Protocol, StreamFailure::Other)
+        }
+        _ => (FailureKind::Other, StreamFailure::Other),
+    }
+}
+
+/// Maps a failed QUIC handshake to the failure it stands for.
+pub fn classify_connection_error(e: &quinn::ConnectionError) -> FailureKind {
+    use quinn::ConnectionError;
+    // Error codes 0x100 to 0x1ff carry a TLS alert
+    let is_tls = |code: quinn::TransportErrorCode| (0x100..0x200).contains(&u64::from(code));
+    match e {
+        ConnectionError::TimedOut => FailureKind::Timeout,
+        ConnectionError::TransportError(e) if is_tls(e.code) => FailureKind::Tls,
+        ConnectionError::ConnectionClosed(close) if is_tls(close.error_code) => FailureKind::Tls,
+        ConnectionError::Reset => FailureKind::Reset,
+        ConnectionError::VersionMismatch => FailureKind::Protocol,
+        _ => FailureKind::Connect,
+    }
+}
+
+/// QUIC connections carrying HTTP/3, each with many concurrent streams.
+///
+/// The QUIC endpoint and its TLS configuration are shared by every connection, so TLS session tickets from
+/// earlier connections can be used for 0-RTT on later ones.
+#[derive(Debug)]
+pub struct Http3ConnectionPool {
+    pub pool: MultiplexedPool<Http3Conn>,
+    endpoint: quinn::Endpoint,
+    /// Send the first request in 0-RTT early data when the server gave us a session ticket before
+    zero_rtt: bool,
+    pub zero_rtt_stats: Arc<ZeroRttStats>,
+}
+
+impl Http3ConnectionPool {
+    /// Connections using the given TLS 1.3 client configuration, ALPN and early data are set here.
+    pub fn new(
+        mut tls: rustls::ClientConfig,
+        address: SocketAddr,
+        connections: usize,
+        max_streams: usize,
+        zero_rtt: bool,
+    ) -> Result<Self, Http3SetupError> {
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        tls.enable_early_data = zero_rtt;
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls)?;
+
+        let bind: SocketAddr = if address.is_ipv6() {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let mut endpoint = quinn::Endpoint::client(bind).map_err(Http3SetupError::Socket)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        Ok(Self {
+            pool: MultiplexedPool::new(connections, max_streams),
+            endpoint,
+            zero_rtt,
+            zero_rtt_stats: Arc::default(),
+        })
+    }
+
+    pub async fn get_or_connect(
+        &self,
+        address: SocketAddr,
+        domain: &str,
+    ) -> Result<(Http3Stream, Option<Duration>), ConnectError> {
+        self.pool
+            .get_or_connect(|| self.connect(address, domain))
+            .await
+    }
+
+    /// Opens a QUIC connection and sets up HTTP/3 on it.
+    async fn connect(
+        &self,
+        address: SocketAddr,
+        domain: &str,
+    ) -> Result<(Http3Conn, JoinHandle<()>), ConnectError> {
+        let connecting = self
+            .endpoint
+            .connect(address, domain)
+            .map_err(ConnectError::Quic)?;
+        let connection = if self.zero_rtt {
+            match connecting.into_0rtt() {
+                Ok((connection, accepted)) => {
+                    self.zero_rtt_stats.attempts.fetch_add(1, Ordering::Relaxed);
+                    let stats = self.zero_rtt_stats.clone();
+                    tokio::spawn(async move {
+                        if accepted.await {
+                            stats.accepted.fetch_add(1, Ordering::Relaxed);
+                        }
+                    });
+                    connection
+                }
+                // No session ticket yet, this one takes the full handshake
+                Err(connecting) => connecting.await.map_err(ConnectError::QuicHandshake)?,
+            }
+        } else {
+            connecting.await.map_err(ConnectError::QuicHandshake)?
+        };
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .map_err(ConnectError::Http3Handshake)?;
+        let task = tokio::spawn(async move {
+            let _ = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+        });
+        Ok((send_request, task))
+    }
+
+    pub async fn discard(&self, stream: Http3Stream, error: &h3::error::StreamError) {
+        let (_, failure) = classify_stream_error(error);
+        self.pool.discard(stream, failure).await;
+    }
+}
+
+/// How often 0-RTT was tried and taken by the server.
+#[derive(Debug, Default)]
+pub struct ZeroRttStats {
+    attempts: AtomicU64,
+    accepted: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ZeroRttReport {
+    pub attempts: u64,
+    pub accepted: u64,
+}
+
+impl ZeroRttStats {
+    pub fn report(&self) -> ZeroRttReport {
+        ZeroRttReport {
+            attempts: self.attempts.load(Ordering::Relaxed),
+            accepted: self.accepted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How many reconnects went out as 0-RTT, for the end of the run. `None` if there were no attempts.
+pub fn format_zero_rtt_report(stats: &ZeroRttStats) -> Option<String> {
+    let report = stats.report();
+    (report.attempts > 0).then(|| {
+        format!(
+            "0-RTT: {} of {} attempts accepted by the server",
+            report.accepted, report.attempts
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TestResult, localhost_cert, server_config};
+
+    /// A QUIC server on a random local port answering every request with `hello h3`, it takes 0-RTT data.
+    /// With `close_after`, each connection is closed shortly after it answered that many requests.
+    fn serve(
+        cert: &rcgen::CertifiedKey<rcgen::KeyPair>,
+        close_after: Option<usize>,
+    ) -> TestResult<SocketAddr> {
+        let mut tls = server_config(cert, &[&rustls::version::TLS13], &[b"h3"])?;
+        // quinn only takes early data with this exact limit
+        tls.max_early_data_size = u32::MAX;
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+        let endpoint = quinn::Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+            (std::net::Ipv4Addr::LOCALHOST, 0).into(),
+        )?;
+        let address = endpoint.local_addr()?;
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                tokio::spawn(async move {
+                    let Ok(connection) = incoming.await else {
+                        return;
+                    };
+                    let Ok(mut h3) = h3::server::Connection::<_, Bytes>::new(
+                        h3_quinn::Connection::new(connection.clone()),
+                    )
+                    .await
+                    else {
+                        return;
+                    };
+                    let mut answered = 0;
+                    while let Ok(Some(resolver)) = h3.accept().await {
+                        let Ok((_, mut stream)) = resolver.resolve_request().await else {
+                            continue;
+                        };
+                        let _ = stream.send_response(http::Response::new(())).await;
+                        let _ = stream.send_data(Bytes::from_static(b"hello h3")).await;
+                        let _ = stream.finish().await;
+                        answered += 1;
+                        if close_after == Some(answered) {
+                            // Time for the response to arrive, closing drops what is not sent yet
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            connection.close(0u32.into(), b"done");
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(address)
+    }
+
+    /// A TLS 1.3 client config trusting `cert`.
+    fn client_config(
+        cert: &rcgen::CertifiedKey<rcgen::KeyPair>,
+    ) -> TestResult<rustls::ClientConfig> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone())?;
+        Ok(rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+    }
+
+    /// Sends a `GET /` on the stream and checks the answer.
+    async fn get(stream: &Http3Stream, address: SocketAddr) -> TestResult {
+        let request = http::Request::builder()
+            .uri(format!("https://localhost:{}/", address.port()))
+            .version(http::Version::HTTP_3)
+            .body(Full::new(Bytes::new()))?;
+        let (response, body) = send_request(&stream.conn, request).await?;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(read_body(body).await?, Bytes::from_static(b"hello h3"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requests_share_one_connection() -> TestResult {
+        let cert = localhost_cert()?;
+        let address = serve(&cert, None)?;
+        let pool = Http3ConnectionPool::new(client_config(&cert)?, address, 1, 10, false)?;
+
+        for _ in 0..3 {
+            let (stream, _) = pool.get_or_connect(address, "localhost").await?;
+            get(&stream, address).await?;
+        }
+        let report = pool.pool.stats.report();
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.streams, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_with_zero_rtt() -> TestResult {
+        let cert = localhost_cert()?;
+        let address = serve(&cert, Some(1))?;
+        let pool = Http3ConnectionPool::new(client_config(&cert)?, address, 1, 10, true)?;
+
+        // No session ticket yet, the first connection takes the full handshake
+        let (stream, _) = pool.get_or_connect(address, "localhost").await?;
+        get(&stream, address).await?;
+        drop(stream);
+        assert_eq!(pool.zero_rtt_stats.report().attempts, 0);
+
+        // Once the server closed the connection, the next stream reconnects with the ticket
+        let mut reconnected = false;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let (stream, connect) = pool.get_or_connect(address, "localhost").await?;
+            if connect.is_some() {
+                get(&stream, address).await?;
+                reconnected = true;
+                break;
+            }
+        }
+        assert!(reconnected);
+        assert_eq!(pool.pool.stats.report().connections, 2);
+        // Whether the server took the early data is only known once the handshake is done
+        for _ in 0..100 {
+            if pool.zero_rtt_stats.report().accepted > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let report = pool.zero_rtt_stats.report();
+        assert_eq!((report.attempts, report.accepted), (1, 1));
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use hdrhistogram::Histogram;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Highest trackable latency in microseconds (one hour). Anything slower is clamped.
+const MAX_TRACKABLE_MICROS: u64 = 60 * 60 * 1_000_000;
+
+/// Percentiles shown in the per-second line.
+pub const INTERVAL_PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// Percentiles shown in the final distribution report.
+pub const REPORT_PERCENTILES: [f64; 8] = [50.0, 75.0, 90.0, 95.0, 99.0, 99.9, 99.99, 100.0];
+
+/// Timing of a single request.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestTiming {
+    /// Time to establish the connection (TCP + TLS), `None` if a pooled connection was reused
+    pub connect: Option<Duration>,
+
+    /// Time until the response head was received
+    pub ttfb: Duration,
+
+    /// Time until the whole response body was received
+    pub full: Duration,
+
+    /// Time from the intended send time until the whole response body was received, only known in constant-rate mode
+    pub corrected: Option<Duration>,
+}
+
+/// One histogram per measured phase, all in microseconds.
+#[derive(Debug, Clone)]
+pub struct PhaseHistograms {
+    pub connect: Histogram<u64>,
+    pub ttfb: Histogram<u64>,
+    pub full: Histogram<u64>,
+    /// Full response time corrected for coordinated omission
+    pub corrected: Histogram<u64>,
+}
+
+impl PhaseHistograms {
+    pub fn new() -> Result<Self, hdrhistogram::CreationError> {
+        let new_histogram = || Histogram::new_with_bounds(1, MAX_TRACKABLE_MICROS, 3);
+        Ok(Self {
+            connect: new_histogram()?,
+            ttfb: new_histogram()?,
+            full: new_histogram()?,
+            corrected: new_histogram()?,
+        })
+    }
+
+    pub fn record(&mut self, timing: &RequestTiming) {
+        if let Some(connect) = timing.connect {
+            self.connect.saturating_record(as_micros(connect));
+        }
+        self.ttfb.saturating_record(as_micros(timing.ttfb));
+        self.full.saturating_record(as_micros(timing.full));
+        if let Some(corrected) = timing.corrected {
+            self.corrected.saturating_record(as_micros(corrected));
+        }
+    }
+
+    /// Records a request that got no response. Only the corrected latency applies, the time it failed after
+    /// is counted as its response time so timeouts and refused connections are not left out of it.
+    pub fn record_failure(&mut self, corrected: Duration) {
+        self.corrected.saturating_record(as_micros(corrected));
+    }
+
+    /// Merge another set of histograms into this one.
+    pub fn merge(&mut self, other: &PhaseHistograms) -> Result<(), hdrhistogram::AdditionError> {
+        self.connect.add(&other.connect)?;
+        self.ttfb.add(&other.ttfb)?;
+        self.full.add(&other.full)?;
+        self.corrected.add(&other.corrected)?;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.connect.reset();
+        self.ttfb.reset();
+        self.full.reset();
+        self.corrected.reset();
+    }
+}
+
+#[derive(Debug)]
+struct RecorderInner {
+    /// Since the last call to [`LatencyRecorder::take_interval`]
+    interval: PhaseHistograms,
+
+    /// Every finished interval since the start of the run
+    total: PhaseHistograms,
+}
+
+/// Shared latency recorder, fed by every worker.
+#[derive(Debug)]
+pub struct LatencyRecorder {
+    inner: Mutex<RecorderInner>,
+}
+
+impl LatencyRecorder {
+    pub fn new() -> Result<Self, hdrhistogram::CreationError> {
+        Ok(Self {
+            inner: Mutex::new(RecorderInner {
+                interval: PhaseHistograms::new()?,
+                total: PhaseHistograms::new()?,
+            }),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RecorderInner> {
+        // Recording never panics halfway, so a poisoned lock still holds usable histograms
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn record(&self, timing: &RequestTiming) {
+        self.lock().interval.record(timing);
+    }
+
+    pub fn record_failure(&self, corrected: Duration) {
+        self.lock().interval.record_failure(corrected);
+    }
+
+    /// Returns the histograms of the current interval, folds them into the total and starts a new interval.
+    pub fn take_interval(&self) -> PhaseHistograms {
+        let mut inner = self.lock();
+        let interval = inner.interval.clone();
+        // Both sides share the same bounds, so adding them cannot fail
+        let _ = inner.total.merge(&interval);
+        inner.interval.reset();
+        interval
+    }
+
+    /// Returns the histograms of the whole run so far, including the unfinished interval.
+    pub fn total(&self) -> PhaseHistograms {
+        let inner = self.lock();
+        let mut total = inner.total.clone();
+        let _ = total.merge(&inner.interval);
+        total
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+/// Formats a latency in microseconds in a human-readable unit.
+pub fn format_micros(micros: u64) -> String {
+    if micros >= 1_000_000 {
+        format!("{:.2}s", micros as f64 / 1_000_000.0)
+    } else if micros >= 1_000 {
+        format!("{:.2}ms", micros as f64 / 1_000.0)
+    } else {
+        format!("{}us", micros)
+    }
+}
+
+/// One-line percentile summary, e.g. `p50: 1.20ms, p90: 3.40ms, ..., max: 12.00ms`.
+pub fn format_percentiles(histogram: &Histogram<u64>) -> String {
+    if histogram.is_empty() {
+        return "no samples".to_string();
+    }
+    let mut parts = INTERVAL_PERCENTILES
+        .iter()
+        .map(|p| {
+            format!(
+                "p{}: {}",
+                p,
+                format_micros(histogram.value_at_percentile(*p))
+            )
+        })
+        .collect::<Vec<_>>();
+    parts.push(format!("max: {}", format_micros(histogram.max())));
+    parts.join(", ")
+}
+
+fn push_distribution(lines: &mut Vec<String>, name: &str, histogram: &Histogram<u64>) {
+    lines.push(format!("{} ({} samples)", name, histogram.len()));
+    if histogram.is_empty() {
+        return;
+    }
+    lines.push(format!(
+        "  min: {}, mean: {}, stdev: {}, max: {}",
+        format_micros(histogram.min()),
+        format_micros(histogram.mean() as u64),
+        format_micros(histogram.stdev() as u64),
+        format_micros(histogram.max()),
+    ));
+    for p in REPORT_PERCENTILES {
+        lines.push(format!(
+            "  {:>7}%  {}",
+            p,
+            format_micros(histogram.value_at_percentile(p))
+        ));
+    }
+}
+
+/// The full latency distribution of the run, one line each.
+pub fn format_report(histograms: &PhaseHistograms) -> String {
+    let mut lines = vec!["Latency distribution:".to_string()];
+    push_distribution(&mut lines, "Connect", &histograms.connect);
+    push_distribution(&mut lines, "Time to first byte", &histograms.ttfb);
+    push_distribution(&mut lines, "Full response", &histograms.full);
+    if !histograms.corrected.is_empty() {
+        push_distribution(
+            &mut lines,
+            "Full response, corrected for coordinated omission",
+            &histograms.corrected,
+        );
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(millis: u64) -> RequestTiming {
+        RequestTiming {
+            connect: None,
+            ttfb: Duration::from_millis(millis),
+            full: Duration::from_millis(millis),
+            corrected: None,
+        }
+    }
+
+    #[test]
+    fn test_take_interval_folds_into_total() -> Result<(), hdrhistogram::CreationError> {
+        let recorder = LatencyRecorder::new()?;
+        recorder.record(&timing(1));
+        recorder.record(&timing(2));
+        assert_eq!(recorder.take_interval().full.len(), 2);
+
+        recorder.record(&timing(3));
+        assert_eq!(recorder.take_interval().full.len(), 1);
+        // The unfinished interval is part of the total as well
+        recorder.record(&timing(4));
+        let total = recorder.total();
+        assert_eq!(total.full.len(), 4);
+        assert_eq!(total.connect.len(), 0);
+        assert_eq!(total.corrected.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failures_only_count_as_corrected() -> Result<(), hdrhistogram::CreationError> {
+        let recorder = LatencyRecorder::new()?;
+        recorder.record_failure(Duration::from_millis(5));
+        let total = recorder.total();
+        assert_eq!(total.corrected.len(), 1);
+        assert_eq!(total.full.len(), 0);
+        assert_eq!(total.ttfb.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_micros() {
+        assert_eq!(format_micros(999), "999us");
+        assert_eq!(format_micros(1_500), "1.50ms");
+        assert_eq!(format_micros(2_000_000), "2.00s");
+    }
+}
@@ -0,0 +1,116 @@
+#![deny(clippy::panic)]
+#![deny(clippy::unwrap_used)]
+#![deny(clippy::expect_used)]
+
+pub mod before_request;
+pub mod budget;
+pub mod check;
+pub mod client;
+pub mod config;
+pub mod dashboard;
+pub mod feeder;
+pub mod http2;
+pub mod http3;
+pub mod latency;
+pub mod mix;
+pub mod multiplex;
+pub mod opts;
+mod pcg64si;
+pub mod profile;
+pub mod proxy;
+pub mod rate;
+pub mod report;
+pub mod run;
+pub mod scenario;
+pub mod template;
+#[cfg(test)]
+mod test_util;
+pub mod timeseries;
+pub mod tls;
+pub mod work_mode;
+
+#[derive(thiserror::Error, Debug)]
+pub enum UbwError {
+    #[error("Failed to resolve DNS {0}")]
+    FailedToResolveDns(std::io::Error),
+
+    #[error("Failed to read body from file {0}")]
+    FailedToReadBodyFromFile(std::io::Error),
+
+    #[error(
+        "According to the args, there is no way to resolve the host. Please check your arguments."
+    )]
+    NoWayToResolveHost,
+
+    #[error("You need to specify a body for a POST request")]
+    RequirePostBody,
+
+    #[error("Give the body either inline or as a file, not both")]
+    ConflictingBody,
+
+    #[error("Unsupported method {0}")]
+    UnsupportedMethod(hyper::Method),
+
+    #[error("The URL is not HTTP or HTTPS")]
+    WeirdUrl,
+
+    #[error("Failed to parse header list {0}")]
+    InvalidHeaderList(#[from] opts::ParseHeaderListError),
+
+    #[error("Failed to create latency histogram {0}")]
+    FailedToCreateHistogram(#[from] hdrhistogram::CreationError),
+
+    #[error("Failed to read load profile from file {0}")]
+    FailedToReadProfileFromFile(std::io::Error),
+
+    #[error("Failed to create time series file {0}")]
+    FailedToCreateTimeSeriesFile(std::io::Error),
+
+    #[error("Invalid load profile {0}")]
+    InvalidProfile(#[from] profile::ParseProfileError),
+
+    #[error("An h2c upgrade needs an http URL, use --http-version 2 for https")]
+    H2cUpgradeRequiresHttp,
+
+    #[error("HTTP/3 needs an https URL")]
+    Http3RequiresTls,
+
+    #[error("Failed to set up HTTP/3 {0}")]
+    FailedToSetUpHttp3(#[from] http3::Http3SetupError),
+
+    #[error("Failed to negotiate the HTTP version {0}")]
+    FailedToNegotiateHttpVersion(client::ConnectError),
+
+    #[error("Invalid request template {0}")]
+    InvalidTemplate(#[from] template::ParseTemplateError),
+
+    #[error("Failed to open the feed file {0}")]
+    FailedToOpenFeed(#[from] feeder::FeedError),
+
+    #[error("The template uses the feed column {0:?}, but no feed file was given")]
+    FeedColumnWithoutFeed(String),
+
+    #[error("The template uses the feed column {0:?}, which the feed file does not have")]
+    UnknownFeedColumn(String),
+
+    #[error("Invalid request mix {0}")]
+    InvalidMix(#[from] mix::MixError),
+
+    #[error("Invalid scenario {0}")]
+    InvalidScenario(#[from] scenario::ScenarioError),
+
+    #[error("The template uses the session variable {0:?}, which only a scenario can extract")]
+    VariableWithoutScenario(String),
+
+    #[error("Failed to create the directory for failed responses {0}")]
+    FailedToCreateSamplesDirectory(std::io::Error),
+
+    #[error("Invalid proxy {0}")]
+    InvalidProxy(#[from] proxy::ProxyError),
+
+    #[error("Invalid TLS settings {0}")]
+    InvalidTls(#[from] tls::TlsError),
+
+    #[error("Invalid options {0}")]
+    InvalidOptions(#[from] clap::Error),
+}
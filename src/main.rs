@@ -2,43 +2,12 @@
 #![deny(clippy::unwrap_used)]
 #![deny(clippy::expect_used)]
 
-use clap::Parser;
-use tokio::task::JoinSet;
 use tokio::signal;
-use crate::work_mode::counter_print;
+use ubw::{
+    budget, client, config, dashboard, http3, latency, mix, multiplex, proxy, rate, report, run,
+};
 
-pub mod before_request;
-pub mod client;
-pub mod opts;
-mod pcg64si;
-pub mod work_mode;
-pub mod emiya;
-
-#[derive(thiserror::Error, Debug)]
-pub enum UbwError {
-    #[error("Failed to resolve DNS {0}")]
-    FailedToResolveDns(std::io::Error),
-
-    #[error("Failed to read body from file {0}")]
-    FailedToReadBodyFromFile(std::io::Error),
-
-    #[error(
-        "According to the args, there is no way to resolve the host. Please check your arguments."
-    )]
-    NoWayToResolveHost,
-
-    #[error("You need to specify a body for a POST request")]
-    RequirePostBody,
-
-    #[error("Unsupported method {0}")]
-    UnsupportedMethod(hyper::Method),
-
-    #[error("The URL is not HTTP or HTTPS")]
-    WeirdUrl,
-
-    #[error("Failed to parse header list {0}")]
-    InvalidHeaderList(#[from] opts::ParseHeaderListError),
-}
+mod emiya;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -48,66 +17,141 @@ async fn main() -> anyhow::Result<()> {
         return Ok(());
     }
     
-    let opts = opts::Opts::parse();
+    let (opts, effective_config) = config::parse_opts()?;
+    if opts.print_config {
+        print!("{}", toml::to_string(&effective_config)?);
+        return Ok(());
+    }
     
-    let concurrent = opts.concurrent;
     let shutdown_after = opts.max_time;
+    let output_file = opts.output_file.clone();
+    let output_format = opts.output_format.or_else(|| {
+        output_file
+            .as_deref()
+            .map(|path| report::OutputFormat::from_path(path).unwrap_or(report::OutputFormat::Json))
+    });
 
     if !opts.instant_cast {
         emiya::wait_for_incantation().await?;
     }
 
-    let work_instance = before_request::prepare_work_instance(opts).await?;
-    let work_instance = std::sync::Arc::new(work_instance);
-    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
-
-    let mut handlers = JoinSet::<()>::new();
-    
-    for _ in 0..concurrent {
-        let work_instance = work_instance.clone();
-        let mut shutdown_rx = shutdown_rx.clone();
-        handlers.spawn(async move {
-            if let Err(e) = client::request_loop(work_instance, &mut shutdown_rx).await {
-                eprintln!("Failed to start request loop: {e}");
-            }
-        });
+    let show_dashboard = opts.dashboard;
+    let run = run::WorkInstanceBuilder::from_opts(opts).build().await?;
+    let work_instance = run.work_instance.clone();
+    for warning in &work_instance.warnings {
+        eprintln!("Warning: {warning}");
     }
-    
-    let arc_for_counter_monitor = work_instance.clone();
-    let shutdown_sig_for_counter_monitor = shutdown_rx.clone();
-    tokio::spawn(async move {
-        let mut shutdown_sig_for_counter_monitor = shutdown_sig_for_counter_monitor;
-        counter_print(
-            &arc_for_counter_monitor.request_counter,
-            &mut shutdown_sig_for_counter_monitor,
-        ).await
+    let cancel_handle = run.cancel_handle();
+
+    // Handle graceful shutdown from signals, silently under the dashboard which owns the screen
+    tokio::spawn(handle_shutdown_signals(
+        cancel_handle.clone(),
+        !show_dashboard,
+    ));
+
+    let dashboard = show_dashboard.then(|| {
+        tokio::spawn(dashboard::run(
+            work_instance.clone(),
+            cancel_handle.sender().clone(),
+            shutdown_after.map(Into::into),
+        ))
     });
-    
-    // Handle graceful shutdown from signals
-    tokio::spawn(handle_shutdown_signals(shutdown_tx.clone()));
-    
-    if let Some(shutdown_after) = shutdown_after {
-        // Create a separate task for the timed shutdown
-        let shutdown_tx_for_timer = shutdown_tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = before_request::shutdown(shutdown_tx_for_timer, *shutdown_after).await {
-                eprintln!("Failed to send shutdown signal: {e}");
-            }
-        });
+
+    let run_report = run.run().await;
+    // The terminal has to be restored before anything else is printed, a failed dashboard must not
+    // cost the report though
+    if let Some(dashboard) = dashboard {
+        match dashboard.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("The dashboard failed: {e}"),
+            Err(e) => eprintln!("The dashboard stopped: {e}"),
+        }
+    }
+    // Errors met during the run are kept until the end, they would tear through the progress lines or the dashboard
+    for error in work_instance
+        .worker_errors
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+    {
+        eprintln!("{error}");
+    }
+    if let Some(e) = work_instance
+        .feeder
+        .as_ref()
+        .and_then(|feeder| feeder.first_error())
+    {
+        eprintln!("{e}, requests without a row were counted as feed failures");
+    }
+    if let Some(e) = work_instance
+        .checks
+        .as_ref()
+        .and_then(|checks| checks.samples.as_ref())
+        .and_then(|samples| samples.save_error())
+    {
+        eprintln!("Failed to save a failed response: {e}");
+    }
+    if let Some(e) = work_instance.time_series.write_error() {
+        eprintln!("{e}, no further samples were written to the file");
     }
-    
-    // Wait for shutdown signal
-    shutdown_rx.clone().changed().await?;
     println!("Shutting down gracefully...");
-    
-    // Wait for all tasks to complete (optional timeout could be added)
-    while handlers.join_next().await.is_some() {}
+
+    println!("{}", run_report.summary());
+    println!("{}", latency::format_report(&work_instance.latency.total()));
+    if let Some(rate_limiter) = &work_instance.rate_limiter {
+        println!("{}", rate::format_report(rate_limiter));
+    }
+    if let Some(budget) = &work_instance.budget {
+        println!("{}", budget::format_report(budget));
+    }
+    let title = if work_instance.scenario.is_some() {
+        "Steps"
+    } else {
+        "Endpoints"
+    };
+    if let Some(report) = mix::format_report(&work_instance.mix, title) {
+        println!("{report}");
+    }
+    for origin in &work_instance.origins {
+        // Several origins are told apart by their URL
+        let protocol = |version: &str| match work_instance.origins.len() {
+            1 => version.to_string(),
+            _ => format!("{} {}", version, origin.url.origin().ascii_serialization()),
+        };
+        match &origin.connection_pool {
+            client::ConnectionPool::Http1(_) => {}
+            client::ConnectionPool::Http2(pool) => {
+                println!(
+                    "{}",
+                    multiplex::format_report(&protocol("HTTP/2"), &pool.pool.stats)
+                );
+            }
+            client::ConnectionPool::Http3(pool) => {
+                println!(
+                    "{}",
+                    multiplex::format_report(&protocol("HTTP/3"), &pool.pool.stats)
+                );
+                if let Some(report) = http3::format_zero_rtt_report(&pool.zero_rtt_stats) {
+                    println!("{report}");
+                }
+            }
+        }
+    }
+    if let Some(proxy) = &work_instance.proxy {
+        println!("{}", proxy::format_report(proxy));
+    }
+    if let Some(output_format) = output_format {
+        run_report.write_to(output_format, output_file.as_deref())?;
+    }
     
     println!("All tasks completed, goodbye!");
     Ok(())
 }
 
-async fn handle_shutdown_signals(shutdown_tx: tokio::sync::watch::Sender<bool>) -> anyhow::Result<()> {
+async fn handle_shutdown_signals(
+    cancel_handle: run::CancelHandle,
+    announce: bool,
+) -> anyhow::Result<()> {
     let ctrl_c = async {
         #[allow(clippy::expect_used)]
         signal::ctrl_c().await.expect("Failed to install Ctrl+C handler");
@@ -124,7 +168,7 @@ async fn handle_shutdown_signals(shutdown_tx: tokio::sync::watch::Sender<bool>)
 
     #[cfg(not(unix))]
     let terminate = std::future::pending::<()>();
-
+    
     #[cfg(windows)]
     #[allow(clippy::expect_used)]
     let ctrl_shutdown = async {
@@ -137,20 +181,17 @@ async fn handle_shutdown_signals(shutdown_tx: tokio::sync::watch::Sender<bool>)
     #[cfg(not(windows))]
     let ctrl_shutdown = std::future::pending::<()>();
 
-    tokio::select! {
-        _ = ctrl_c => {
-            println!("Received Ctrl+C signal");
-        }
-        _ = terminate => {
-            println!("Received terminate signal");
-        }
-        _ = ctrl_shutdown => {
-            println!("Received shutdown signal");
-        }
+    let received = tokio::select! {
+        _ = ctrl_c => "Ctrl+C",
+        _ = terminate => "terminate",
+        _ = ctrl_shutdown => "shutdown",
+    };
+    if announce {
+        println!("Received {received} signal");
     }
 
     // Send shutdown signal to all tasks
-    let _ = shutdown_tx.send(true);
-    
+    cancel_handle.cancel();
+
     Ok(())
 }
@@ -0,0 +1,339 @@
+use crate::latency::{LatencyRecorder, format_percentiles};
+use crate::pcg64si::Pcg64Si;
+use crate::report::{CountReport, DistributionReport};
+use crate::template::RequestTemplate;
+use crate::work_mode::{ClientResponseCodeType, RequestCounter, WorkMode};
+use hyper::HeaderMap;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use url::Url;
+
+/// One request of a mix or scenario file.
+///
+/// The URL is resolved against the `-u` URL, so it may be a path or point at another origin.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestEntry {
+    pub name: Option<String>,
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub url: String,
+    /// Added to the `-H` headers, replacing those of the same name
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<String>,
+    /// Relative to the directory of the mix or scenario file
+    pub body_file: Option<PathBuf>,
+    /// Falls back to `-T`
+    pub content_type: Option<String>,
+}
+
+/// One entry of a mix file.
+///
+/// ```json
+/// [
+///   {"name": "item", "weight": 70, "url": "/items/{{randint 1 1000}}"},
+///   {"name": "cart", "weight": 20, "method": "POST", "url": "/cart", "body": "{\"id\": 1}",
+///    "content_type": "application/json"},
+///   {"name": "search", "weight": 10, "url": "https://search.example.com/search?q=ubw"}
+/// ]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct MixEntry {
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(flatten)]
+    pub request: RequestEntry,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MixError {
+    #[error("Failed to read mix file {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid mix file {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("The mix file has no endpoints")]
+    Empty,
+
+    #[error("All endpoints of the mix have weight 0")]
+    ZeroWeight,
+
+    #[error("Invalid URL {0:?}: {1}")]
+    InvalidUrl(String, url::ParseError),
+
+    #[error("Invalid method {0:?}")]
+    InvalidMethod(String),
+
+    #[error("Invalid header {0:?}")]
+    InvalidHeader(String),
+}
+
+/// Reads the entries of a mix file, a JSON array of [`MixEntry`].
+pub async fn read_mix_file(path: &std::path::Path) -> Result<Vec<MixEntry>, MixError> {
+    let entries: Vec<MixEntry> = serde_json::from_str(&tokio::fs::read_to_string(path).await?)?;
+    if entries.is_empty() {
+        return Err(MixError::Empty);
+    }
+    Ok(entries)
+}
+
+/// Requests to one endpoint and how they went.
+#[derive(Debug)]
+pub struct EndpointStats {
+    pub counter: RequestCounter,
+    pub latency: LatencyRecorder,
+    /// Session variables the scenario step could not extract from its responses
+    pub missing_vars: AtomicU64,
+}
+
+impl EndpointStats {
+    pub fn new() -> Result<Self, hdrhistogram::CreationError> {
+        Ok(Self {
+            counter: RequestCounter::new(),
+            latency: LatencyRecorder::new()?,
+            missing_vars: AtomicU64::new(0),
+        })
+    }
+
+    pub fn record_missing_vars(&self, count: u64) {
+        if count > 0 {
+            self.missing_vars.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+}
+
+/// One kind of request in the mix.
+#[derive(Debug)]
+pub struct Endpoint {
+    pub name: String,
+    pub weight: u32,
+    pub url: Url,
+    /// Index into the origins of the run, whose connections the requests go over
+    pub origin: usize,
+    pub mode: WorkMode,
+    pub header_map: HeaderMap,
+    /// Present when the URL, headers or body have template variables
+    pub template: Option<RequestTemplate>,
+    /// Present when there is more than one endpoint or a scenario, otherwise the totals say it all
+    pub stats: Option<EndpointStats>,
+}
+
+/// The endpoints of a run, each picked for a request in proportion to its weight.
+#[derive(Debug)]
+pub struct RequestMix {
+    pub endpoints: Vec<Endpoint>,
+    /// Running sum of the weights, for picking by a random number below the total
+    cumulative_weights: Vec<u64>,
+}
+
+impl RequestMix {
+    pub fn new(endpoints: Vec<Endpoint>) -> Result<Self, MixError> {
+        let cumulative_weights = endpoints
+            .iter()
+            .scan(0u64, |sum, endpoint| {
+                *sum += u64::from(endpoint.weight);
+                Some(*sum)
+            })
+            .collect::<Vec<_>>();
+        match cumulative_weights.last() {
+            None => Err(MixError::Empty),
+            Some(0) => Err(MixError::ZeroWeight),
+            Some(_) => Ok(Self {
+                endpoints,
+                cumulative_weights,
+            }),
+        }
+    }
+
+    pub fn is_mixed(&self) -> bool {
+        self.endpoints.len() > 1
+    }
+
+    /// `GET <url>` for a single endpoint, otherwise how many there are.
+    pub fn describe(&self) -> String {
+        match &self.endpoints[..] {
+            [endpoint] => format!("{} {}", endpoint.mode.method, endpoint.url),
+            endpoints => format!("{} endpoints", endpoints.len()),
+        }
+    }
+
+    /// Picks the endpoint of the next request, returned with its index.
+    pub fn pick(&self, rng: &mut Pcg64Si) -> (usize, &Endpoint) {
+        let index = match self.cumulative_weights.last() {
+            Some(&total) if self.is_mixed() => {
+                let point = rng.next_u64() % total;
+                self.cumulative_weights
+                    .partition_point(|&cumulative| cumulative <= point)
+            }
+            _ => 0,
+        };
+        let index = index.min(self.endpoints.len() - 1);
+        (index, &self.endpoints[index])
+    }
+
+    /// Per-endpoint results, empty unless the endpoints kept their own stats.
+    pub fn report(&self) -> Vec<EndpointReport> {
+        let total = self
+            .endpoints
+            .iter()
+            .filter_map(|endpoint| endpoint.stats.as_ref())
+            .map(|stats| stats.counter.get_total())
+            .sum::<u64>();
+        self.endpoints
+            .iter()
+            .filter_map(|endpoint| {
+                let stats = endpoint.stats.as_ref()?;
+                let requests = stats.counter.get_total();
+                Some(EndpointReport {
+                    name: endpoint.name.clone(),
+                    method: endpoint.mode.method.to_string(),
+                    url: endpoint.url.to_string(),
+                    weight: endpoint.weight,
+                    requests,
+                    share: requests as f64 / total.max(1) as f64,
+                    responses: ClientResponseCodeType::ALL
+                        .iter()
+                        .map(|code_type| CountReport {
+                            name: code_type.name().to_string(),
+                            count: stats.counter.get_cumulative(*code_type),
+                        })
+                        .collect(),
+                    latency: (&stats.latency.total().full).into(),
+                    missing_vars: stats.missing_vars.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Results of one endpoint of the mix.
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointReport {
+    pub name: String,
+    pub method: String,
+    pub url: String,
+    pub weight: u32,
+    pub requests: u64,
+    /// Fraction of all requests that went to this endpoint
+    pub share: f64,
+    pub responses: Vec<CountReport>,
+    /// Full response latency
+    pub latency: DistributionReport,
+    /// Session variables a scenario step could not extract
+    #[serde(skip_serializing_if = "is_zero")]
+    pub missing_vars: u64,
+}
+
+fn is_zero(count: &u64) -> bool {
+    *count == 0
+}
+
+impl EndpointReport {
+    /// Hands every metric of the report to `push` as name and value, for the CSV and Markdown reports.
+    pub fn push_rows(&self, mut push: impl FnMut(String, String)) {
+        push("method".into(), self.method.clone());
+        push("url".into(), self.url.clone());
+        push("weight".into(), self.weight.to_string());
+        push("requests".into(), self.requests.to_string());
+        push("share".into(), format!("{:.3}", self.share));
+        for count in &self.responses {
+            push(count.name.clone(), count.count.to_string());
+        }
+        if self.missing_vars > 0 {
+            push("missing_vars".into(), self.missing_vars.to_string());
+        }
+        push("mean_us".into(), format!("{:.1}", self.latency.mean_us));
+        for percentile in &self.latency.percentiles {
+            push(
+                format!("p{}_us", percentile.percentile),
+                percentile.value_us.to_string(),
+            );
+        }
+    }
+}
+
+/// One line per endpoint under `title`, for the end of a run that kept per-endpoint stats.
+/// `None` if it did not.
+pub fn format_report(mix: &RequestMix, title: &str) -> Option<String> {
+    let reports = mix.report();
+    if reports.is_empty() {
+        return None;
+    }
+    let mut lines = vec![format!("{}:", title)];
+    for (endpoint, report) in mix.endpoints.iter().zip(reports) {
+        let latency = endpoint
+            .stats
+            .as_ref()
+            .map(|stats| format_percentiles(&stats.latency.total().full))
+            .unwrap_or_default();
+        let mut responses = report
+            .responses
+            .iter()
+            .filter(|count| count.count > 0)
+            .map(|count| format!("{}: {}", count.name, count.count))
+            .collect::<Vec<_>>();
+        if report.missing_vars > 0 {
+            responses.push(format!("missing variables: {}", report.missing_vars));
+        }
+        let responses = responses.join(", ");
+        lines.push(format!(
+            "  {} ({} {}): {} requests ({:.1}%), {}",
+            report.name,
+            report.method,
+            report.url,
+            report.requests,
+            report.share * 100.0,
+            responses
+        ));
+        lines.push(format!("    {}", latency));
+    }
+    Some(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn endpoint(weight: u32) -> Result<Endpoint, url::ParseError> {
+        Ok(Endpoint {
+            name: weight.to_string(),
+            weight,
+            url: Url::parse("http://localhost/")?,
+            origin: 0,
+            mode: WorkMode {
+                method: hyper::Method::GET,
+                body: None,
+            },
+            header_map: HeaderMap::new(),
+            template: None,
+            stats: None,
+        })
+    }
+
+    #[test]
+    fn test_pick_follows_weights() -> Result<(), Box<dyn std::error::Error>> {
+        let mix = RequestMix::new(vec![endpoint(70)?, endpoint(0)?, endpoint(30)?])?;
+        let mut rng = Pcg64Si::seed_from_u64(1);
+        let mut picks = [0u32; 3];
+        for _ in 0..10_000 {
+            picks[mix.pick(&mut rng).0] += 1;
+        }
+        assert_eq!(picks[1], 0);
+        assert!((6_500..7_500).contains(&picks[0]), "{:?}", picks);
+        assert!(RequestMix::new(vec![endpoint(0)?]).is_err());
+        Ok(())
+    }
+}
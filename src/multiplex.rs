@@ -0,0 +1,233 @@
+use crate::client::ConnectError;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+/// One multiplexed connection and the streams it may still open.
+struct Slot<C> {
+    conn: C,
+    /// Drives the connection, finished once it is closed
+    task: JoinHandle<()>,
+    streams: Arc<Semaphore>,
+    /// Tells a reconnected slot apart from the connection a stream was opened on
+    generation: u64,
+}
+
+/// A stream on one of the pooled connections, the stream slot is given back when it is dropped.
+pub struct PooledStream<C> {
+    pub conn: C,
+    slot: usize,
+    generation: u64,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Why a stream failed, as far as the pool is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFailure {
+    /// The server is closing the connection, it takes no new streams
+    GoAway,
+    /// The server reset the stream
+    Reset,
+    Other,
+}
+
+/// A fixed number of connections, each carrying up to `max_streams` concurrent streams.
+///
+/// Workers are spread over the connections round-robin. A connection that is closed, by a GOAWAY or otherwise,
+/// is replaced by the next worker that picks it.
+pub struct MultiplexedPool<C> {
+    slots: Vec<Mutex<Option<Slot<C>>>>,
+    next: AtomicUsize,
+    max_streams: usize,
+    pub stats: MultiplexStats,
+}
+
+impl<C> std::fmt::Debug for MultiplexedPool<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MultiplexedPool")
+            .field("connections", &self.slots.len())
+            .field("max_streams", &self.max_streams)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl<C: Clone> MultiplexedPool<C> {
+    pub fn new(connections: usize, max_streams: usize) -> Self {
+        Self {
+            slots: (0..connections.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            max_streams: max_streams.max(1),
+            stats: MultiplexStats::default(),
+        }
+    }
+
+    /// Opens a stream on the next connection, calling `connect` first if it is not open.
+    /// The returned duration is the connect time, `None` if the connection was already open.
+    pub async fn get_or_connect<F, Fut>(
+        &self,
+        connect: F,
+    ) -> Result<(PooledStream<C>, Option<Duration>), ConnectError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(C, JoinHandle<()>), ConnectError>>,
+    {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let (conn, streams, generation, connect_time) = {
+            let mut slot = self.slots[index].lock().await;
+            match slot.as_ref() {
+                Some(open) if !open.task.is_finished() => (
+                    open.conn.clone(),
+                    open.streams.clone(),
+                    open.generation,
+                    None,
+                ),
+                _ => {
+                    let start = Instant::now();
+                    let (conn, task) = connect().await?;
+                    let connect_time = start.elapsed();
+                    let generation = self.stats.connections.fetch_add(1, Ordering::Relaxed) + 1;
+                    let streams = Arc::new(Semaphore::new(self.max_streams));
+                    *slot = Some(Slot {
+                        conn: conn.clone(),
+                        task,
+                        streams: streams.clone(),
+                        generation,
+                    });
+                    (conn, streams, generation, Some(connect_time))
+                }
+            }
+        };
+        // The semaphore is never closed
+        let permit = streams.clone().acquire_owned().await.ok();
+        let in_flight = (self.max_streams - streams.available_permits()) as u64;
+        self.stats
+            .max_in_flight
+            .fetch_max(in_flight, Ordering::Relaxed);
+        self.stats.streams.fetch_add(1, Ordering::Relaxed);
+        Ok((
+            PooledStream {
+                conn,
+                slot: index,
+                generation,
+                _permit: permit,
+            },
+            connect_time,
+        ))
+    }
+
+    /// Counts a failed stream. After a GOAWAY the connection is dropped so the next stream gets a fresh one.
+    pub async fn discard(&self, stream: PooledStream<C>, failure: StreamFailure) {
+        match failure {
+            StreamFailure::GoAway => {
+                self.stats.go_away.fetch_add(1, Ordering::Relaxed);
+                let mut slot = self.slots[stream.slot].lock().await;
+                if slot
+                    .as_ref()
+                    .is_some_and(|s| s.generation == stream.generation)
+                {
+                    *slot = None;
+                }
+            }
+            StreamFailure::Reset => {
+                self.stats.reset_stream.fetch_add(1, Ordering::Relaxed);
+            }
+            StreamFailure::Other => {}
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MultiplexStats {
+    /// Connections opened, reconnects included
+    connections: AtomicU64,
+    /// Streams opened
+    streams: AtomicU64,
+    /// Most streams in flight on one connection at once
+    max_in_flight: AtomicU64,
+    /// Streams that failed because the server sent GOAWAY
+    go_away: AtomicU64,
+    /// Streams the server reset
+    reset_stream: AtomicU64,
+}
+
+/// Connection and stream usage over the whole run.
+#[derive(Debug, Clone, Serialize)]
+pub struct MultiplexReport {
+    pub connections: u64,
+    pub streams: u64,
+    pub streams_per_connection: f64,
+    pub max_concurrent_streams: u64,
+    pub go_away: u64,
+    pub reset_stream: u64,
+}
+
+impl MultiplexStats {
+    pub fn report(&self) -> MultiplexReport {
+        let connections = self.connections.load(Ordering::Relaxed);
+        let streams = self.streams.load(Ordering::Relaxed);
+        MultiplexReport {
+            connections,
+            streams,
+            streams_per_connection: streams as f64 / connections.max(1) as f64,
+            max_concurrent_streams: self.max_in_flight.load(Ordering::Relaxed),
+            go_away: self.go_away.load(Ordering::Relaxed),
+            reset_stream: self.reset_stream.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MultiplexReport {
+    /// Adds up the connection and stream counts of several pools, the busiest pool gives the maximum of
+    /// concurrent streams. `None` if there are no pools.
+    pub fn combine(reports: impl IntoIterator<Item = MultiplexReport>) -> Option<Self> {
+        reports.into_iter().reduce(|total, report| {
+            let connections = total.connections + report.connections;
+            let streams = total.streams + report.streams;
+            MultiplexReport {
+                connections,
+                streams,
+                streams_per_connection: streams as f64 / connections.max(1) as f64,
+                max_concurrent_streams: total
+                    .max_concurrent_streams
+                    .max(report.max_concurrent_streams),
+                go_away: total.go_away + report.go_away,
+                reset_stream: total.reset_stream + report.reset_stream,
+            }
+        })
+    }
+
+    /// Hands every metric of the report to `push` as name and value, for the CSV and Markdown reports.
+    pub fn push_rows(&self, mut push: impl FnMut(String, String)) {
+        push("connections".into(), self.connections.to_string());
+        push("streams".into(), self.streams.to_string());
+        push(
+            "streams_per_connection".into(),
+            format!("{:.2}", self.streams_per_connection),
+        );
+        push(
+            "max_concurrent_streams".into(),
+            self.max_concurrent_streams.to_string(),
+        );
+        push("go_away".into(), self.go_away.to_string());
+        push("reset_stream".into(), self.reset_stream.to_string());
+    }
+}
+
+/// The connection usage of a multiplexed protocol, for the end of the run.
+pub fn format_report(protocol: &str, stats: &MultiplexStats) -> String {
+    let report = stats.report();
+    format!(
+        "{}: {} connections, {} streams ({:.1} per connection, at most {} at once), GOAWAY: {}, stream resets: {}",
+        protocol,
+        report.connections,
+        report.streams,
+        report.streams_per_connection,
+        report.max_concurrent_streams,
+        report.go_away,
+        report.reset_stream
+    )
+}
@@ -1,7 +1,15 @@
+use crate::check::{HeaderCheck, JsonCheck, StatusSet};
+use crate::client::HttpVersion;
+use crate::feeder::{FeedFormat, FeedOrder};
+use crate::profile::{LoadProfile, ProfileTarget};
+use crate::report::OutputFormat;
+use crate::timeseries::TimeSeriesFormat;
+use crate::tls::TlsVersion;
 use clap::Parser;
 use compact_str::CompactString;
 use hyper::{HeaderMap, Method};
 use std::net::IpAddr;
+use std::num::{NonZeroU32, NonZeroU64};
 use std::str::FromStr;
 use url::Url;
 
@@ -9,6 +17,18 @@ use url::Url;
 #[command(version, about, long_about = None)]
 #[command(arg_required_else_help = true)]
 pub struct Opts {
+    #[arg(
+        help = "TOML file with any of these options, the ones given on the command line take precedence",
+        long = "config"
+    )]
+    pub config: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Print the effective configuration in the config file format and exit",
+        long = "print-config"
+    )]
+    pub print_config: bool,
+
     #[arg(help = "The URL to fetch", short)]
     pub url: Url,
 
@@ -18,13 +38,85 @@ pub struct Opts {
     #[arg(help = "The maximum time to wait for a response", short = 't', default_value = None)]
     pub max_time: Option<humantime::Duration>,
 
+    #[arg(
+        help = "Send exactly this many requests in total, shared by all workers",
+        short = 'n',
+        long = "requests"
+    )]
+    pub requests: Option<NonZeroU64>,
+
+    #[arg(
+        help = "Send requests at a constant rate (requests per second) instead of as fast as possible",
+        short = 'R',
+        long = "rate"
+    )]
+    pub rate: Option<NonZeroU32>,
+
+    #[arg(
+        help = "Load profile stages, e.g. ramp:100-5000:2m,hold:5000:10m,spike:20000:30s,sine:1000~500/30s:5m",
+        long = "profile",
+        conflicts_with_all = ["rate", "profile_file"]
+    )]
+    pub profile: Option<LoadProfile>,
+
+    #[arg(
+        help = "Read the load profile stages from a file, one stage per line",
+        long = "profile-file",
+        conflicts_with = "rate"
+    )]
+    pub profile_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "What the load profile controls",
+        long = "profile-target",
+        value_enum,
+        default_value_t = ProfileTarget::Rate
+    )]
+    pub profile_target: ProfileTarget,
+
+    #[arg(
+        help = "Give up on a request after this long, connecting included",
+        long = "timeout"
+    )]
+    pub timeout: Option<humantime::Duration>,
+
+    #[arg(
+        help = "HTTP version to speak, auto negotiates it through TLS ALPN",
+        long = "http-version",
+        value_enum,
+        default_value_t = HttpVersion::Http1
+    )]
+    pub http_version: HttpVersion,
+
+    #[arg(
+        help = "Number of HTTP/2 or HTTP/3 connections, the concurrent requests are spread over them as streams",
+        long = "connections",
+        alias = "h2-connections",
+        default_value_t = NonZeroU32::MIN
+    )]
+    pub connections: NonZeroU32,
+
+    #[arg(
+        help = "Most concurrent streams per HTTP/2 or HTTP/3 connection",
+        long = "max-streams",
+        alias = "h2-max-streams",
+        default_value_t = NonZeroU32::new(100).unwrap_or(NonZeroU32::MIN)
+    )]
+    pub max_streams: NonZeroU32,
+
+    #[arg(
+        help = "Send the first HTTP/3 request of a reconnect as 0-RTT early data",
+        long = "zero-rtt"
+    )]
+    pub zero_rtt: bool,
+
     #[arg(help = "Simulate host file", short = 'i')]
     pub host: Option<IpAddr>,
 
     #[arg(help = "Add headers to the request", short = 'H', long = "header")]
     pub header: Vec<HeaderListItem>,
 
-    #[arg(help = "The HTTP method to use", short = 'X', default_value_t = Method::GET)]
+    #[arg(help = "The HTTP method to use, custom methods are sent as given", short = 'X', default_value_t = Method::GET)]
     pub method: Method,
 
     #[arg(help = "The body to send", short = 'd', long = "data")]
@@ -33,17 +125,196 @@ pub struct Opts {
     #[arg(help = "The file to send", short = 'D', long = "data-binary")]
     pub body_file: Option<std::path::PathBuf>,
 
-    #[arg(help = "Add headers to the proxy request", long = "proxy-header")]
-    pub proxy_headers: Vec<CompactString>,
+    #[arg(
+        help = "Seed for the random template variables like {{randint 1 100}} and the endpoint picks of a mix, random if not given",
+        long = "seed"
+    )]
+    pub seed: Option<u64>,
+
+    #[arg(
+        help = "CSV or JSONL file whose rows fill in {{feed <column>}} variables, one row per request",
+        long = "feed"
+    )]
+    pub feed: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Format of the feed file, guessed from its extension if not given",
+        long = "feed-format",
+        value_enum
+    )]
+    pub feed_format: Option<FeedFormat>,
+
+    #[arg(
+        help = "Which row of the feed file a request gets",
+        long = "feed-order",
+        value_enum,
+        default_value_t = FeedOrder::Sequential
+    )]
+    pub feed_order: FeedOrder,
+
+    #[arg(
+        help = "JSON file with a weighted mix of endpoints to send requests to, relative URLs are resolved against -u",
+        long = "mix",
+        conflicts_with_all = ["body_string", "body_file"]
+    )]
+    pub mix: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "TOML file with the steps of a user session that every worker goes through in a loop, relative URLs are resolved against -u",
+        long = "scenario",
+        conflicts_with_all = ["mix", "body_string", "body_file"]
+    )]
+    pub scenario: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Count responses with other status codes as failed checks, e.g. 200,204 or 2xx,304",
+        long = "expect-status"
+    )]
+    pub expect_status: Option<StatusSet>,
+
+    #[arg(
+        help = "Count responses without this header as failed checks, given as <name> or <name>: <value>",
+        long = "expect-header"
+    )]
+    pub expect_headers: Vec<HeaderCheck>,
+
+    #[arg(
+        help = "Count responses whose body does not contain this text as failed checks",
+        long = "expect-body"
+    )]
+    pub expect_body: Vec<String>,
+
+    #[arg(
+        help = "Count responses whose body does not match this regex as failed checks",
+        long = "expect-body-regex"
+    )]
+    pub expect_body_regex: Vec<regex::Regex>,
+
+    #[arg(
+        help = "Count responses whose JSON body has another value at the pointer as failed checks, e.g. /status=\"ok\"",
+        long = "expect-json"
+    )]
+    pub expect_json: Vec<JsonCheck>,
+
+    #[arg(
+        help = "Count responses with a larger body in bytes as failed checks",
+        long = "max-body-size"
+    )]
+    pub max_body_size: Option<u64>,
 
     #[arg(
-        help = "Add headers to the accept request",
+        help = "Count responses that take longer as failed checks",
+        long = "max-latency"
+    )]
+    pub max_latency: Option<humantime::Duration>,
+
+    #[arg(
+        help = "Save responses that failed a check to this directory",
+        long = "save-failures"
+    )]
+    pub save_failures: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Save at most this many failed responses",
+        long = "save-failures-max",
+        default_value_t = 20,
+        requires = "save_failures"
+    )]
+    pub save_failures_max: usize,
+
+    #[arg(
+        help = "Send every request through this proxy, http://, socks5:// or socks5h:// to let the proxy resolve hosts, with optional user:password@ credentials, SOCKS5 defaults to port 1080",
+        long = "proxy"
+    )]
+    pub proxy: Option<Url>,
+
+    #[arg(
+        help = "Add headers to the CONNECT and forwarded requests an HTTP proxy gets",
+        long = "proxy-header",
+        requires = "proxy"
+    )]
+    pub proxy_headers: Vec<HeaderListItem>,
+
+    #[arg(
+        help = "Accept any server certificate and host name, for staging servers with self-signed certificates",
+        short = 'k',
+        long = "insecure"
+    )]
+    pub insecure: bool,
+
+    #[arg(
+        help = "Trust the certificate authorities in this PEM bundle on top of the system ones",
+        long = "cacert"
+    )]
+    pub ca_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Present this PEM client certificate chain for mutual TLS",
+        long = "cert",
+        requires = "key",
+        conflicts_with = "pkcs12"
+    )]
+    pub cert: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "The PEM PKCS#8 private key of --cert",
+        long = "key",
+        requires = "cert"
+    )]
+    pub key: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Present the client certificate and key in this PKCS#12 archive for mutual TLS, not supported with HTTP/3",
+        long = "pkcs12"
+    )]
+    pub pkcs12: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "The password of the --pkcs12 archive",
+        long = "pkcs12-password",
+        requires = "pkcs12"
+    )]
+    pub pkcs12_password: Option<String>,
+
+    #[arg(
+        help = "The oldest TLS version to accept, rustls speaks 1.2 and 1.3 only",
+        long = "tls-min-version",
+        value_enum
+    )]
+    pub tls_min_version: Option<TlsVersion>,
+
+    #[arg(
+        help = "The newest TLS version to offer, HTTP/3 needs 1.3",
+        long = "tls-max-version",
+        value_enum
+    )]
+    pub tls_max_version: Option<TlsVersion>,
+
+    #[arg(
+        help = "Offer only these cipher suites in this order, e.g. TLS13_AES_128_GCM_SHA256,TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256, needs the rustls backend for TLS over TCP",
+        long = "tls-ciphers",
+        value_delimiter = ','
+    )]
+    pub tls_ciphers: Vec<String>,
+
+    #[arg(
+        help = "Make a full TLS handshake on every connection instead of resuming earlier sessions, needs the rustls backend",
+        long = "no-tls-resumption"
+    )]
+    pub no_tls_resumption: bool,
+    
+    #[arg(
+        help = "Media types to accept, sent together as one Accept header with every request, e.g. -A application/json -A 'text/*;q=0.5'",
         short = 'A',
         long = "accept-header"
     )]
     pub accept_headers: Vec<CompactString>,
 
-    #[arg(help = "The content type to use", short = 'T', long = "content-type")]
+    #[arg(
+        help = "The content type of the request body, guessed from the --data-binary file extension if not given",
+        short = 'T',
+        long = "content-type"
+    )]
     pub content_type: Option<CompactString>,
 
     #[arg(help = "Use IPv6", short = '6', default_value_t = true)]
@@ -51,7 +322,40 @@ pub struct Opts {
 
     #[arg(help = "Use IPv4", short = '4', default_value_t = true)]
     pub ipv4: bool,
-    
+
+    #[arg(
+        help = "Write a final report in this format, guessed from --output-file if not given",
+        long = "output-format",
+        value_enum
+    )]
+    pub output_format: Option<OutputFormat>,
+
+    #[arg(
+        help = "Write the final report to this file instead of stdout",
+        long = "output-file"
+    )]
+    pub output_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Stream the per-second statistics to this file",
+        long = "timeseries-file"
+    )]
+    pub timeseries_file: Option<std::path::PathBuf>,
+
+    #[arg(
+        help = "Format of the time series file, guessed from its extension if not given",
+        long = "timeseries-format",
+        value_enum,
+        requires = "timeseries_file"
+    )]
+    pub timeseries_format: Option<TimeSeriesFormat>,
+
+    #[arg(
+        help = "Show a live full-screen dashboard instead of per-second lines",
+        long = "tui"
+    )]
+    pub dashboard: bool,
+
     #[arg(help = "Don't wait for incitation", long = "instant-cast", default_value_t = false)]
     pub instant_cast: bool,
 }
@@ -62,6 +366,33 @@ pub struct HeaderListItem {
     pub header_value: CompactString,
 }
 
+impl HeaderListItem {
+    /// `name: value` for reports, with the value of a header that carries credentials left out.
+    pub fn redacted(&self) -> String {
+        if is_sensitive_header(&self.header_name) {
+            format!("{}: {}", self.header_name, REDACTED)
+        } else {
+            format!("{}: {}", self.header_name, self.header_value)
+        }
+    }
+}
+
+/// Stands in for the value of a header that carries credentials.
+pub const REDACTED: &str = "[redacted]";
+
+/// Whether the header carries credentials, which must not end up in reports or saved responses.
+pub fn is_sensitive_header(name: &str) -> bool {
+    [
+        "authorization",
+        "proxy-authorization",
+        "cookie",
+        "set-cookie",
+        "x-api-key",
+    ]
+    .iter()
+    .any(|sensitive| name.eq_ignore_ascii_case(sensitive))
+}
+
 impl FromStr for HeaderListItem {
     type Err = anyhow::Error;
 
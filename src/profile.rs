@@ -0,0 +1,515 @@
+use crate::client::ActiveClock;
+use crate::rate::RateLimiter;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// How often the driver re-evaluates the profile.
+const TICK: Duration = Duration::from_millis(100);
+
+/// A rate profile gets enough workers to keep up with its peak rate while responses take up to this long.
+/// Slower responses show up as schedule lag, `-c` adds workers on top.
+const RATE_WORKER_LATENCY: Duration = Duration::from_millis(100);
+
+/// What a load profile controls over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProfileTarget {
+    /// Requests per second, in constant-rate mode
+    Rate,
+
+    /// Number of active workers
+    Concurrency,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StageShape {
+    /// Linear change to `to`, starting at `from` or where the previous stage ended
+    Ramp { from: Option<f64>, to: f64 },
+
+    /// Constant level
+    Hold { value: f64 },
+
+    /// Staircase to `to` in `steps` equal steps, starting at `from` or where the previous stage ended
+    Step {
+        from: Option<f64>,
+        to: f64,
+        steps: u32,
+    },
+
+    /// Jump to `value`, the next stage continues from the level before the spike
+    Spike { value: f64 },
+
+    /// Oscillation around `base`
+    Sine {
+        base: f64,
+        amplitude: f64,
+        period: Duration,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub shape: StageShape,
+    pub duration: Duration,
+}
+
+impl Stage {
+    /// Level `elapsed` into this stage, given the level the previous stage ended at.
+    pub fn level_at(&self, elapsed: Duration, start_level: f64) -> f64 {
+        let progress = if self.duration.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / self.duration.as_secs_f64()).clamp(0.0, 1.0)
+        };
+        let level = match &self.shape {
+            StageShape::Ramp { from, to } => {
+                let from = from.unwrap_or(start_level);
+                from + (to - from) * progress
+            }
+            StageShape::Hold { value } | StageShape::Spike { value } => *value,
+            StageShape::Step { from, to, steps } => {
+                let from = from.unwrap_or(start_level);
+                let steps = f64::from(*steps);
+                let current = ((progress * steps).floor() + 1.0).min(steps);
+                from + (to - from) * current / steps
+            }
+            StageShape::Sine {
+                base,
+                amplitude,
+                period,
+            } => {
+                let phase = elapsed.as_secs_f64() / period.as_secs_f64().max(f64::EPSILON);
+                base + amplitude * (phase * std::f64::consts::TAU).sin()
+            }
+        };
+        level.max(0.0)
+    }
+
+    /// The level the next stage starts from.
+    pub fn end_level(&self, start_level: f64) -> f64 {
+        match &self.shape {
+            StageShape::Ramp { to, .. } | StageShape::Step { to, .. } => *to,
+            StageShape::Hold { value } => *value,
+            StageShape::Spike { .. } => start_level,
+            StageShape::Sine { base, .. } => *base,
+        }
+    }
+
+    /// The highest level reached during this stage.
+    pub fn peak(&self, start_level: f64) -> f64 {
+        match &self.shape {
+            StageShape::Ramp { from, to } | StageShape::Step { from, to, .. } => {
+                from.unwrap_or(start_level).max(*to)
+            }
+            StageShape::Hold { value } | StageShape::Spike { value } => *value,
+            StageShape::Sine {
+                base, amplitude, ..
+            } => base + amplitude.abs(),
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let duration = humantime::format_duration(self.duration);
+        match &self.shape {
+            StageShape::Ramp {
+                from: Some(from),
+                to,
+            } => {
+                write!(f, "ramp {} -> {} over {}", from, to, duration)
+            }
+            StageShape::Ramp { from: None, to } => write!(f, "ramp to {} over {}", to, duration),
+            StageShape::Hold { value } => write!(f, "hold {} for {}", value, duration),
+            StageShape::Step {
+                from: Some(from),
+                to,
+                steps,
+            } => write!(
+                f,
+                "step {} -> {} in {} steps over {}",
+                from, to, steps, duration
+            ),
+            StageShape::Step {
+                from: None,
+                to,
+                steps,
+            } => {
+                write!(f, "step to {} in {} steps over {}", to, steps, duration)
+            }
+            StageShape::Spike { value } => write!(f, "spike to {} for {}", value, duration),
+            StageShape::Sine {
+                base,
+                amplitude,
+                period,
+            } => write!(
+                f,
+                "sine {} ~ {} every {} for {}",
+                base,
+                amplitude,
+                humantime::format_duration(*period),
+                duration
+            ),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseProfileError {
+    #[error("Invalid stage {0:?}, expected <kind>:<spec>:<duration>")]
+    InvalidStageFormat(String),
+
+    #[error("Unknown stage kind {0:?}, expected ramp, hold, step, spike or sine")]
+    UnknownStageKind(String),
+
+    #[error("Invalid level {0:?}")]
+    InvalidLevel(String),
+
+    #[error("Invalid number of steps {0:?}")]
+    InvalidStepCount(String),
+
+    #[error("Invalid duration {0:?}: {1}")]
+    InvalidDuration(String, humantime::DurationError),
+
+    #[error("The load profile has no stages")]
+    Empty,
+}
+
+fn parse_level(s: &str) -> Result<f64, ParseProfileError> {
+    s.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|level| level.is_finite() && *level >= 0.0)
+        .ok_or_else(|| ParseProfileError::InvalidLevel(s.to_string()))
+}
+
+fn parse_duration(s: &str) -> Result<Duration, ParseProfileError> {
+    humantime::parse_duration(s.trim())
+        .map_err(|e| ParseProfileError::InvalidDuration(s.to_string(), e))
+}
+
+/// Parses `[<from>-]<to>`.
+fn parse_range(s: &str) -> Result<(Option<f64>, f64), ParseProfileError> {
+    match s.split_once('-') {
+        Some((from, to)) => Ok((Some(parse_level(from)?), parse_level(to)?)),
+        None => Ok((None, parse_level(s)?)),
+    }
+}
+
+impl FromStr for Stage {
+    type Err = ParseProfileError;
+
+    /// Parses one stage:
+    ///
+    /// - `ramp:[<from>-]<to>:<duration>`
+    /// - `hold:<value>:<duration>`
+    /// - `step:[<from>-]<to>/<steps>:<duration>`
+    /// - `spike:<value>:<duration>`
+    /// - `sine:<base>~<amplitude>/<period>:<duration>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseProfileError::InvalidStageFormat(s.to_string());
+        let mut parts = s.trim().splitn(3, ':');
+        let (Some(kind), Some(spec), Some(duration)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let duration = parse_duration(duration)?;
+        let shape = match kind.trim() {
+            "ramp" => {
+                let (from, to) = parse_range(spec)?;
+                StageShape::Ramp { from, to }
+            }
+            "hold" => StageShape::Hold {
+                value: parse_level(spec)?,
+            },
+            "step" => {
+                let (range, steps) = spec.split_once('/').ok_or_else(invalid)?;
+                let (from, to) = parse_range(range)?;
+                let steps = steps
+                    .trim()
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|steps| *steps > 0)
+                    .ok_or_else(|| ParseProfileError::InvalidStepCount(steps.to_string()))?;
+                StageShape::Step { from, to, steps }
+            }
+            "spike" => StageShape::Spike {
+                value: parse_level(spec)?,
+            },
+            "sine" => {
+                let (base, rest) = spec.split_once('~').ok_or_else(invalid)?;
+                let (amplitude, period) = rest.split_once('/').ok_or_else(invalid)?;
+                StageShape::Sine {
+                    base: parse_level(base)?,
+                    amplitude: parse_level(amplitude)?,
+                    period: parse_duration(period)?,
+                }
+            }
+            other => return Err(ParseProfileError::UnknownStageKind(other.to_string())),
+        };
+        Ok(Stage { shape, duration })
+    }
+}
+
+/// A sequence of stages, each changing the load level over its duration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadProfile {
+    pub stages: Vec<Stage>,
+}
+
+impl FromStr for LoadProfile {
+    type Err = ParseProfileError;
+
+    /// Stages are separated by commas or new lines, `#` starts a comment.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let stages = s
+            .lines()
+            .map(|line| line.split_once('#').map_or(line, |(stage, _)| stage))
+            .flat_map(|line| line.split(','))
+            .filter(|stage| !stage.trim().is_empty())
+            .map(Stage::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        if stages.is_empty() {
+            return Err(ParseProfileError::Empty);
+        }
+        Ok(Self { stages })
+    }
+}
+
+impl LoadProfile {
+    /// Total duration of all stages.
+    pub fn duration(&self) -> Duration {
+        self.stages.iter().map(|stage| stage.duration).sum()
+    }
+
+    /// The highest level reached by any stage.
+    pub fn peak(&self) -> f64 {
+        let mut start_level = 0.0;
+        let mut peak: f64 = 0.0;
+        for stage in &self.stages {
+            peak = peak.max(stage.peak(start_level));
+            start_level = stage.end_level(start_level);
+        }
+        peak
+    }
+
+    /// The stage index and level `elapsed` into the profile, `None` once the profile is over.
+    pub fn stage_at(&self, elapsed: Duration) -> Option<(usize, f64)> {
+        let mut stage_start = Duration::ZERO;
+        let mut start_level = 0.0;
+        for (index, stage) in self.stages.iter().enumerate() {
+            let stage_end = stage_start + stage.duration;
+            if elapsed < stage_end {
+                return Some((index, stage.level_at(elapsed - stage_start, start_level)));
+            }
+            stage_start = stage_end;
+            start_level = stage.end_level(start_level);
+        }
+        None
+    }
+}
+
+/// Applies a load profile while the run is going and exposes where it currently is.
+#[derive(Debug)]
+pub struct ProfileRunner {
+    pub profile: LoadProfile,
+    pub target: ProfileTarget,
+
+    /// Index of the current stage
+    stage: AtomicUsize,
+
+    /// Current level, as `f64` bits
+    level: AtomicU64,
+
+    /// Number of workers allowed to send, only used for [`ProfileTarget::Concurrency`]
+    active_workers: tokio::sync::watch::Sender<usize>,
+}
+
+impl ProfileRunner {
+    pub fn new(profile: LoadProfile, target: ProfileTarget) -> Self {
+        let (initial_stage, initial_level) = profile.stage_at(Duration::ZERO).unwrap_or((0, 0.0));
+        Self {
+            profile,
+            target,
+            stage: AtomicUsize::new(initial_stage),
+            level: AtomicU64::new(initial_level.to_bits()),
+            active_workers: tokio::sync::watch::Sender::new(initial_level.round() as usize),
+        }
+    }
+
+    /// Number of workers needed to reach the peak of the profile.
+    pub fn peak_workers(&self) -> usize {
+        match self.target {
+            ProfileTarget::Rate => {
+                (self.profile.peak() * RATE_WORKER_LATENCY.as_secs_f64()).ceil() as usize
+            }
+            ProfileTarget::Concurrency => self.profile.peak().ceil() as usize,
+        }
+    }
+
+    /// The current stage index and level.
+    pub fn current(&self) -> (usize, f64) {
+        (
+            self.stage.load(Ordering::Relaxed),
+            f64::from_bits(self.level.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Short description of the current stage for the per-second output.
+    pub fn describe_current(&self) -> String {
+        let (stage, level) = self.current();
+        let kind = match self.profile.stages.get(stage).map(|stage| &stage.shape) {
+            Some(StageShape::Ramp { .. }) => "ramp",
+            Some(StageShape::Hold { .. }) => "hold",
+            Some(StageShape::Step { .. }) => "step",
+            Some(StageShape::Spike { .. }) => "spike",
+            Some(StageShape::Sine { .. }) => "sine",
+            None => "done",
+        };
+        let unit = match self.target {
+            ProfileTarget::Rate => "rps",
+            ProfileTarget::Concurrency => "workers",
+        };
+        format!(
+            "stage {}/{} {}, target: {:.0} {}",
+            stage + 1,
+            self.profile.stages.len(),
+            kind,
+            level,
+            unit
+        )
+    }
+
+    /// Waits until the worker with the given index is allowed to send.
+    /// Always ready unless the profile controls the concurrency.
+    pub async fn wait_until_active(&self, worker_id: usize) {
+        if self.target != ProfileTarget::Concurrency {
+            return;
+        }
+        let mut active_workers = self.active_workers.subscribe();
+        let _ = active_workers.wait_for(|active| worker_id < *active).await;
+    }
+
+    /// Follows the profile on `clock` until it is over, then requests a shutdown.
+    /// Stage transitions are printed unless `quiet` is set.
+    pub async fn drive(
+        &self,
+        clock: ActiveClock<'_>,
+        rate_limiter: Option<&RateLimiter>,
+        shutdown_tx: tokio::sync::watch::Sender<bool>,
+        quiet: bool,
+    ) {
+        let mut shutdown_rx = shutdown_tx.subscribe();
+        let mut ticker = tokio::time::interval(TICK);
+        let mut last_stage = None;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown_rx.changed() => {
+                    return;
+                }
+            }
+            let Some((stage, level)) = self.profile.stage_at(clock.elapsed()) else {
+                if !quiet {
+                    println!("Load profile finished");
+                }
+                let _ = shutdown_tx.send(true);
+                return;
+            };
+            if last_stage != Some(stage) {
+                if let Some(current) = self.profile.stages.get(stage)
+                    && !quiet
+                {
+                    println!(
+                        "==> Stage {}/{}: {}",
+                        stage + 1,
+                        self.profile.stages.len(),
+                        current
+                    );
+                }
+                last_stage = Some(stage);
+            }
+            self.stage.store(stage, Ordering::Relaxed);
+            self.level.store(level.to_bits(), Ordering::Relaxed);
+            match self.target {
+                ProfileTarget::Rate => {
+                    if let Some(rate_limiter) = rate_limiter {
+                        rate_limiter.set_rate(level);
+                    }
+                }
+                ProfileTarget::Concurrency => {
+                    self.active_workers.send_replace(level.round() as usize);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() -> Result<(), ParseProfileError> {
+        let profile: LoadProfile =
+            "ramp:100-5000:2m, hold:5000:10m\nspike:20000:30s # peak\nsine:1000~500/30s:5m"
+                .parse()?;
+        assert_eq!(profile.stages.len(), 4);
+        assert_eq!(
+            profile.stages[0].shape,
+            StageShape::Ramp {
+                from: Some(100.0),
+                to: 5000.0
+            }
+        );
+        assert_eq!(profile.stages[2].duration, Duration::from_secs(30));
+        assert_eq!(
+            profile.duration(),
+            Duration::from_secs(2 * 60 + 10 * 60 + 30 + 5 * 60)
+        );
+        assert_eq!(profile.peak(), 20000.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid_profile() {
+        assert!("".parse::<LoadProfile>().is_err());
+        assert!("ramp:100".parse::<LoadProfile>().is_err());
+        assert!("jump:100:1s".parse::<LoadProfile>().is_err());
+        assert!("step:100-200/0:1s".parse::<LoadProfile>().is_err());
+        assert!("hold:-5:1s".parse::<LoadProfile>().is_err());
+    }
+
+    #[test]
+    fn test_stage_levels() -> Result<(), ParseProfileError> {
+        let profile: LoadProfile =
+            "ramp:0-100:10s,spike:500:1s,ramp:200:10s,step:400/2:10s".parse()?;
+        assert_eq!(profile.stage_at(Duration::from_secs(5)), Some((0, 50.0)));
+        assert_eq!(
+            profile.stage_at(Duration::from_millis(10_500)),
+            Some((1, 500.0))
+        );
+        // The ramp after the spike starts from the level before the spike
+        assert_eq!(profile.stage_at(Duration::from_secs(11)), Some((2, 100.0)));
+        assert_eq!(profile.stage_at(Duration::from_secs(22)), Some((3, 300.0)));
+        assert_eq!(profile.stage_at(Duration::from_secs(27)), Some((3, 400.0)));
+        assert_eq!(profile.stage_at(Duration::from_secs(31)), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_peak_workers() -> Result<(), ParseProfileError> {
+        let profile: LoadProfile = "ramp:100-5000:2m,spike:20000:30s".parse()?;
+        assert_eq!(
+            ProfileRunner::new(profile.clone(), ProfileTarget::Rate).peak_workers(),
+            2000
+        );
+        let profile: LoadProfile = "ramp:1-20:1m".parse()?;
+        assert_eq!(
+            ProfileRunner::new(profile, ProfileTarget::Concurrency).peak_workers(),
+            20
+        );
+        Ok(())
+    }
+}
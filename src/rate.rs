@@ -0,0 +1,274 @@
+use crate::latency::format_micros;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Lag below this is timer jitter rather than falling behind, tokio timers have millisecond resolution.
+const LATE_THRESHOLD: Duration = Duration::from_millis(1);
+
+/// Hands out send times on a fixed global schedule, independent of how fast responses come back.
+///
+/// Every worker claims the next slot and sleeps until it is due. When all workers are busy the slots
+/// pile up and are sent late; how late is tracked as the schedule lag.
+#[derive(Debug)]
+pub struct RateLimiter {
+    schedule: Mutex<Schedule>,
+
+    /// Worst lag behind the schedule since the last call to `take_interval_max_lag`, in microseconds
+    interval_max_lag: AtomicU64,
+
+    /// Worst lag behind the schedule for the whole run, in microseconds
+    max_lag: AtomicU64,
+
+    /// Sum of every lag, in microseconds
+    lag_sum: AtomicU64,
+
+    /// Number of requests that were sent noticeably after their slot
+    late_count: AtomicU64,
+
+    /// Number of slots handed out
+    slot_count: AtomicU64,
+
+    /// Wakes the workers waiting for a slot when the rate goes up from zero
+    rate_changed: tokio::sync::Notify,
+}
+
+#[derive(Debug)]
+struct Schedule {
+    /// Intended send time of the next request
+    next: Instant,
+
+    /// Time between two consecutive slots, `None` while the rate is zero and nothing is sent
+    interval: Option<Duration>,
+}
+
+/// Slots are only claimed this long before they are due, a worker waiting for a later one looks at the
+/// schedule again meanwhile. So a slot that was far off under a low rate does not hold it back once the
+/// rate goes up.
+const MAX_SLOT_WAIT: Duration = Duration::from_millis(100);
+
+/// `None` for a rate of zero, or one so low its interval does not fit in a `Duration`.
+fn interval_for(requests_per_second: f64) -> Option<Duration> {
+    if requests_per_second > 0.0 {
+        Duration::try_from_secs_f64(1.0 / requests_per_second).ok()
+    } else {
+        None
+    }
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> Self {
+        Self {
+            schedule: Mutex::new(Schedule {
+                next: Instant::now(),
+                interval: interval_for(requests_per_second),
+            }),
+            interval_max_lag: AtomicU64::new(0),
+            max_lag: AtomicU64::new(0),
+            lag_sum: AtomicU64::new(0),
+            late_count: AtomicU64::new(0),
+            slot_count: AtomicU64::new(0),
+            rate_changed: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Claims the next slot, waits until it is due and returns the time the request is supposed to be sent at.
+    /// Nothing is sent while the rate is zero, not even a slot claimed before it dropped to zero.
+    pub async fn wait_for_slot(&self) -> Instant {
+        loop {
+            // Created before looking at the rate, so a change in between is not missed
+            let rate_changed = self.rate_changed.notified();
+            match self.try_claim() {
+                Ok(send_time) => {
+                    tokio::time::sleep_until(send_time.into()).await;
+                    if !self.is_stopped() {
+                        return send_time;
+                    }
+                }
+                Err(Some(due)) => {
+                    let wake = due.min(Instant::now() + MAX_SLOT_WAIT);
+                    tokio::time::sleep_until(wake.into()).await;
+                }
+                Err(None) => rate_changed.await,
+            }
+        }
+    }
+
+    /// Claims the next slot if it is due within [`MAX_SLOT_WAIT`], otherwise returns when it is due,
+    /// `None` while the rate is zero.
+    fn try_claim(&self) -> Result<Instant, Option<Instant>> {
+        let mut schedule = self.schedule.lock().unwrap_or_else(|e| e.into_inner());
+        let interval = schedule.interval.ok_or(None)?;
+        let send_time = schedule.next;
+        if send_time > Instant::now() + MAX_SLOT_WAIT {
+            return Err(Some(send_time));
+        }
+        match send_time.checked_add(interval) {
+            Some(next) => schedule.next = next,
+            // The slot after this one would never come, the same as a rate of zero
+            None => schedule.interval = None,
+        }
+        self.slot_count.fetch_add(1, Ordering::Relaxed);
+        Ok(send_time)
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.schedule
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .interval
+            .is_none()
+    }
+
+    /// Changes the rate for every slot that has not been handed out yet, a rate of zero stops sending.
+    pub fn set_rate(&self, requests_per_second: f64) {
+        let mut schedule = self.schedule.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = schedule.interval;
+        schedule.interval = interval_for(requests_per_second);
+        match (previous, schedule.interval) {
+            (None, Some(_)) => {
+                // The slots of the time at zero are not owed
+                schedule.next = Instant::now();
+                self.rate_changed.notify_waiters();
+            }
+            (Some(previous), Some(interval)) if previous != interval => {
+                // The next slot follows the last one handed out at the new spacing
+                if let Some(next) = schedule
+                    .next
+                    .checked_sub(previous)
+                    .and_then(|last| last.checked_add(interval))
+                {
+                    schedule.next = next;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Starts the schedule over from now, dropping every slot that was not handed out yet.
+    pub fn restart(&self) {
+        let mut schedule = self.schedule.lock().unwrap_or_else(|e| e.into_inner());
+        schedule.next = Instant::now();
+    }
+
+    /// Records how long after its intended send time a request actually went out.
+    pub fn record_lag(&self, lag: Duration) {
+        let micros = u64::try_from(lag.as_micros()).unwrap_or(u64::MAX);
+        self.interval_max_lag.fetch_max(micros, Ordering::Relaxed);
+        self.max_lag.fetch_max(micros, Ordering::Relaxed);
+        self.lag_sum.fetch_add(micros, Ordering::Relaxed);
+        if lag > LATE_THRESHOLD {
+            self.late_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the worst lag since the last call and resets it.
+    pub fn take_interval_max_lag(&self) -> u64 {
+        self.interval_max_lag.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn max_lag(&self) -> u64 {
+        self.max_lag.load(Ordering::Relaxed)
+    }
+
+    /// Mean lag over all handed out slots, in microseconds.
+    pub fn mean_lag(&self) -> u64 {
+        let slots = self.slot_count.load(Ordering::Relaxed);
+        if slots == 0 {
+            return 0;
+        }
+        self.lag_sum.load(Ordering::Relaxed) / slots
+    }
+
+    pub fn late_count(&self) -> u64 {
+        self.late_count.load(Ordering::Relaxed)
+    }
+
+    pub fn slot_count(&self) -> u64 {
+        self.slot_count.load(Ordering::Relaxed)
+    }
+}
+
+/// How well the run kept up with the requested rate, for the end of the run.
+pub fn format_report(rate_limiter: &RateLimiter) -> String {
+    format!(
+        "Schedule: {} slots, {} sent late, mean lag: {}, max lag: {}",
+        rate_limiter.slot_count(),
+        rate_limiter.late_count(),
+        format_micros(rate_limiter.mean_lag()),
+        format_micros(rate_limiter.max_lag()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_slots_follow_the_rate() {
+        let rate_limiter = RateLimiter::new(100.0);
+        let first = rate_limiter.wait_for_slot().await;
+        let second = rate_limiter.wait_for_slot().await;
+        let third = rate_limiter.wait_for_slot().await;
+        assert_eq!(second - first, Duration::from_millis(10));
+        assert_eq!(third - second, Duration::from_millis(10));
+        assert_eq!(rate_limiter.slot_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_lag_statistics() {
+        let rate_limiter = RateLimiter::new(1000.0);
+        for _ in 0..4 {
+            rate_limiter.wait_for_slot().await;
+        }
+        for lag in [0, 500, 3_000, 5_000] {
+            rate_limiter.record_lag(Duration::from_micros(lag));
+        }
+        // Only lags above the timer resolution count as late
+        assert_eq!(rate_limiter.late_count(), 2);
+        assert_eq!(rate_limiter.max_lag(), 5_000);
+        assert_eq!(rate_limiter.mean_lag(), 2_125);
+        assert_eq!(rate_limiter.take_interval_max_lag(), 5_000);
+        assert_eq!(rate_limiter.take_interval_max_lag(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_zero_rate_sends_nothing() -> Result<(), Box<dyn std::error::Error>> {
+        let rate_limiter = Arc::new(RateLimiter::new(0.0));
+        let wait = Duration::from_millis(50);
+        assert!(
+            tokio::time::timeout(wait, rate_limiter.wait_for_slot())
+                .await
+                .is_err()
+        );
+
+        let waiting = rate_limiter.clone();
+        let slot = tokio::spawn(async move { waiting.wait_for_slot().await });
+        tokio::time::sleep(wait).await;
+        let set_at = Instant::now();
+        rate_limiter.set_rate(10.0);
+        // The waiting worker is woken up, its slot is not dated back to the time at zero
+        let send_time = tokio::time::timeout(wait, slot).await??;
+        assert!(send_time >= set_at);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_below_one_per_second() -> Result<(), Box<dyn std::error::Error>> {
+        let rate_limiter = Arc::new(RateLimiter::new(0.5));
+        let first = rate_limiter.wait_for_slot().await;
+        let second =
+            tokio::time::timeout(Duration::from_secs(3), rate_limiter.wait_for_slot()).await?;
+        assert_eq!(second - first, Duration::from_secs(2));
+
+        // The slot 2s away is moved up once the rate goes up
+        let waiting = rate_limiter.clone();
+        let third = tokio::spawn(async move { waiting.wait_for_slot().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        rate_limiter.set_rate(10.0);
+        let third = tokio::time::timeout(Duration::from_millis(500), third).await??;
+        assert_eq!(third - second, Duration::from_millis(100));
+        Ok(())
+    }
+}
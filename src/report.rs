@@ -0,0 +1,861 @@
+use crate::budget::RequestBudget;
+use crate::client::{ConnectionPool, WorkInstance};
+use crate::feeder::FeedFormat;
+use crate::http3::ZeroRttReport;
+use crate::latency::{PhaseHistograms, REPORT_PERCENTILES};
+use crate::mix::EndpointReport;
+use crate::multiplex::MultiplexReport;
+use crate::opts::{HeaderListItem, Opts};
+use crate::proxy::{ProxyReport, without_credentials};
+use crate::rate::RateLimiter;
+use crate::timeseries::IntervalSample;
+use crate::tls::TlsOptions;
+use crate::work_mode::ClientResponseCodeType;
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Format of the machine-readable final report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Json,
+    Csv,
+    Markdown,
+}
+
+impl OutputFormat {
+    /// Guesses the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(OutputFormat::Json),
+            "csv" => Some(OutputFormat::Csv),
+            "md" | "markdown" => Some(OutputFormat::Markdown),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WriteReportError {
+    #[error("Failed to write report {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to serialize report as JSON {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Failed to serialize report as CSV {0}")]
+    Csv(#[from] csv::Error),
+}
+
+/// The options a run was started with, as given on the command line.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunOptions {
+    pub url: String,
+    pub method: String,
+    pub concurrent: u16,
+    pub max_time: Option<String>,
+    pub timeout: Option<String>,
+    pub requests: Option<u64>,
+    pub rate: Option<u32>,
+    pub profile: Option<String>,
+    pub profile_file: Option<String>,
+    pub profile_target: String,
+    pub host: Option<String>,
+    pub headers: Vec<String>,
+    pub body: Option<String>,
+    pub content_type: Option<String>,
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub http_version: String,
+    /// Without its credentials
+    pub proxy: Option<String>,
+    /// The TLS backend and what the handshake is pinned to
+    pub tls: String,
+    pub mix: Option<String>,
+    pub scenario: Option<String>,
+    pub feed: Option<String>,
+    /// Given or guessed from the feed file, only present with a feed
+    pub feed_format: Option<String>,
+    /// Only present with a feed
+    pub feed_order: Option<String>,
+    /// The response checks as `<check> <expected>`, e.g. `status 2xx,304`
+    pub checks: Vec<String>,
+    pub seed: Option<u64>,
+}
+
+impl From<&Opts> for RunOptions {
+    fn from(opts: &Opts) -> Self {
+        let body = match (&opts.body_string, &opts.body_file) {
+            (Some(body), _) => Some(format!("{} bytes inline", body.len())),
+            (None, Some(path)) => Some(path.display().to_string()),
+            (None, None) => None,
+        };
+        Self {
+            url: opts.url.to_string(),
+            method: opts.method.to_string(),
+            concurrent: opts.concurrent,
+            max_time: opts.max_time.map(|t| t.to_string()),
+            timeout: opts.timeout.map(|t| t.to_string()),
+            requests: opts.requests.map(|n| n.get()),
+            rate: opts.rate.map(|r| r.get()),
+            profile: opts.profile.as_ref().map(|profile| {
+                profile
+                    .stages
+                    .iter()
+                    .map(|stage| stage.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }),
+            profile_file: opts.profile_file.as_ref().map(|p| p.display().to_string()),
+            profile_target: format!("{:?}", opts.profile_target).to_lowercase(),
+            host: opts.host.map(|h| h.to_string()),
+            headers: opts.header.iter().map(HeaderListItem::redacted).collect(),
+            body,
+            content_type: opts.content_type.as_ref().map(|c| c.to_string()),
+            ipv4: opts.ipv4,
+            ipv6: opts.ipv6,
+            http_version: format!("{:?}", opts.http_version).to_lowercase(),
+            proxy: opts.proxy.as_ref().map(without_credentials),
+            tls: TlsOptions::from(opts).summary(),
+            mix: opts.mix.as_ref().map(|p| p.display().to_string()),
+            scenario: opts.scenario.as_ref().map(|p| p.display().to_string()),
+            feed: opts.feed.as_ref().map(|p| p.display().to_string()),
+            feed_format: opts.feed.as_deref().map(|path| {
+                let format = opts
+                    .feed_format
+                    .unwrap_or_else(|| FeedFormat::from_path(path));
+                format!("{:?}", format).to_lowercase()
+            }),
+            feed_order: opts
+                .feed
+                .as_ref()
+                .map(|_| format!("{:?}", opts.feed_order).to_lowercase()),
+            checks: checks(opts),
+            seed: opts.seed,
+        }
+    }
+}
+
+fn checks(opts: &Opts) -> Vec<String> {
+    let mut checks = Vec::new();
+    if let Some(status) = &opts.expect_status {
+        checks.push(format!("status {}", status));
+    }
+    checks.extend(opts.expect_headers.iter().map(|h| format!("header {}", h)));
+    checks.extend(opts.expect_body.iter().map(|b| format!("body {}", b)));
+    checks.extend(
+        opts.expect_body_regex
+            .iter()
+            .map(|r| format!("body_regex {}", r)),
+    );
+    checks.extend(opts.expect_json.iter().map(|j| format!("json {}", j)));
+    if let Some(size) = opts.max_body_size {
+        checks.push(format!("body_size {}", size));
+    }
+    if let Some(latency) = &opts.max_latency {
+        checks.push(format!("latency {}", latency));
+    }
+    checks
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PercentileReport {
+    pub percentile: f64,
+    pub value_us: u64,
+}
+
+/// Summary of one latency histogram, all values in microseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct DistributionReport {
+    pub samples: u64,
+    pub min_us: u64,
+    pub mean_us: f64,
+    pub stdev_us: f64,
+    pub max_us: u64,
+    pub percentiles: Vec<PercentileReport>,
+}
+
+impl From<&Histogram<u64>> for DistributionReport {
+    fn from(histogram: &Histogram<u64>) -> Self {
+        if histogram.is_empty() {
+            return Self {
+                samples: 0,
+                min_us: 0,
+                mean_us: 0.0,
+                stdev_us: 0.0,
+                max_us: 0,
+                percentiles: Vec::new(),
+            };
+        }
+        Self {
+            samples: histogram.len(),
+            min_us: histogram.min(),
+            mean_us: histogram.mean(),
+            stdev_us: histogram.stdev(),
+            max_us: histogram.max(),
+            percentiles: REPORT_PERCENTILES
+                .iter()
+                .map(|p| PercentileReport {
+                    percentile: *p,
+                    value_us: histogram.value_at_percentile(*p),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyReport {
+    pub connect: DistributionReport,
+    pub ttfb: DistributionReport,
+    pub full: DistributionReport,
+    /// Only present in constant-rate mode
+    pub corrected: Option<DistributionReport>,
+}
+
+impl From<&PhaseHistograms> for LatencyReport {
+    fn from(histograms: &PhaseHistograms) -> Self {
+        Self {
+            connect: (&histograms.connect).into(),
+            ttfb: (&histograms.ttfb).into(),
+            full: (&histograms.full).into(),
+            corrected: (!histograms.corrected.is_empty()).then(|| (&histograms.corrected).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleReport {
+    pub slots: u64,
+    pub late: u64,
+    pub mean_lag_us: u64,
+    pub max_lag_us: u64,
+}
+
+impl From<&RateLimiter> for ScheduleReport {
+    fn from(rate_limiter: &RateLimiter) -> Self {
+        Self {
+            slots: rate_limiter.slot_count(),
+            late: rate_limiter.late_count(),
+            mean_lag_us: rate_limiter.mean_lag(),
+            max_lag_us: rate_limiter.max_lag(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BudgetReport {
+    pub total: u64,
+    pub completed: u64,
+    pub fully_completed: bool,
+}
+
+impl From<&RequestBudget> for BudgetReport {
+    fn from(budget: &RequestBudget) -> Self {
+        Self {
+            total: budget.total(),
+            completed: budget.completed(),
+            fully_completed: budget.is_complete(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CountReport {
+    pub name: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Http3Report {
+    #[serde(flatten)]
+    pub streams: MultiplexReport,
+    pub zero_rtt: ZeroRttReport,
+}
+
+impl Http3Report {
+    /// Adds up the streams and 0-RTT counts of the HTTP/3 origins, `None` if the run had none.
+    fn combine(reports: impl Iterator<Item = Http3Report>) -> Option<Self> {
+        let reports = reports.collect::<Vec<_>>();
+        Some(Http3Report {
+            streams: MultiplexReport::combine(reports.iter().map(|r| r.streams.clone()))?,
+            zero_rtt: ZeroRttReport {
+                attempts: reports.iter().map(|r| r.zero_rtt.attempts).sum(),
+                accepted: reports.iter().map(|r| r.zero_rtt.accepted).sum(),
+            },
+        })
+    }
+}
+
+/// Everything known about a finished run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub options: RunOptions,
+    pub address: SocketAddr,
+    pub started_at: String,
+    pub duration_secs: f64,
+    pub total_requests: u64,
+    pub requests_per_second: f64,
+    pub bytes_out: u64,
+    pub bytes_in: u64,
+    /// Per [`ClientResponseCodeType`]
+    pub responses: Vec<CountReport>,
+    pub status_codes: Vec<CountReport>,
+    pub errors: Vec<CountReport>,
+    /// Responses that failed a check, per [`CheckKind`](crate::check::CheckKind)
+    pub failed_checks: Vec<CountReport>,
+    pub latency: LatencyReport,
+    pub schedule: Option<ScheduleReport>,
+    pub budget: Option<BudgetReport>,
+    /// Only present when the run spoke HTTP/2
+    pub http2: Option<MultiplexReport>,
+    /// Only present when the run spoke HTTP/3
+    pub http3: Option<Http3Report>,
+    /// Only present when the run went through a proxy
+    pub proxy: Option<ProxyReport>,
+    /// Per endpoint, only present when the run had a mix of them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<EndpointReport>,
+    /// Per-second samples, only written in the JSON report
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub intervals: Vec<IntervalSample>,
+}
+
+impl RunReport {
+    pub fn collect(
+        options: RunOptions,
+        work_instance: &WorkInstance,
+        started_at: chrono::DateTime<chrono::Local>,
+        duration: Duration,
+    ) -> Self {
+        let counter = &work_instance.request_counter;
+        let total_requests = ClientResponseCodeType::ALL
+            .iter()
+            .map(|code_type| counter.get_cumulative(*code_type))
+            .sum::<u64>();
+        let duration_secs = duration.as_secs_f64();
+        let (bytes_out, bytes_in) = counter.total_bytes();
+        Self {
+            options,
+            address: work_instance.origins[0].address,
+            started_at: started_at.to_rfc3339(),
+            duration_secs,
+            total_requests,
+            requests_per_second: if duration_secs > 0.0 {
+                total_requests as f64 / duration_secs
+            } else {
+                0.0
+            },
+            bytes_out,
+            bytes_in,
+            responses: ClientResponseCodeType::ALL
+                .iter()
+                .map(|code_type| CountReport {
+                    name: code_type.name().to_string(),
+                    count: counter.get_cumulative(*code_type),
+                })
+                .collect(),
+            status_codes: counter
+                .status_codes()
+                .into_iter()
+                .map(|(status, count)| CountReport {
+                    name: status.to_string(),
+                    count,
+                })
+                .collect(),
+            errors: counter
+                .failures()
+                .into_iter()
+                .map(|(kind, count)| CountReport {
+                    name: kind.name().to_string(),
+                    count,
+                })
+                .collect(),
+            failed_checks: counter
+                .check_failures()
+                .into_iter()
+                .map(|(kind, count)| CountReport {
+                    name: kind.name().to_string(),
+                    count,
+                })
+                .collect(),
+            latency: (&work_instance.latency.total()).into(),
+            schedule: work_instance.rate_limiter.as_ref().map(Into::into),
+            budget: work_instance.budget.as_ref().map(Into::into),
+            http2: MultiplexReport::combine(work_instance.origins.iter().filter_map(|origin| {
+                match &origin.connection_pool {
+                    ConnectionPool::Http2(pool) => Some(pool.pool.stats.report()),
+                    _ => None,
+                }
+            })),
+            http3: Http3Report::combine(work_instance.origins.iter().filter_map(|origin| {
+                match &origin.connection_pool {
+                    ConnectionPool::Http3(pool) => Some(Http3Report {
+                        streams: pool.pool.stats.report(),
+                        zero_rtt: pool.zero_rtt_stats.report(),
+                    }),
+                    _ => None,
+                }
+            })),
+            proxy: work_instance
+                .proxy
+                .as_ref()
+                .map(|proxy| proxy.stats.report()),
+            endpoints: work_instance.mix.report(),
+            intervals: work_instance.time_series.samples(),
+        }
+    }
+
+    /// The totals in human-readable form, one line each.
+    pub fn summary(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "Summary: {} requests in {:.2}s, {:.2} requests/s",
+                self.total_requests, self.duration_secs, self.requests_per_second
+            ),
+            format!("  {}", join_counts(&self.responses)),
+        ];
+        if !self.status_codes.is_empty() {
+            lines.push(format!(
+                "  Status codes: {}",
+                join_counts(&self.status_codes)
+            ));
+        }
+        let errors = self
+            .errors
+            .iter()
+            .filter(|e| e.count > 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            lines.push(format!("  Errors: {}", join_counts(&errors)));
+        }
+        let failed_checks = self
+            .failed_checks
+            .iter()
+            .filter(|c| c.count > 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !failed_checks.is_empty() {
+            lines.push(format!("  Failed checks: {}", join_counts(&failed_checks)));
+        }
+        lines.join("\n")
+    }
+
+    /// Flattens the report into `section, metric, value` rows.
+    fn rows(&self) -> Vec<ReportRow> {
+        let mut rows = Vec::new();
+        let mut push = |section: &str, metric: String, value: String| {
+            rows.push(ReportRow {
+                section: section.to_string(),
+                metric,
+                value,
+            })
+        };
+
+        let options = &self.options;
+        push("options", "url".into(), options.url.clone());
+        push("options", "method".into(), options.method.clone());
+        push(
+            "options",
+            "concurrent".into(),
+            options.concurrent.to_string(),
+        );
+        push(
+            "options",
+            "max_time".into(),
+            display_option(&options.max_time),
+        );
+        push(
+            "options",
+            "timeout".into(),
+            display_option(&options.timeout),
+        );
+        push(
+            "options",
+            "requests".into(),
+            display_option(&options.requests),
+        );
+        push("options", "rate".into(), display_option(&options.rate));
+        push(
+            "options",
+            "profile".into(),
+            display_option(&options.profile),
+        );
+        push(
+            "options",
+            "profile_file".into(),
+            display_option(&options.profile_file),
+        );
+        push(
+            "options",
+            "profile_target".into(),
+            options.profile_target.clone(),
+        );
+        push("options", "host".into(), display_option(&options.host));
+        push("options", "headers".into(), options.headers.join("; "));
+        push("options", "body".into(), display_option(&options.body));
+        push(
+            "options",
+            "content_type".into(),
+            display_option(&options.content_type),
+        );
+        push("options", "ipv4".into(), options.ipv4.to_string());
+        push("options", "ipv6".into(), options.ipv6.to_string());
+        push("options", "proxy".into(), display_option(&options.proxy));
+        push(
+            "options",
+            "http_version".into(),
+            options.http_version.clone(),
+        );
+        push("options", "tls".into(), options.tls.clone());
+        push("options", "mix".into(), display_option(&options.mix));
+        push(
+            "options",
+            "scenario".into(),
+            display_option(&options.scenario),
+        );
+        push("options", "feed".into(), display_option(&options.feed));
+        push(
+            "options",
+            "feed_format".into(),
+            display_option(&options.feed_format),
+        );
+        push(
+            "options",
+            "feed_order".into(),
+            display_option(&options.feed_order),
+        );
+        push("options", "checks".into(), options.checks.join("; "));
+        push("options", "seed".into(), display_option(&options.seed));
+
+        push("run", "address".into(), self.address.to_string());
+        push("run", "started_at".into(), self.started_at.clone());
+        push(
+            "run",
+            "duration_secs".into(),
+            format!("{:.3}", self.duration_secs),
+        );
+        push(
+            "run",
+            "total_requests".into(),
+            self.total_requests.to_string(),
+        );
+        push(
+            "run",
+            "requests_per_second".into(),
+            format!("{:.3}", self.requests_per_second),
+        );
+        push("run", "bytes_out".into(), self.bytes_out.to_string());
+        push("run", "bytes_in".into(), self.bytes_in.to_string());
+
+        for count in &self.responses {
+            push("responses", count.name.clone(), count.count.to_string());
+        }
+        for count in &self.status_codes {
+            push("status_codes", count.name.clone(), count.count.to_string());
+        }
+        for count in &self.errors {
+            push("errors", count.name.clone(), count.count.to_string());
+        }
+        for count in &self.failed_checks {
+            push("failed_checks", count.name.clone(), count.count.to_string());
+        }
+
+        let mut distributions = vec![
+            ("latency_connect", &self.latency.connect),
+            ("latency_ttfb", &self.latency.ttfb),
+            ("latency_full", &self.latency.full),
+        ];
+        if let Some(corrected) = &self.latency.corrected {
+            distributions.push(("latency_corrected", corrected));
+        }
+        for (section, distribution) in distributions {
+            push(section, "samples".into(), distribution.samples.to_string());
+            push(section, "min_us".into(), distribution.min_us.to_string());
+            push(
+                section,
+                "mean_us".into(),
+                format!("{:.1}", distribution.mean_us),
+            );
+            push(
+                section,
+                "stdev_us".into(),
+                format!("{:.1}", distribution.stdev_us),
+            );
+            push(section, "max_us".into(), distribution.max_us.to_string());
+            for percentile in &distribution.percentiles {
+                push(
+                    section,
+                    format!("p{}_us", percentile.percentile),
+                    percentile.value_us.to_string(),
+                );
+            }
+        }
+
+        if let Some(schedule) = &self.schedule {
+            push("schedule", "slots".into(), schedule.slots.to_string());
+            push("schedule", "late".into(), schedule.late.to_string());
+            push(
+                "schedule",
+                "mean_lag_us".into(),
+                schedule.mean_lag_us.to_string(),
+            );
+            push(
+                "schedule",
+                "max_lag_us".into(),
+                schedule.max_lag_us.to_string(),
+            );
+        }
+        if let Some(budget) = &self.budget {
+            push("budget", "total".into(), budget.total.to_string());
+            push("budget", "completed".into(), budget.completed.to_string());
+            push(
+                "budget",
+                "fully_completed".into(),
+                budget.fully_completed.to_string(),
+            );
+        }
+        if let Some(http2) = &self.http2 {
+            http2.push_rows(|metric, value| push("http2", metric, value));
+        }
+        if let Some(http3) = &self.http3 {
+            http3
+                .streams
+                .push_rows(|metric, value| push("http3", metric, value));
+            push(
+                "http3",
+                "zero_rtt_attempts".into(),
+                http3.zero_rtt.attempts.to_string(),
+            );
+            push(
+                "http3",
+                "zero_rtt_accepted".into(),
+                http3.zero_rtt.accepted.to_string(),
+            );
+        }
+        if let Some(proxy) = &self.proxy {
+            proxy.push_rows(|metric, value| push("proxy", metric, value));
+        }
+        for endpoint in &self.endpoints {
+            let section = format!("endpoint {}", endpoint.name);
+            endpoint.push_rows(|metric, value| push(&section, metric, value));
+        }
+        rows
+    }
+
+    pub fn write(&self, format: OutputFormat, writer: impl Write) -> Result<(), WriteReportError> {
+        match format {
+            OutputFormat::Json => {
+                let mut writer = writer;
+                serde_json::to_writer_pretty(&mut writer, self)?;
+                writeln!(writer)?;
+            }
+            OutputFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                for row in self.rows() {
+                    writer.serialize(row)?;
+                }
+                writer.flush()?;
+            }
+            OutputFormat::Markdown => self.write_markdown(writer)?,
+        }
+        Ok(())
+    }
+
+    fn write_markdown(&self, mut writer: impl Write) -> std::io::Result<()> {
+        writeln!(writer, "# ubw run report")?;
+        let mut current_section = None;
+        for row in self.rows() {
+            if current_section.as_ref() != Some(&row.section) {
+                writeln!(writer)?;
+                writeln!(writer, "## {}", row.section)?;
+                writeln!(writer)?;
+                writeln!(writer, "| metric | value |")?;
+                writeln!(writer, "| --- | --- |")?;
+                current_section = Some(row.section.clone());
+            }
+            writeln!(
+                writer,
+                "| {} | {} |",
+                escape_markdown(&row.metric),
+                escape_markdown(&row.value)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the report to `path`, or to stdout if there is none.
+    pub fn write_to(
+        &self,
+        format: OutputFormat,
+        path: Option<&Path>,
+    ) -> Result<(), WriteReportError> {
+        match path {
+            Some(path) => {
+                let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+                self.write(format, file)
+            }
+            None => self.write(format, std::io::stdout().lock()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReportRow {
+    section: String,
+    metric: String,
+    value: String,
+}
+
+fn display_option<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(ToString::to_string).unwrap_or_default()
+}
+
+fn escape_markdown(s: &str) -> String {
+    s.replace('|', "\\|")
+}
+
+fn join_counts(counts: &[CountReport]) -> String {
+    counts
+        .iter()
+        .map(|c| format!("{}: {}", c.name, c.count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::WorkInstanceBuilder;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn test_json_and_csv_read_back() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let opts = Opts::try_parse_from([
+            "ubw",
+            "-u",
+            "http://127.0.0.1:1/",
+            "--scenario",
+            "session.toml",
+            "--feed",
+            "users.csv",
+            "--feed-order",
+            "random",
+            "--expect-status",
+            "2xx,304",
+            "--expect-json",
+            "/ok=true",
+            "--seed",
+            "7",
+            "-H",
+            "Authorization: Bearer secret",
+            "-H",
+            "X-Mode: a",
+        ])?;
+        // Nothing is sent, the report only needs the counters of a work instance
+        let run = WorkInstanceBuilder::new("http://127.0.0.1:1/".parse()?)?
+            .build()
+            .await?;
+        let report = RunReport::collect(
+            RunOptions::from(&opts),
+            &run.work_instance,
+            chrono::Local::now(),
+            Duration::from_secs(1),
+        );
+
+        let mut json = Vec::new();
+        report.write(OutputFormat::Json, &mut json)?;
+        let json: serde_json::Value = serde_json::from_slice(&json)?;
+        let options = &json["options"];
+        assert_eq!(options["scenario"], "session.toml");
+        assert_eq!(options["mix"], serde_json::Value::Null);
+        assert_eq!(options["feed"], "users.csv");
+        assert_eq!(options["feed_format"], "csv");
+        assert_eq!(options["feed_order"], "random");
+        assert_eq!(
+            options["checks"],
+            serde_json::json!(["status 2xx,304", "json /ok=true"])
+        );
+        assert_eq!(options["seed"], 7);
+        // Credentials stay out of the report
+        assert_eq!(
+            options["headers"],
+            serde_json::json!(["Authorization: [redacted]", "X-Mode: a"])
+        );
+        assert_eq!(json["total_requests"], 0);
+
+        let mut csv = Vec::new();
+        report.write(OutputFormat::Csv, &mut csv)?;
+        let rows = csv::Reader::from_reader(&csv[..])
+            .records()
+            .map(|record| Ok(record?.iter().map(str::to_string).collect::<Vec<_>>()))
+            .collect::<Result<Vec<_>, csv::Error>>()?;
+        let value = |section: &str, metric: &str| {
+            rows.iter()
+                .find(|row| row[0] == section && row[1] == metric)
+                .map(|row| row[2].clone())
+        };
+        assert_eq!(
+            value("options", "scenario").as_deref(),
+            Some("session.toml")
+        );
+        assert_eq!(value("options", "mix").as_deref(), Some(""));
+        assert_eq!(value("options", "feed_order").as_deref(), Some("random"));
+        assert_eq!(
+            value("options", "checks").as_deref(),
+            Some("status 2xx,304; json /ok=true")
+        );
+        assert_eq!(value("options", "seed").as_deref(), Some("7"));
+        assert_eq!(value("run", "total_requests").as_deref(), Some("0"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_markdown_tables() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let opts = Opts::try_parse_from(["ubw", "-u", "http://127.0.0.1:1/", "-H", "X-Mode: a|b"])?;
+        let run = WorkInstanceBuilder::new("http://127.0.0.1:1/".parse()?)?
+            .build()
+            .await?;
+        run.work_instance
+            .request_counter
+            .record_status(hyper::StatusCode::OK);
+        run.work_instance
+            .request_counter
+            .record_status(hyper::StatusCode::OK);
+        let report = RunReport::collect(
+            RunOptions::from(&opts),
+            &run.work_instance,
+            chrono::Local::now(),
+            Duration::from_secs(1),
+        );
+        let mut markdown = Vec::new();
+        report.write(OutputFormat::Markdown, &mut markdown)?;
+        let markdown = String::from_utf8(markdown)?;
+        let mut lines = markdown.lines();
+        assert_eq!(lines.next(), Some("# ubw run report"));
+        // Every section is a table of its own, under its heading
+        let sections = markdown
+            .split("\n## ")
+            .skip(1)
+            .map(|section| section.lines().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(sections[0][0], "options");
+        assert!(sections.iter().any(|section| section[0] == "status_codes"));
+        for section in &sections {
+            assert_eq!(section[1..4], ["", "| metric | value |", "| --- | --- |"]);
+            assert!(
+                section[4..]
+                    .iter()
+                    .all(|row| row.starts_with("| ") && row.ends_with(" |"))
+            );
+        }
+        // A pipe in a value does not end the cell
+        assert!(sections[0].contains(&"| headers | X-Mode: a\\|b |"));
+        assert!(sections.iter().flatten().any(|row| *row == "| 200 | 2 |"));
+        Ok(())
+    }
+}
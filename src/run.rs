@@ -0,0 +1,503 @@
+use crate::UbwError;
+use crate::before_request::prepare_work_instance;
+use crate::check::StatusSet;
+use crate::client::{self, HttpVersion, WorkInstance};
+use crate::opts::{HeaderListItem, Opts};
+use crate::profile::LoadProfile;
+use crate::report::{RunOptions, RunReport};
+use crate::timeseries::IntervalSample;
+use crate::work_mode::counter_print;
+use clap::Parser;
+use hyper::Method;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use url::Url;
+
+/// Called with the statistics of every second while the run is going.
+pub type ProgressCallback = Arc<dyn Fn(&IntervalSample) + Send + Sync>;
+
+/// Sets up a run from code instead of the command line.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let run = ubw::run::WorkInstanceBuilder::new("http://localhost:8080/".parse()?)?
+///     .concurrent(16)
+///     .max_time(std::time::Duration::from_secs(10))
+///     .on_progress(|sample| println!("{} requests/s", sample.requests_per_second))
+///     .build()
+///     .await?;
+/// let report = run.run().await;
+/// assert_eq!(report.errors.iter().map(|e| e.count).sum::<u64>(), 0);
+/// # Ok(())
+/// # }
+/// ```
+pub struct WorkInstanceBuilder {
+    opts: Opts,
+    quiet: bool,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl WorkInstanceBuilder {
+    /// Starts from the command line defaults, with nothing printed during the run.
+    pub fn new(url: Url) -> Result<Self, UbwError> {
+        let opts = Opts::try_parse_from(["ubw", "-u", url.as_str()])?;
+        Ok(Self {
+            opts,
+            quiet: true,
+            on_progress: None,
+        })
+    }
+
+    /// Starts from parsed options, printing the progress unless the dashboard is asked for.
+    pub fn from_opts(opts: Opts) -> Self {
+        Self {
+            quiet: opts.dashboard,
+            opts,
+            on_progress: None,
+        }
+    }
+
+    /// Starts from command line style arguments, the program name first.
+    pub fn from_args<I, T>(args: I) -> Result<Self, UbwError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Ok(Self {
+            quiet: true,
+            ..Self::from_opts(Opts::try_parse_from(args)?)
+        })
+    }
+
+    pub fn concurrent(mut self, concurrent: u16) -> Self {
+        self.opts.concurrent = concurrent;
+        self
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.opts.method = method;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.opts.header.push(HeaderListItem {
+            header_name: name.into(),
+            header_value: value.into(),
+        });
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.opts.body_string = Some(body.into());
+        self
+    }
+
+    pub fn max_time(mut self, max_time: Duration) -> Self {
+        self.opts.max_time = Some(max_time.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.opts.timeout = Some(timeout.into());
+        self
+    }
+
+    pub fn requests(mut self, requests: NonZeroU64) -> Self {
+        self.opts.requests = Some(requests);
+        self
+    }
+
+    pub fn rate(mut self, rate: NonZeroU32) -> Self {
+        self.opts.rate = Some(rate);
+        self
+    }
+
+    pub fn profile(mut self, profile: LoadProfile) -> Self {
+        self.opts.profile = Some(profile);
+        self
+    }
+
+    pub fn http_version(mut self, http_version: HttpVersion) -> Self {
+        self.opts.http_version = http_version;
+        self
+    }
+
+    pub fn expect_status(mut self, status: StatusSet) -> Self {
+        self.opts.expect_status = Some(status);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.opts.seed = Some(seed);
+        self
+    }
+
+    /// Changes any other option.
+    pub fn opts(mut self, f: impl FnOnce(&mut Opts)) -> Self {
+        f(&mut self.opts);
+        self
+    }
+
+    /// Keeps the per-second lines and stage changes from being printed.
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    pub fn on_progress(
+        mut self,
+        on_progress: impl Fn(&IntervalSample) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+
+    /// Resolves the host and reads every file the options name, nothing is sent yet.
+    pub async fn build(self) -> Result<Run, UbwError> {
+        let options = RunOptions::from(&self.opts);
+        let max_time = self.opts.max_time.map(Into::into);
+        let mut work_instance = prepare_work_instance(self.opts).await?;
+        work_instance.quiet = self.quiet;
+        let (shutdown_tx, _) = watch::channel(false);
+        Ok(Run {
+            work_instance: Arc::new(work_instance),
+            options,
+            max_time,
+            shutdown_tx,
+            on_progress: self.on_progress,
+        })
+    }
+}
+
+/// Stops a run early, the run still returns its report.
+#[derive(Debug, Clone)]
+pub struct CancelHandle(watch::Sender<bool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// The channel the run stops on, for tasks that have to notice the shutdown as well.
+    pub fn sender(&self) -> &watch::Sender<bool> {
+        &self.0
+    }
+}
+
+/// A run that is ready to go.
+pub struct Run {
+    pub work_instance: Arc<WorkInstance>,
+    options: RunOptions,
+    /// Stop after this long
+    max_time: Option<Duration>,
+    shutdown_tx: watch::Sender<bool>,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl Run {
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle(self.shutdown_tx.clone())
+    }
+
+    /// Sends requests until the time limit, the request count or the load profile ends the run, or it is cancelled.
+    pub async fn run(self) -> RunReport {
+        let work_instance = self.work_instance;
+        let mut handlers = JoinSet::<()>::new();
+        let started_at = chrono::Local::now();
+        let start = Instant::now();
+
+        for worker_id in 0..work_instance.worker_count {
+            let work_instance = work_instance.clone();
+            let mut shutdown_rx = self.shutdown_tx.subscribe();
+            handlers.spawn(async move {
+                if let Err(e) =
+                    client::request_loop(work_instance.clone(), worker_id, &mut shutdown_rx).await
+                {
+                    work_instance
+                        .worker_errors
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(format!("Worker {worker_id} stopped: {e}"));
+                }
+            });
+        }
+
+        // Everything but the workers, aborted once they are done
+        let mut tasks = JoinSet::<()>::new();
+        let arc_for_counter_monitor = work_instance.clone();
+        let mut shutdown_sig_for_counter_monitor = self.shutdown_tx.subscribe();
+        let on_progress = self.on_progress.clone();
+        tasks.spawn(async move {
+            counter_print(
+                &arc_for_counter_monitor,
+                &mut shutdown_sig_for_counter_monitor,
+                on_progress.as_ref(),
+            )
+            .await
+        });
+
+        if work_instance.profile.is_some() {
+            let arc_for_profile = work_instance.clone();
+            let shutdown_tx_for_profile = self.shutdown_tx.clone();
+            tasks.spawn(async move {
+                if let Some(profile) = &arc_for_profile.profile {
+                    profile
+                        .drive(
+                            arc_for_profile.active_clock(),
+                            arc_for_profile.rate_limiter.as_ref(),
+                            shutdown_tx_for_profile,
+                            arc_for_profile.quiet,
+                        )
+                        .await;
+                }
+            });
+        }
+
+        if work_instance.budget.is_some() {
+            let arc_for_budget = work_instance.clone();
+            let shutdown_tx_for_budget = self.shutdown_tx.clone();
+            tasks.spawn(async move {
+                if let Some(budget) = &arc_for_budget.budget {
+                    budget.wait_until_released().await;
+                    let _ = shutdown_tx_for_budget.send(true);
+                }
+            });
+        }
+
+        if let Some(max_time) = self.max_time {
+            let arc_for_timer = work_instance.clone();
+            let shutdown_tx_for_timer = self.shutdown_tx.clone();
+            tasks.spawn(async move {
+                arc_for_timer.sleep_unpaused(max_time).await;
+                let _ = shutdown_tx_for_timer.send(true);
+            });
+        }
+
+        // `self.shutdown_tx` is still alive, so this only returns once the run is stopped, or once every
+        // worker has stopped on its own
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        tokio::select! {
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown) => {}
+            _ = async { while handlers.join_next().await.is_some() {} } => {}
+        }
+        self.shutdown_tx.send_replace(true);
+        while handlers.join_next().await.is_some() {}
+        tasks.abort_all();
+        while tasks.join_next().await.is_some() {}
+
+        RunReport::collect(self.options, &work_instance, started_at, start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TestResult, read_head, serve, serve_ok, serve_silent, temp_file};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_run_returns_report() -> TestResult {
+        let address = serve_ok().await?;
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .concurrent(2)
+            .requests(NonZeroU64::new(20).ok_or("zero")?)
+            .build()
+            .await?;
+        let report = run.run().await;
+        assert_eq!(report.total_requests, 20);
+        assert_eq!(report.status_codes.iter().map(|c| c.count).sum::<u64>(), 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_run() -> TestResult {
+        let address = serve_ok().await?;
+        let samples = Arc::new(AtomicUsize::new(0));
+        let samples_in_callback = samples.clone();
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .on_progress(move |_| {
+                samples_in_callback.fetch_add(1, Ordering::Relaxed);
+            })
+            .build()
+            .await?;
+        let cancel_handle = run.cancel_handle();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            cancel_handle.cancel();
+        });
+        let report = tokio::time::timeout(Duration::from_secs(10), run.run()).await?;
+        assert!(report.total_requests > 0);
+        // A second sample may fit in on a slow machine
+        assert!(samples.load(Ordering::Relaxed) >= 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pause_holds_time_limit() -> TestResult {
+        let address = serve_ok().await?;
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .max_time(Duration::from_millis(200))
+            .quiet(true)
+            .build()
+            .await?;
+        let work_instance = run.work_instance.clone();
+        work_instance.set_paused(true);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            work_instance.set_paused(false);
+        });
+        let start = Instant::now();
+        let report = tokio::time::timeout(Duration::from_secs(10), run.run()).await?;
+        assert!(start.elapsed() >= Duration::from_millis(800));
+        assert!(report.total_requests > 0);
+        Ok(())
+    }
+
+    /// An HTTP/1.1 server that records the method and body of every request.
+    /// HEAD is answered with a `content-length` but no body, as the spec has it.
+    #[allow(clippy::type_complexity)]
+    async fn serve_recording() -> std::io::Result<(
+        std::net::SocketAddr,
+        Arc<std::sync::Mutex<Vec<(String, String)>>>,
+    )> {
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let address = serve(move |mut stream| {
+            let recorded = recorded.clone();
+            async move {
+                while let Ok(head) = read_head(&mut stream).await {
+                    let method = head.split(' ').next().unwrap_or_default().to_string();
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length: ")?
+                                .parse()
+                                .ok()
+                        })
+                        .unwrap_or(0);
+                    let mut body = vec![0; length];
+                    if stream.read_exact(&mut body).await.is_err() {
+                        return;
+                    }
+                    let response: &[u8] = if method == "HEAD" {
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n"
+                    } else {
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok"
+                    };
+                    recorded
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push((method, String::from_utf8_lossy(&body).into_owned()));
+                    if stream.write_all(response).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        })
+        .await?;
+        Ok((address, requests))
+    }
+
+    #[tokio::test]
+    async fn test_head_and_custom_method() -> TestResult {
+        let (address, requests) = serve_recording().await?;
+        // hyper knows the request was HEAD and ends the body at the head, the 2 announced bytes never come
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .method(Method::HEAD)
+            .timeout(Duration::from_secs(2))
+            .requests(NonZeroU64::new(3).ok_or("zero")?)
+            .quiet(true)
+            .build()
+            .await?;
+        let report = run.run().await;
+        assert_eq!(report.status_codes.iter().map(|c| c.count).sum::<u64>(), 3);
+
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .method(Method::from_bytes(b"PURGE")?)
+            .body("key=1")
+            .requests(NonZeroU64::new(1).ok_or("zero")?)
+            .quiet(true)
+            .build()
+            .await?;
+        let report = run.run().await;
+        assert_eq!(report.status_codes.iter().map(|c| c.count).sum::<u64>(), 1);
+
+        let requests = requests.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        assert_eq!(requests.len(), 4);
+        assert!(
+            requests[..3]
+                .iter()
+                .all(|(method, body)| method == "HEAD" && body.is_empty())
+        );
+        assert_eq!(requests[3], ("PURGE".to_string(), "key=1".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bad_feed_row_is_a_feed_failure() -> TestResult {
+        let address = serve_ok().await?;
+        let path = temp_file("bad-row.jsonl", "{\"id\":1}\nnot json\n{\"id\":2}\n")?;
+        let run =
+            WorkInstanceBuilder::new(format!("http://{}/?id={{{{feed id}}}}", address).parse()?)?
+                .requests(NonZeroU64::new(3).ok_or("zero")?)
+                .opts(|opts| opts.feed = Some(path.clone()))
+                .quiet(true)
+                .build()
+                .await?;
+        let report = run.run().await;
+        std::fs::remove_file(path)?;
+        assert_eq!(report.status_codes.iter().map(|c| c.count).sum::<u64>(), 2);
+        assert!(
+            report
+                .errors
+                .iter()
+                .any(|e| e.name == "feed" && e.count == 1)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_time_limit_cuts_the_budget_short() -> TestResult {
+        let address = serve_silent().await?;
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .concurrent(4)
+            .requests(NonZeroU64::new(4).ok_or("zero")?)
+            .max_time(Duration::from_millis(300))
+            .quiet(true)
+            .build()
+            .await?;
+        let report = tokio::time::timeout(Duration::from_secs(10), run.run()).await?;
+        let budget = report.budget.ok_or("no budget in the report")?;
+        assert_eq!(budget.completed, 0);
+        assert!(!budget.fully_completed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_requests_count_in_the_corrected_latency() -> TestResult {
+        let address = serve_silent().await?;
+        let run = WorkInstanceBuilder::new(format!("http://{}/", address).parse()?)?
+            .rate(NonZeroU32::new(20).ok_or("zero")?)
+            .timeout(Duration::from_millis(100))
+            .requests(NonZeroU64::new(3).ok_or("zero")?)
+            .quiet(true)
+            .build()
+            .await?;
+        let report = tokio::time::timeout(Duration::from_secs(10), run.run()).await?;
+        // Every request timed out, the time until then is still what the scheduled requests waited
+        assert_eq!(report.latency.full.samples, 0);
+        let corrected = report.latency.corrected.ok_or("no corrected latency")?;
+        assert_eq!(corrected.samples, 3);
+        assert!(corrected.min_us >= 100_000);
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+use crate::mix::RequestEntry;
+use bytes::Bytes;
+use hyper::http;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// A scenario file, the steps of one user session that every worker goes through over and over.
+///
+/// ```toml
+/// think_time = "1s"
+///
+/// [[steps]]
+/// name = "login"
+/// method = "POST"
+/// url = "/login"
+/// body = '{"user": "{{feed user}}", "password": "secret"}'
+/// content_type = "application/json"
+/// extract = { token = { json = "/token" } }
+///
+/// [[steps]]
+/// name = "profile"
+/// url = "/me"
+/// headers = { Authorization = "Bearer {{var token}}" }
+/// repeat = 3
+/// think_time = "200ms"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioFile {
+    /// Pause after every step that has no think time of its own
+    pub think_time: Option<String>,
+    pub steps: Vec<StepEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StepEntry {
+    #[serde(flatten)]
+    pub request: RequestEntry,
+    /// Session variables to take from the response, used as `{{var <name>}}` in later steps
+    #[serde(default)]
+    pub extract: BTreeMap<String, Extract>,
+    pub think_time: Option<String>,
+    /// Send the step this many times in a row
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+    /// Keys neither the request nor the step knows, `deny_unknown_fields` does not work with `flatten`
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, toml::Value>,
+}
+
+fn default_repeat() -> u32 {
+    1
+}
+
+/// Where a session variable comes from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Extract {
+    /// A JSON pointer into the response body, e.g. `/data/token`
+    Json(String),
+    /// A response header
+    Header(String),
+}
+
+impl Extract {
+    fn apply(&self, response: &http::response::Parts, body: &[u8]) -> Option<String> {
+        match self {
+            Extract::Json(pointer) => {
+                let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+                match value.pointer(pointer)? {
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    value => Some(value.to_string()),
+                }
+            }
+            Extract::Header(name) => response
+                .headers
+                .get(name.as_str())?
+                .to_str()
+                .ok()
+                .map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScenarioError {
+    #[error("Failed to read scenario file {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid scenario file {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("The scenario has no steps")]
+    Empty,
+
+    #[error("Invalid think time {0:?}: {1}")]
+    InvalidThinkTime(String, humantime::DurationError),
+
+    #[error(
+        "The scenario uses the variable {0:?}, but no step before it, nor the step itself if it repeats, extracts it"
+    )]
+    UnknownVariable(String),
+
+    #[error("Unknown key {0:?} in a scenario step")]
+    UnknownStepKey(String),
+}
+
+/// Reads a scenario file, TOML as described at [`ScenarioFile`].
+pub async fn read_scenario_file(path: &std::path::Path) -> Result<ScenarioFile, ScenarioError> {
+    let scenario: ScenarioFile = toml::from_str(&tokio::fs::read_to_string(path).await?)?;
+    if scenario.steps.is_empty() {
+        return Err(ScenarioError::Empty);
+    }
+    Ok(scenario)
+}
+
+impl ScenarioFile {
+    /// Splits the file into the scenario, whose step `i` sends endpoint `i`, and the requests of the steps.
+    pub fn into_scenario(self) -> Result<(Scenario, Vec<RequestEntry>), ScenarioError> {
+        let default_think_time = self
+            .think_time
+            .as_deref()
+            .map(parse_think_time)
+            .transpose()?;
+        let mut steps = Vec::new();
+        let mut requests = Vec::new();
+        for (index, entry) in self.steps.into_iter().enumerate() {
+            if let Some(key) = entry.extra.into_keys().next() {
+                return Err(ScenarioError::UnknownStepKey(key));
+            }
+            steps.push(Step {
+                endpoint: index,
+                extract: entry.extract.into_iter().collect(),
+                think_time: match entry.think_time.as_deref() {
+                    Some(think_time) => Some(parse_think_time(think_time)?),
+                    None => default_think_time,
+                },
+                repeat: entry.repeat.max(1),
+            });
+            requests.push(entry.request);
+        }
+        Ok((Scenario { steps }, requests))
+    }
+}
+
+pub fn parse_think_time(s: &str) -> Result<Duration, ScenarioError> {
+    humantime::parse_duration(s.trim())
+        .map_err(|e| ScenarioError::InvalidThinkTime(s.to_string(), e))
+}
+
+/// One step of the scenario, its request is an endpoint of the run.
+#[derive(Debug)]
+pub struct Step {
+    /// Index into the endpoints of the run
+    pub endpoint: usize,
+    pub extract: Vec<(String, Extract)>,
+    pub think_time: Option<Duration>,
+    pub repeat: u32,
+}
+
+#[derive(Debug)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    /// Checks that every variable step `index` uses is extracted by an earlier step, or by the step itself when it
+    /// repeats. A repeating step sends its own variables empty on its first repetition, as a pagination cursor is.
+    pub fn check_variables<'a>(
+        &self,
+        index: usize,
+        used: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), ScenarioError> {
+        let repeats = self.steps.get(index).is_some_and(|step| step.repeat > 1);
+        let extracting = &self.steps[..(index + usize::from(repeats)).min(self.steps.len())];
+        for name in used {
+            if !extracting
+                .iter()
+                .any(|step| step.extract.iter().any(|(extracted, _)| extracted == name))
+            {
+                return Err(ScenarioError::UnknownVariable(name.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where one worker is in the scenario and the variables it extracted so far.
+///
+/// A step that gets no response ends the session, the next one starts from the first step.
+pub struct Session<'a> {
+    scenario: &'a Scenario,
+    step: usize,
+    repetition: u32,
+    pub vars: HashMap<String, String>,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(scenario: &'a Scenario) -> Self {
+        Self {
+            scenario,
+            step: 0,
+            repetition: 0,
+            vars: HashMap::new(),
+        }
+    }
+
+    /// Index of the endpoint of the current step.
+    pub fn endpoint(&self) -> usize {
+        self.scenario
+            .steps
+            .get(self.step)
+            .map_or(0, |step| step.endpoint)
+    }
+
+    /// Takes the variables out of the response of the current step and moves on to the next one.
+    ///
+    /// Returns how long to think before the next step and how many variables could not be extracted.
+    pub fn complete(
+        &mut self,
+        response: Option<&(http::response::Parts, Bytes)>,
+    ) -> (Option<Duration>, u64) {
+        let Some(step) = self.scenario.steps.get(self.step) else {
+            return (None, 0);
+        };
+        let Some((parts, body)) = response else {
+            self.restart();
+            return (None, 0);
+        };
+        let mut missing = 0;
+        for (name, extract) in &step.extract {
+            match extract.apply(parts, body) {
+                Some(value) => {
+                    self.vars.insert(name.clone(), value);
+                }
+                None => missing += 1,
+            }
+        }
+        self.repetition += 1;
+        if self.repetition >= step.repeat {
+            self.repetition = 0;
+            self.step += 1;
+            if self.step >= self.scenario.steps.len() {
+                self.restart();
+            }
+        }
+        (step.think_time, missing)
+    }
+
+    fn restart(&mut self) {
+        self.step = 0;
+        self.repetition = 0;
+        self.vars.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_extracts_and_loops() -> Result<(), Box<dyn std::error::Error>> {
+        let file: ScenarioFile = toml::from_str(
+            r#"
+            [[steps]]
+            url = "/login"
+            extract = { token = { json = "/auth/token" }, id = { header = "x-id" } }
+
+            [[steps]]
+            url = "/me"
+            repeat = 2
+            think_time = "10ms"
+            "#,
+        )?;
+        let (scenario, requests) = file.into_scenario()?;
+        assert_eq!(requests[1].url, "/me");
+        assert!(scenario.check_variables(1, ["token"]).is_ok());
+        assert!(scenario.check_variables(1, ["nope"]).is_err());
+
+        let response = http::Response::builder().header("x-id", "7").body(())?;
+        let response = (
+            response.into_parts().0,
+            Bytes::from_static(br#"{"auth": {"token": "abc"}}"#),
+        );
+        let mut session = Session::new(&scenario);
+        assert_eq!(session.complete(Some(&response)), (None, 0));
+        assert_eq!(session.vars["token"], "abc");
+        assert_eq!(session.vars["id"], "7");
+        assert_eq!(session.endpoint(), 1);
+        let think_time = Some(Duration::from_millis(10));
+        assert_eq!(session.complete(Some(&response)), (think_time, 0));
+        assert_eq!(session.endpoint(), 1);
+        assert_eq!(session.complete(Some(&response)), (think_time, 0));
+        assert_eq!(session.endpoint(), 0);
+        assert!(session.vars.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_extracted_before_use() -> Result<(), Box<dyn std::error::Error>> {
+        let file: ScenarioFile = toml::from_str(
+            r#"
+            [[steps]]
+            url = "/me"
+
+            [[steps]]
+            url = "/poll"
+            repeat = 3
+            extract = { cursor = { header = "x-cursor" } }
+
+            [[steps]]
+            url = "/login"
+            extract = { token = { json = "/token" } }
+            "#,
+        )?;
+        let (scenario, _) = file.into_scenario()?;
+        // Extracted only by a later step
+        assert!(matches!(
+            scenario.check_variables(0, ["token"]),
+            Err(ScenarioError::UnknownVariable(name)) if name == "token"
+        ));
+        assert!(scenario.check_variables(1, ["token"]).is_err());
+        assert!(scenario.check_variables(2, ["token"]).is_err());
+        // A repeating step may use what it extracts, later steps may too
+        assert!(scenario.check_variables(1, ["cursor"]).is_ok());
+        assert!(scenario.check_variables(2, ["cursor"]).is_ok());
+        assert!(scenario.check_variables(0, ["cursor"]).is_err());
+
+        // The repeating step has no cursor on its first repetition, then the one of its last response
+        let page = |cursor: &str| -> Result<_, http::Error> {
+            let response = http::Response::builder()
+                .header("x-cursor", cursor)
+                .body(())?;
+            Ok((response.into_parts().0, Bytes::new()))
+        };
+        let mut session = Session::new(&scenario);
+        session.complete(Some(&page("")?));
+        assert_eq!(session.endpoint(), 1);
+        assert!(!session.vars.contains_key("cursor"));
+        session.complete(Some(&page("2")?));
+        assert_eq!(session.endpoint(), 1);
+        assert_eq!(session.vars["cursor"], "2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_step_key() -> Result<(), Box<dyn std::error::Error>> {
+        let file: ScenarioFile = toml::from_str(
+            r#"
+            [[steps]]
+            url = "/login"
+            extracts = { token = { json = "/token" } }
+            "#,
+        )?;
+        assert!(matches!(
+            file.into_scenario(),
+            Err(ScenarioError::UnknownStepKey(key)) if key == "extracts"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,568 @@
+use crate::feeder::Row;
+use crate::pcg64si::Pcg64Si;
+use bytes::Bytes;
+use hyper::HeaderMap;
+use hyper::header::HeaderName;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Everything but the unreserved characters of RFC 3986, so a value stays one path segment or query value.
+const URL_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// How feed and session values are written into the rendered text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    /// As they are, for headers and bodies
+    None,
+    /// Percent-encoded, for the path and query
+    Url,
+}
+
+/// A string with `{{...}}` variables, evaluated anew for every request.
+///
+/// `\{{` is a literal `{{`, and a `{{...}}` that does not start with a known variable name is kept as it is, so
+/// Mustache or Jinja text passes through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Var(Var),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Var {
+    /// `{{randint <min> <max>}}`, both ends included
+    RandInt(i64, i64),
+    /// `{{uuid}}`, a random version 4 UUID
+    Uuid,
+    /// `{{seq}}`, the number of the request to this endpoint, counted over all workers from 0. Every endpoint
+    /// of a mix or scenario has its own count
+    Seq,
+    /// `{{random_string <len>}}`, alphanumeric
+    RandomString(usize),
+    /// `{{timestamp}}`, Unix time in seconds
+    Timestamp,
+    /// `{{feed <column>}}`, a column of the row the request got from the feed file
+    Feed(String),
+    /// `{{var <name>}}`, a session variable a previous step of the scenario extracted from its response
+    Session(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseTemplateError {
+    #[error("Unclosed template variable in {0:?}, write \\{{{{ for a literal {{{{")]
+    Unclosed(String),
+
+    #[error(
+        "Unknown template variable {0:?}, expected randint, uuid, seq, random_string, timestamp, feed or var"
+    )]
+    UnknownVar(String),
+
+    #[error("Invalid arguments in template variable {0:?}")]
+    InvalidArgs(String),
+}
+
+impl FromStr for Var {
+    type Err = ParseTemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseTemplateError::InvalidArgs(s.to_string());
+        let mut words = s.split_whitespace();
+        let var = match words.next().unwrap_or_default() {
+            "randint" => {
+                let (Some(min), Some(max)) = (words.next(), words.next()) else {
+                    return Err(invalid());
+                };
+                let min = min.parse::<i64>().map_err(|_| invalid())?;
+                let max = max.parse::<i64>().map_err(|_| invalid())?;
+                if min > max {
+                    return Err(invalid());
+                }
+                Var::RandInt(min, max)
+            }
+            "uuid" => Var::Uuid,
+            "seq" => Var::Seq,
+            "random_string" => {
+                let len = words.next().ok_or_else(invalid)?;
+                Var::RandomString(len.parse().map_err(|_| invalid())?)
+            }
+            "timestamp" => Var::Timestamp,
+            "feed" => Var::Feed(words.next().ok_or_else(invalid)?.to_string()),
+            "var" => Var::Session(words.next().ok_or_else(invalid)?.to_string()),
+            _ => return Err(ParseTemplateError::UnknownVar(s.to_string())),
+        };
+        match words.next() {
+            Some(_) => Err(invalid()),
+            None => Ok(var),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = ParseTemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        let mut literal = String::new();
+        while let Some(open) = rest.find("{{") {
+            let (before, tail) = rest.split_at(open);
+            if let Some(before) = before.strip_suffix('\\') {
+                literal.push_str(before);
+                literal.push_str("{{");
+                rest = &tail[2..];
+                continue;
+            }
+            literal.push_str(before);
+            let Some(close) = tail.find("}}") else {
+                return Err(ParseTemplateError::Unclosed(s.to_string()));
+            };
+            match tail[2..close].trim().parse() {
+                Ok(var) => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Var(var));
+                }
+                Err(ParseTemplateError::UnknownVar(_)) => literal.push_str(&tail[..close + 2]),
+                Err(e) => return Err(e),
+            }
+            rest = &tail[close + 2..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+}
+
+impl Template {
+    /// `true` if the template has at least one variable, otherwise it always renders the same string.
+    pub fn has_vars(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Var(_)))
+    }
+
+    pub fn render(&self, vars: &mut RequestVars, escape: Escape) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Var(var) => vars.write(var, escape, &mut out),
+            }
+        }
+        out
+    }
+
+    /// The feed file columns the template uses.
+    pub fn feed_columns(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Var(Var::Feed(column)) => Some(column.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The session variables the template uses.
+    pub fn session_vars(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Var(Var::Session(name)) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Parses a template from the path and query of a URL, where the URL parser percent-encoded the braces and
+    /// spaces of the variables.
+    pub fn from_url_path(path_and_query: &str) -> Result<Self, ParseTemplateError> {
+        let mut unescaped = String::new();
+        let mut rest = path_and_query;
+        while let Some(open) = find_escaped_open(rest) {
+            let (literal, tail) = rest.split_at(open.0);
+            let tail = &tail[open.1..];
+            let Some(close) = find_escaped_close(tail) else {
+                return Err(ParseTemplateError::Unclosed(path_and_query.to_string()));
+            };
+            let var = percent_decode(&tail[..close.0]);
+            unescaped.push_str(literal);
+            match var.trim().parse::<Var>() {
+                // Left as the URL parser wrote it, a plain `{{...}}` stays literal when parsed below
+                Err(ParseTemplateError::UnknownVar(_)) => {
+                    unescaped.push_str(&rest[open.0..][..open.1 + close.0 + close.1])
+                }
+                _ => {
+                    unescaped.push_str("{{");
+                    unescaped.push_str(&var);
+                    unescaped.push_str("}}");
+                }
+            }
+            rest = &tail[close.0 + close.1..];
+        }
+        unescaped.push_str(rest);
+        unescaped.parse()
+    }
+}
+
+/// Position and length of the first `{{`, escaped or not.
+fn find_escaped_open(s: &str) -> Option<(usize, usize)> {
+    find_delimiter(s, "{{", "%7b%7b")
+}
+
+/// Position and length of the first `}}`, escaped or not.
+fn find_escaped_close(s: &str) -> Option<(usize, usize)> {
+    find_delimiter(s, "}}", "%7d%7d")
+}
+
+fn find_delimiter(s: &str, plain: &str, escaped: &str) -> Option<(usize, usize)> {
+    let lower = s.to_ascii_lowercase();
+    [
+        lower.find(plain).map(|at| (at, plain.len())),
+        lower.find(escaped).map(|at| (at, escaped.len())),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The state variables are drawn from while one request is rendered.
+///
+/// `{{seq}}`, `{{timestamp}}`, the feed row and the session variables are the same everywhere in one request, the random variables differ
+/// each time.
+pub struct RequestVars<'a> {
+    rng: &'a mut Pcg64Si,
+    seq: u64,
+    timestamp: u64,
+    row: Option<&'a Row>,
+    session: Option<&'a HashMap<String, String>>,
+}
+
+impl RequestVars<'_> {
+    /// Writes the value of the variable, feed and session values escaped as asked, the others never need it.
+    fn write(&mut self, var: &Var, escape: Escape, out: &mut String) {
+        use std::fmt::Write;
+        const ALPHANUMERIC: &[u8] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+        let _ = match var {
+            Var::RandInt(min, max) => {
+                let (min, max) = (*min, *max);
+                let span = max.abs_diff(min).wrapping_add(1);
+                let offset = match span {
+                    // The whole range of i64
+                    0 => self.rng.next_u64(),
+                    span => self.rng.next_u64() % span,
+                };
+                write!(out, "{}", min.wrapping_add_unsigned(offset))
+            }
+            Var::Uuid => {
+                let mut bytes = [0u8; 16];
+                self.rng.fill_bytes(&mut bytes);
+                // Version 4, variant 1
+                bytes[6] = (bytes[6] & 0x0f) | 0x40;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+                let hex = bytes.iter().fold(String::new(), |mut hex, byte| {
+                    let _ = write!(hex, "{:02x}", byte);
+                    hex
+                });
+                write!(
+                    out,
+                    "{}-{}-{}-{}-{}",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..32]
+                )
+            }
+            Var::Seq => write!(out, "{}", self.seq),
+            Var::RandomString(len) => {
+                out.extend((0..*len).map(|_| {
+                    ALPHANUMERIC[(self.rng.next_u32() as usize) % ALPHANUMERIC.len()] as char
+                }));
+                Ok(())
+            }
+            Var::Timestamp => write!(out, "{}", self.timestamp),
+            Var::Feed(column) => {
+                if let Some(value) = self.row.and_then(|row| row.get(column)) {
+                    push_escaped(out, value, escape);
+                }
+                Ok(())
+            }
+            Var::Session(name) => {
+                if let Some(value) = self.session.and_then(|session| session.get(name)) {
+                    push_escaped(out, value, escape);
+                }
+                Ok(())
+            }
+        };
+    }
+}
+
+fn push_escaped(out: &mut String, value: &str, escape: Escape) {
+    match escape {
+        Escape::None => out.push_str(value),
+        Escape::Url => out.extend(utf8_percent_encode(value, URL_VALUE)),
+    }
+}
+
+/// The parts of the request that have variables in them.
+#[derive(Debug)]
+pub struct RequestTemplate {
+    pub path_and_query: Option<Template>,
+    pub headers: Vec<(HeaderName, Template)>,
+    pub body: Option<Template>,
+    seq: AtomicU64,
+}
+
+/// A request rendered from a [`RequestTemplate`].
+pub struct RenderedRequest {
+    pub path_and_query: Option<String>,
+    pub headers: Vec<(HeaderName, String)>,
+    pub body: Option<Bytes>,
+}
+
+impl RequestTemplate {
+    /// Picks out the templated parts of a request, `None` if there are none.
+    ///
+    /// Templated header values are taken out of `header_map`, other values of the same name stay. A body is only
+    /// templated if it is UTF-8.
+    pub fn extract(
+        path_and_query: &str,
+        header_map: &mut HeaderMap,
+        body: Option<&Bytes>,
+    ) -> Result<Option<Self>, ParseTemplateError> {
+        let path_and_query =
+            Some(Template::from_url_path(path_and_query)?).filter(Template::has_vars);
+
+        let mut headers = Vec::new();
+        let mut plain = HeaderMap::new();
+        for (name, value) in header_map.iter() {
+            if let Ok(text) = value.to_str() {
+                let template: Template = text.parse()?;
+                if template.has_vars() {
+                    headers.push((name.clone(), template));
+                    continue;
+                }
+            }
+            plain.append(name.clone(), value.clone());
+        }
+        *header_map = plain;
+
+        let body = match body.map(|body| std::str::from_utf8(body)) {
+            Some(Ok(body)) => Some(body.parse::<Template>()?).filter(Template::has_vars),
+            _ => None,
+        };
+
+        if path_and_query.is_none() && headers.is_empty() && body.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(RequestTemplate {
+            path_and_query,
+            headers,
+            body,
+            seq: AtomicU64::new(0),
+        }))
+    }
+
+    /// The feed file columns used anywhere in the request.
+    pub fn feed_columns(&self) -> impl Iterator<Item = &str> {
+        self.path_and_query
+            .iter()
+            .chain(self.headers.iter().map(|(_, template)| template))
+            .chain(self.body.iter())
+            .flat_map(Template::feed_columns)
+    }
+
+    /// The session variables used anywhere in the request.
+    pub fn session_vars(&self) -> impl Iterator<Item = &str> {
+        self.path_and_query
+            .iter()
+            .chain(self.headers.iter().map(|(_, template)| template))
+            .chain(self.body.iter())
+            .flat_map(Template::session_vars)
+    }
+
+    /// Renders the request, with `row` filling in the `{{feed <column>}}` variables and `session` the
+    /// `{{var <name>}}` ones.
+    pub fn render(
+        &self,
+        rng: &mut Pcg64Si,
+        row: Option<&Row>,
+        session: Option<&HashMap<String, String>>,
+    ) -> RenderedRequest {
+        let mut vars = RequestVars {
+            rng,
+            row,
+            session,
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+        };
+        RenderedRequest {
+            path_and_query: self
+                .path_and_query
+                .as_ref()
+                .map(|t| t.render(&mut vars, Escape::Url)),
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, t)| (name.clone(), t.render(&mut vars, Escape::None)))
+                .collect(),
+            body: self
+                .body
+                .as_ref()
+                .map(|t| Bytes::from(t.render(&mut vars, Escape::None))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_parse_and_render() -> Result<(), ParseTemplateError> {
+        let template: Template =
+            "/item/{{randint 5 7}}?id={{ seq }}&s={{random_string 4}}".parse()?;
+        let mut rng = Pcg64Si::seed_from_u64(1);
+        let mut vars = RequestVars {
+            rng: &mut rng,
+            seq: 42,
+            timestamp: 0,
+            row: None,
+            session: None,
+        };
+        let rendered = template.render(&mut vars, Escape::None);
+        let (path, query) = rendered.split_once('?').unwrap_or_default();
+        let id = path
+            .trim_start_matches("/item/")
+            .parse::<i64>()
+            .unwrap_or_default();
+        assert!((5..=7).contains(&id));
+        assert!(query.starts_with("id=42&s="));
+        assert_eq!(query.len(), "id=42&s=".len() + 4);
+
+        let row = Row::from([("user".to_string(), "alice".to_string())]);
+        vars.row = Some(&row);
+        let template: Template = "/u/{{feed user}}/{{feed missing}}".parse()?;
+        assert_eq!(template.render(&mut vars, Escape::None), "/u/alice/");
+
+        // Values in the URL are percent-encoded
+        let row = Row::from([("user".to_string(), "a b/c?".to_string())]);
+        vars.row = Some(&row);
+        let template: Template = "/u/{{feed user}}?q={{feed user}}".parse()?;
+        assert_eq!(
+            template.render(&mut vars, Escape::Url),
+            "/u/a%20b%2Fc%3F?q=a%20b%2Fc%3F"
+        );
+
+        let session = HashMap::from([("token".to_string(), "abc".to_string())]);
+        vars.session = Some(&session);
+        let template: Template = "Bearer {{var token}}".parse()?;
+        assert_eq!(template.render(&mut vars, Escape::None), "Bearer abc");
+        assert_eq!(template.session_vars().collect::<Vec<_>>(), ["token"]);
+
+        assert!(!"/plain".parse::<Template>()?.has_vars());
+        assert!("{{seq".parse::<Template>().is_err());
+        assert!("{{randint 1}}".parse::<Template>().is_err());
+        assert!("{{randint 9 1}}".parse::<Template>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_url_template_is_unescaped() -> Result<(), ParseTemplateError> {
+        assert_eq!(
+            Template::from_url_path("/a/%7B%7Brandint%201%2010%7D%7D?q={{uuid}}")?,
+            "/a/{{randint 1 10}}?q={{uuid}}".parse()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_braces() -> Result<(), ParseTemplateError> {
+        let mut rng = Pcg64Si::seed_from_u64(1);
+        let mut vars = RequestVars {
+            rng: &mut rng,
+            seq: 3,
+            timestamp: 0,
+            row: None,
+            session: None,
+        };
+        let template: Template = "<p>{{name}} {{#items}}</p> \\{{seq}} {{seq}}".parse()?;
+        assert!(template.has_vars());
+        assert_eq!(
+            template.render(&mut vars, Escape::None),
+            "<p>{{name}} {{#items}}</p> {{seq}} 3"
+        );
+        assert!(!"Hello {{ user.name }}".parse::<Template>()?.has_vars());
+        assert!(!Template::from_url_path("/a/%7B%7Bname%7D%7D")?.has_vars());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_keeps_plain_header_values() -> Result<(), ParseTemplateError> {
+        let mut headers = HeaderMap::new();
+        headers.append("x-a", HeaderValue::from_static("{{uuid}}"));
+        headers.append("x-a", HeaderValue::from_static("1"));
+        headers.append("x-b", HeaderValue::from_static("{{name}}"));
+        let template = RequestTemplate::extract("/", &mut headers, None)?;
+        let templated = template.map(|t| t.headers).unwrap_or_default();
+        assert_eq!(templated.len(), 1);
+        assert_eq!(templated[0].0, "x-a");
+        assert_eq!(headers.get_all("x-a").iter().collect::<Vec<_>>(), ["1"]);
+        assert_eq!(
+            headers.get_all("x-b").iter().collect::<Vec<_>>(),
+            ["{{name}}"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_is_reproducible() -> Result<(), ParseTemplateError> {
+        let mut headers = HeaderMap::new();
+        let render = |headers: &mut HeaderMap| -> Result<Option<String>, ParseTemplateError> {
+            let template = RequestTemplate::extract("/{{uuid}}", headers, None)?;
+            let mut rng = Pcg64Si::seed_from_u64(7);
+            Ok(template.and_then(|t| t.render(&mut rng, None, None).path_and_query))
+        };
+        let first = render(&mut headers)?;
+        assert!(first.is_some());
+        assert_eq!(first, render(&mut headers)?);
+        Ok(())
+    }
+}
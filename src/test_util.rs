@@ -0,0 +1,96 @@
+//! Temporary files, certificates and local servers shared by the tests of every module.
+
+use quinn::rustls;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub type TestResult<T = ()> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Writes `content` to a file in the temp directory, named after the process so parallel runs do not clash.
+/// The test removes it when done.
+pub fn temp_file(name: &str, content: &str) -> std::io::Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!("ubw-{}-{}", std::process::id(), name));
+    std::fs::write(&path, content)?;
+    Ok(path)
+}
+
+/// A self-signed certificate for `localhost`.
+pub fn localhost_cert() -> Result<rcgen::CertifiedKey<rcgen::KeyPair>, rcgen::Error> {
+    rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+}
+
+/// A rustls server config presenting `cert`, offering the `alpn` protocols.
+pub fn server_config(
+    cert: &rcgen::CertifiedKey<rcgen::KeyPair>,
+    versions: &[&'static rustls::SupportedProtocolVersion],
+    alpn: &[&[u8]],
+) -> TestResult<rustls::ServerConfig> {
+    let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(versions)?
+    .with_no_client_auth()
+    .with_single_cert(vec![cert.cert.der().clone()], key.into())?;
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(config)
+}
+
+/// A listener on a random local port.
+pub async fn listen() -> std::io::Result<(TcpListener, SocketAddr)> {
+    let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+    let address = listener.local_addr()?;
+    Ok((listener, address))
+}
+
+/// Listens on a random local port and hands every connection to `handle`, each in a task of its own.
+pub async fn serve<F, Fut>(handle: F) -> std::io::Result<SocketAddr>
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (listener, address) = listen().await?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle(stream));
+        }
+    });
+    Ok(address)
+}
+
+/// An HTTP/1.1 server that answers every request with `200 ok`.
+pub async fn serve_ok() -> std::io::Result<SocketAddr> {
+    serve(|mut stream| async move {
+        let mut buffer = [0; 4096];
+        while let Ok(read) = stream.read(&mut buffer).await
+            && read > 0
+        {
+            let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+            if stream.write_all(response).await.is_err() {
+                return;
+            }
+        }
+    })
+    .await
+}
+
+/// A server that accepts connections and never answers.
+pub async fn serve_silent() -> std::io::Result<SocketAddr> {
+    serve(|stream| async move {
+        let _stream = stream;
+        std::future::pending::<()>().await
+    })
+    .await
+}
+
+/// Reads an HTTP/1.1 head up to its empty line, a byte at a time so nothing after it is taken from the stream.
+pub async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
@@ -0,0 +1,336 @@
+use crate::latency::PhaseHistograms;
+use crate::work_mode::{ClientResponseCodeType, RequestCounter};
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Format of the per-second time series file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TimeSeriesFormat {
+    Ndjson,
+    Csv,
+}
+
+impl TimeSeriesFormat {
+    /// Guesses the format from a file extension, NDJSON unless it is `.csv`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => TimeSeriesFormat::Csv,
+            _ => TimeSeriesFormat::Ndjson,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WriteTimeSeriesError {
+    #[error("Failed to write time series {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to serialize time series sample as JSON {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Failed to serialize time series sample as CSV {0}")]
+    Csv(#[from] csv::Error),
+}
+
+/// What happened during one reporting interval.
+#[derive(Debug, Clone, Serialize)]
+pub struct IntervalSample {
+    pub timestamp: String,
+    pub elapsed_secs: f64,
+    pub code2: u64,
+    pub code3: u64,
+    pub code4: u64,
+    pub code5: u64,
+    /// Responses with a status outside of 2xx to 5xx
+    pub other_status: u64,
+    pub failure: u64,
+    pub failed_check: u64,
+    pub requests_per_second: f64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+    /// Only present in constant-rate mode
+    pub corrected_p99_us: Option<u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl IntervalSample {
+    /// Builds the sample of the interval that just ended from the live counters and latency histograms.
+    /// Takes the class and byte counters of the interval, resetting each in the same step as it is read.
+    pub fn collect(
+        counter: &RequestCounter,
+        interval: &PhaseHistograms,
+        elapsed: Duration,
+        interval_length: Duration,
+    ) -> Self {
+        let counts = ClientResponseCodeType::ALL.map(|code_type| counter.take(code_type));
+        let [
+            code2,
+            code3,
+            code4,
+            code5,
+            other_status,
+            failure,
+            failed_check,
+        ] = counts;
+        let (bytes_out, bytes_in) = counter.take_bytes();
+        let full = &interval.full;
+        let percentile = |p: f64| {
+            if full.is_empty() {
+                0
+            } else {
+                full.value_at_percentile(p)
+            }
+        };
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            elapsed_secs: elapsed.as_secs_f64(),
+            code2,
+            code3,
+            code4,
+            code5,
+            other_status,
+            failure,
+            failed_check,
+            requests_per_second: counts.iter().sum::<u64>() as f64
+                / interval_length.as_secs_f64().max(f64::EPSILON),
+            p50_us: percentile(50.0),
+            p90_us: percentile(90.0),
+            p99_us: percentile(99.0),
+            p999_us: percentile(99.9),
+            max_us: full.max(),
+            corrected_p99_us: (!interval.corrected.is_empty())
+                .then(|| interval.corrected.value_at_percentile(99.0)),
+            bytes_in,
+            bytes_out,
+        }
+    }
+}
+
+enum SampleWriter {
+    Ndjson(std::io::BufWriter<std::fs::File>),
+    Csv(Box<csv::Writer<std::fs::File>>),
+}
+
+impl std::fmt::Debug for SampleWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleWriter::Ndjson(_) => f.write_str("SampleWriter::Ndjson"),
+            SampleWriter::Csv(_) => f.write_str("SampleWriter::Csv"),
+        }
+    }
+}
+
+impl SampleWriter {
+    fn write(&mut self, sample: &IntervalSample) -> Result<(), WriteTimeSeriesError> {
+        match self {
+            SampleWriter::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, sample)?;
+                writeln!(writer)?;
+                writer.flush()?;
+            }
+            SampleWriter::Csv(writer) => {
+                writer.serialize(sample)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every interval of the run, optionally streamed to a file as it is recorded.
+#[derive(Debug, Default)]
+pub struct TimeSeries {
+    samples: Mutex<Vec<IntervalSample>>,
+    /// Dropped when a write fails, the samples are still kept
+    writer: Mutex<Option<SampleWriter>>,
+    write_error: Mutex<Option<String>>,
+}
+
+impl TimeSeries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A time series that also writes every sample to `path`.
+    pub fn with_file(path: &Path, format: TimeSeriesFormat) -> std::io::Result<Self> {
+        let file = std::fs::File::create(path)?;
+        let writer = match format {
+            TimeSeriesFormat::Ndjson => SampleWriter::Ndjson(std::io::BufWriter::new(file)),
+            TimeSeriesFormat::Csv => SampleWriter::Csv(Box::new(csv::Writer::from_writer(file))),
+        };
+        Ok(Self {
+            writer: Mutex::new(Some(writer)),
+            ..Self::default()
+        })
+    }
+
+    /// Keeps the sample and writes it out if there is a file.
+    ///
+    /// The first failed write is returned and ends writing, later samples are only kept.
+    pub fn push(&self, sample: IntervalSample) -> Result<(), WriteTimeSeriesError> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match writer.as_mut() {
+            Some(w) => w.write(&sample),
+            None => Ok(()),
+        };
+        if let Err(e) = &result {
+            *writer = None;
+            *self.write_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e.to_string());
+        }
+        drop(writer);
+        self.samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sample);
+        result
+    }
+
+    /// The last `count` samples.
+    pub fn recent(&self, count: usize) -> Vec<IntervalSample> {
+        let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples[samples.len().saturating_sub(count)..].to_vec()
+    }
+
+    /// Why the file stopped being written, if it did.
+    pub fn write_error(&self) -> Option<String> {
+        self.write_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Every sample recorded so far.
+    pub fn samples(&self) -> Vec<IntervalSample> {
+        self.samples
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TestResult, temp_file};
+
+    /// A sample with two 2xx and one 5xx response, then an empty one with only a corrected latency.
+    fn two_samples() -> TestResult<Vec<IntervalSample>> {
+        let counter = RequestCounter::new();
+        counter.record_status(hyper::StatusCode::OK);
+        counter.record_status(hyper::StatusCode::OK);
+        counter.record_status(hyper::StatusCode::SERVICE_UNAVAILABLE);
+        counter.record_bytes(10, 100);
+        let mut interval = PhaseHistograms::new()?;
+        interval.full.record(1000)?;
+        let first = IntervalSample::collect(
+            &counter,
+            &interval,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        );
+        interval.reset();
+        interval.corrected.record(2000)?;
+        let second = IntervalSample::collect(
+            &counter,
+            &interval,
+            Duration::from_secs(2),
+            Duration::from_secs(1),
+        );
+        Ok(vec![first, second])
+    }
+
+    #[test]
+    fn test_collect_resets_interval_counters() -> TestResult {
+        let samples = two_samples()?;
+        let counts = |s: &IntervalSample| (s.code2, s.code5, s.bytes_out, s.bytes_in);
+        assert_eq!(counts(&samples[0]), (2, 1, 10, 100));
+        assert_eq!(samples[0].requests_per_second, 3.0);
+        assert_eq!(samples[0].corrected_p99_us, None);
+        assert_eq!(counts(&samples[1]), (0, 0, 0, 0));
+        assert_eq!(samples[1].requests_per_second, 0.0);
+        assert_eq!(samples[1].p99_us, 0);
+        assert!(samples[1].corrected_p99_us.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ndjson_read_back() -> TestResult {
+        let path = temp_file("timeseries.ndjson", "")?;
+        let time_series = TimeSeries::with_file(&path, TimeSeriesFormat::Ndjson)?;
+        for sample in two_samples()? {
+            time_series.push(sample)?;
+        }
+        let content = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        let lines = content
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["code2"], 2);
+        assert_eq!(lines[0]["bytes_in"], 100);
+        assert_eq!(lines[0]["corrected_p99_us"], serde_json::Value::Null);
+        assert_eq!(lines[1]["code2"], 0);
+        assert!(lines[1]["corrected_p99_us"].is_u64());
+        assert_eq!(time_series.samples().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_csv_read_back() -> TestResult {
+        let path = temp_file("timeseries.csv", "")?;
+        let time_series = TimeSeries::with_file(&path, TimeSeriesFormat::Csv)?;
+        for sample in two_samples()? {
+            time_series.push(sample)?;
+        }
+        let mut reader = csv::Reader::from_path(&path)?;
+        let headers = reader.headers()?.clone();
+        let rows = reader.records().collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [
+                "timestamp",
+                "elapsed_secs",
+                "code2",
+                "code3",
+                "code4",
+                "code5",
+                "other_status",
+                "failure",
+                "failed_check",
+                "requests_per_second",
+                "p50_us",
+                "p90_us",
+                "p99_us",
+                "p999_us",
+                "max_us",
+                "corrected_p99_us",
+                "bytes_in",
+                "bytes_out",
+            ]
+        );
+        assert_eq!(rows.len(), 2);
+        let column = |row: usize, name: &str| {
+            let index = headers.iter().position(|header| header == name);
+            index
+                .and_then(|index| rows[row].get(index))
+                .unwrap_or("missing")
+        };
+        assert!(rows.iter().all(|row| row.len() == headers.len()));
+        assert_eq!(column(0, "code2"), "2");
+        assert_eq!(column(0, "code5"), "1");
+        assert_eq!(column(0, "bytes_out"), "10");
+        assert_eq!(column(0, "corrected_p99_us"), "");
+        assert_eq!(column(1, "code2"), "0");
+        assert!(column(1, "corrected_p99_us").parse::<u64>().is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,705 @@
+use crate::opts::Opts;
+use quinn::rustls;
+use rustls::client::Resumption;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Enable the native-tls or the rustls feature to get a TLS backend");
+
+/// The library TLS over TCP goes through, HTTP/3 always uses rustls.
+#[cfg(feature = "rustls")]
+pub const BACKEND: &str = "rustls";
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub const BACKEND: &str = "native-tls";
+
+/// A TLS session over TCP, boxed for rustls keeps its buffers inline.
+#[cfg(feature = "rustls")]
+pub type TlsStream = Box<tokio_rustls::client::TlsStream<TcpStream>>;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub type TlsStream = tokio_native_tls::TlsStream<TcpStream>;
+
+/// Why a TLS handshake failed.
+#[cfg(feature = "rustls")]
+pub type HandshakeError = std::io::Error;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub type HandshakeError = tokio_native_tls::native_tls::Error;
+
+#[cfg(feature = "rustls")]
+type Connector = tokio_rustls::TlsConnector;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+type Connector = tokio_native_tls::TlsConnector;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("No PEM certificate in {0}")]
+    NoCertificate(PathBuf),
+
+    #[error("Invalid PEM private key in {0}: {1}")]
+    InvalidKey(PathBuf, rustls::pki_types::pem::Error),
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    #[error("Failed to set up TLS {0}")]
+    NativeTls(#[from] tokio_native_tls::native_tls::Error),
+
+    #[error("Failed to set up TLS {0}")]
+    Rustls(#[from] rustls::Error),
+
+    #[error(
+        "PKCS#12 client certificates need the native-tls backend and do not work with HTTP/3, give it as --cert and --key"
+    )]
+    Pkcs12NotSupported,
+
+    #[error("The minimum TLS version {0} is above the maximum {1}")]
+    VersionRange(TlsVersion, TlsVersion),
+
+    #[error("rustls only speaks TLS 1.2 and 1.3, which the version range leaves out")]
+    NoRustlsVersion,
+
+    #[error("HTTP/3 needs TLS 1.3, which the version range leaves out")]
+    Http3NeedsTls13,
+
+    #[error("Unknown cipher suite {0:?}, expected a name like TLS13_AES_128_GCM_SHA256")]
+    UnknownCipher(String),
+
+    #[error("Choosing cipher suites needs the rustls backend, build with --features rustls")]
+    CiphersNeedRustls,
+
+    #[error(
+        "Turning off session resumption needs the rustls backend, build with --features rustls"
+    )]
+    ResumptionNeedsRustls,
+}
+
+/// A TLS protocol version to pin the handshake to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum TlsVersion {
+    #[value(name = "1.0")]
+    Tls10,
+    #[value(name = "1.1")]
+    Tls11,
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let version = match self {
+            TlsVersion::Tls10 => "1.0",
+            TlsVersion::Tls11 => "1.1",
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        };
+        write!(f, "TLS {}", version)
+    }
+}
+
+/// TLS settings as given on the command line, the files are read by [`TlsConfig::load`].
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// Accept any certificate and host name
+    pub insecure: bool,
+    /// PEM bundle of certificate authorities trusted on top of the system ones
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate chain, with `key`
+    pub cert: Option<PathBuf>,
+    /// PEM PKCS#8 private key of `cert`
+    pub key: Option<PathBuf>,
+    /// PKCS#12 archive with the client certificate and its key, instead of `cert` and `key`
+    pub pkcs12: Option<PathBuf>,
+    pub pkcs12_password: Option<String>,
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    /// Cipher suite names in order of preference, all the backend offers if empty
+    pub ciphers: Vec<String>,
+    /// Every connection makes a full handshake
+    pub no_resumption: bool,
+}
+
+impl From<&Opts> for TlsOptions {
+    fn from(opts: &Opts) -> Self {
+        Self {
+            insecure: opts.insecure,
+            ca_file: opts.ca_file.clone(),
+            cert: opts.cert.clone(),
+            key: opts.key.clone(),
+            pkcs12: opts.pkcs12.clone(),
+            pkcs12_password: opts.pkcs12_password.clone(),
+            min_version: opts.tls_min_version,
+            max_version: opts.tls_max_version,
+            ciphers: opts.tls_ciphers.clone(),
+            no_resumption: opts.no_tls_resumption,
+        }
+    }
+}
+
+impl TlsOptions {
+    /// The backend and whatever the handshake is pinned to, for the report.
+    pub fn summary(&self) -> String {
+        let mut parts = vec![BACKEND.to_string()];
+        match (self.min_version, self.max_version) {
+            (None, None) => {}
+            (Some(min), Some(max)) if min == max => parts.push(min.to_string()),
+            (min, max) => parts.push(format!(
+                "{} to {}",
+                min.map_or("any".to_string(), |v| v.to_string()),
+                max.map_or("any".to_string(), |v| v.to_string())
+            )),
+        }
+        if !self.ciphers.is_empty() {
+            parts.push(self.ciphers.join(":"));
+        }
+        if self.no_resumption {
+            parts.push("no resumption".to_string());
+        }
+        if self.insecure {
+            parts.push("insecure".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+/// The ALPN protocols offered in the TLS handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alpn {
+    /// Nothing, the server falls back to HTTP/1.1
+    None,
+    /// HTTP/2 only
+    Http2,
+    /// HTTP/2 or HTTP/1.1, to find out which one the server prefers
+    Negotiate,
+}
+
+impl Alpn {
+    fn protocols(self) -> &'static [&'static str] {
+        match self {
+            Alpn::None => &[],
+            Alpn::Http2 => &["h2"],
+            Alpn::Negotiate => &["h2", "http/1.1"],
+        }
+    }
+}
+
+/// TLS connectors set up once for the whole run, one per set of ALPN protocols.
+pub struct TlsConfig {
+    settings: Settings,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    plain: Connector,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    http2: Connector,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    negotiate: Connector,
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("settings", &self.settings)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What every connector is built from, with the files already read.
+struct Settings {
+    options: TlsOptions,
+    /// Trusted on top of the system certificate authorities
+    ca_certs: Vec<CertificateDer<'static>>,
+    /// Client certificate chain and key, unless it came as PKCS#12
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    identity: Option<tokio_native_tls::native_tls::Identity>,
+    /// One session cache for every rustls configuration
+    resumption: Resumption,
+}
+
+impl std::fmt::Debug for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Settings")
+            .field("options", &self.options)
+            .field("ca_certs", &self.ca_certs.len())
+            .finish()
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|e| TlsError::Read(path.to_path_buf(), e))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_slice_iter(&read(path)?)
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+impl TlsConfig {
+    /// Reads the certificate files and builds the connectors.
+    pub fn load(options: TlsOptions) -> Result<Self, TlsError> {
+        if let (Some(min), Some(max)) = (options.min_version, options.max_version)
+            && min > max
+        {
+            return Err(TlsError::VersionRange(min, max));
+        }
+        let ca_certs = match &options.ca_file {
+            Some(path) => read_certificates(path)?,
+            None => Vec::new(),
+        };
+        let client_auth = match (&options.cert, &options.key) {
+            (Some(cert), Some(key)) => {
+                let chain = read_certificates(cert)?;
+                let key_der = PrivateKeyDer::from_pem_slice(&read(key)?)
+                    .map_err(|e| TlsError::InvalidKey(key.clone(), e))?;
+                Some((chain, key_der))
+            }
+            _ => None,
+        };
+        #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+        let identity = {
+            use tokio_native_tls::native_tls::Identity;
+            match (&options.cert, &options.key, &options.pkcs12) {
+                (Some(cert), Some(key), _) => {
+                    Some(Identity::from_pkcs8(&read(cert)?, &read(key)?)?)
+                }
+                (_, _, Some(archive)) => Some(Identity::from_pkcs12(
+                    &read(archive)?,
+                    options.pkcs12_password.as_deref().unwrap_or_default(),
+                )?),
+                _ => None,
+            }
+        };
+        let resumption = if options.no_resumption {
+            Resumption::disabled()
+        } else {
+            Resumption::default()
+        };
+
+        let settings = Settings {
+            options,
+            ca_certs,
+            client_auth,
+            #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+            identity,
+            resumption,
+        };
+        Ok(Self {
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            plain: settings.connector(Alpn::None)?,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            http2: settings.connector(Alpn::Http2)?,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            negotiate: settings.connector(Alpn::Negotiate)?,
+            settings,
+        })
+    }
+
+    /// Opens a TLS session, offering the given ALPN protocols.
+    pub async fn connect(
+        &self,
+        domain: &str,
+        stream: TcpStream,
+        alpn: Alpn,
+    ) -> Result<TlsStream, HandshakeError> {
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        let connector = match alpn {
+            Alpn::None => &self.plain,
+            Alpn::Http2 => &self.http2,
+            Alpn::Negotiate => &self.negotiate,
+        };
+        #[cfg(feature = "rustls")]
+        {
+            // IPv6 hosts come in brackets from the URL
+            let name = ServerName::try_from(domain.trim_matches(['[', ']']).to_string())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            connector.connect(name, stream).await.map(Box::new)
+        }
+        #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+        connector.connect(domain, stream).await
+    }
+
+    /// A TLS 1.3 client configuration with the same trust, client certificate and ciphers, for QUIC.
+    pub fn quic_client_config(&self) -> Result<rustls::ClientConfig, TlsError> {
+        if self
+            .settings
+            .options
+            .max_version
+            .is_some_and(|max| max < TlsVersion::Tls13)
+        {
+            return Err(TlsError::Http3NeedsTls13);
+        }
+        self.settings.rustls_config(&[&rustls::version::TLS13])
+    }
+}
+
+impl Settings {
+    /// A rustls configuration for the given protocol versions, without ALPN.
+    fn rustls_config(
+        &self,
+        versions: &[&'static rustls::SupportedProtocolVersion],
+    ) -> Result<rustls::ClientConfig, TlsError> {
+        if self.options.pkcs12.is_some() {
+            return Err(TlsError::Pkcs12NotSupported);
+        }
+        let mut provider = rustls::crypto::ring::default_provider();
+        if !self.options.ciphers.is_empty() {
+            let offered = std::mem::take(&mut provider.cipher_suites);
+            provider.cipher_suites = self
+                .options
+                .ciphers
+                .iter()
+                .map(|name| {
+                    offered
+                        .iter()
+                        .find(|suite| {
+                            suite
+                                .suite()
+                                .as_str()
+                                .is_some_and(|suite| suite.eq_ignore_ascii_case(name))
+                        })
+                        .copied()
+                        .ok_or_else(|| TlsError::UnknownCipher(name.clone()))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        let provider = Arc::new(provider);
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(versions)?;
+        let builder = if self.options.insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        } else {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            roots.add_parsable_certificates(self.ca_certs.iter().cloned());
+            builder.with_root_certificates(roots)
+        };
+        let mut config = match &self.client_auth {
+            Some((chain, key)) => builder.with_client_auth_cert(chain.clone(), key.clone_key())?,
+            None => builder.with_no_client_auth(),
+        };
+        config.resumption = self.resumption.clone();
+        Ok(config)
+    }
+
+    #[cfg(feature = "rustls")]
+    fn connector(&self, alpn: Alpn) -> Result<Connector, TlsError> {
+        let in_range = |version: TlsVersion| {
+            self.options.min_version.is_none_or(|min| min <= version)
+                && self.options.max_version.is_none_or(|max| max >= version)
+        };
+        let versions = [
+            (TlsVersion::Tls12, &rustls::version::TLS12),
+            (TlsVersion::Tls13, &rustls::version::TLS13),
+        ]
+        .into_iter()
+        .filter(|(version, _)| in_range(*version))
+        .map(|(_, supported)| supported)
+        .collect::<Vec<_>>();
+        if versions.is_empty() {
+            return Err(TlsError::NoRustlsVersion);
+        }
+        let mut config = self.rustls_config(&versions)?;
+        config.alpn_protocols = alpn
+            .protocols()
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        Ok(Arc::new(config).into())
+    }
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    fn connector(&self, alpn: Alpn) -> Result<Connector, TlsError> {
+        use tokio_native_tls::native_tls::{self, Protocol};
+        if !self.options.ciphers.is_empty() {
+            return Err(TlsError::CiphersNeedRustls);
+        }
+        if self.options.no_resumption {
+            return Err(TlsError::ResumptionNeedsRustls);
+        }
+        let protocol = |version: TlsVersion| match version {
+            TlsVersion::Tls10 => Protocol::Tlsv10,
+            TlsVersion::Tls11 => Protocol::Tlsv11,
+            TlsVersion::Tls12 => Protocol::Tlsv12,
+            TlsVersion::Tls13 => Protocol::Tlsv13,
+        };
+        let mut builder = native_tls::TlsConnector::builder();
+        builder
+            .request_alpns(alpn.protocols())
+            .danger_accept_invalid_certs(self.options.insecure)
+            .danger_accept_invalid_hostnames(self.options.insecure)
+            .min_protocol_version(self.options.min_version.map(protocol))
+            .max_protocol_version(self.options.max_version.map(protocol));
+        for cert in &self.ca_certs {
+            builder.add_root_certificate(native_tls::Certificate::from_der(cert)?);
+        }
+        if let Some(identity) = &self.identity {
+            builder.identity(identity.clone());
+        }
+        Ok(builder.build()?.into())
+    }
+}
+
+/// Asks which protocol the server picked through ALPN.
+pub fn negotiated_alpn(stream: &TlsStream) -> Result<Option<Vec<u8>>, HandshakeError> {
+    #[cfg(feature = "rustls")]
+    return Ok(stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec));
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    stream.get_ref().negotiated_alpn()
+}
+
+/// Accepts any server certificate for `--insecure`, the handshake signatures are still checked.
+#[derive(Debug)]
+struct NoVerification(Arc<rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::{TestResult, localhost_cert, serve, temp_file};
+
+    /// A TLS server for `localhost` that completes handshakes and closes.
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    async fn serve_tls(
+        cert: &rcgen::CertifiedKey<rcgen::KeyPair>,
+    ) -> TestResult<std::net::SocketAddr> {
+        use tokio_native_tls::native_tls;
+        let identity = native_tls::Identity::from_pkcs8(
+            cert.cert.pem().as_bytes(),
+            cert.signing_key.serialize_pem().as_bytes(),
+        )?;
+        let acceptor = tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+        Ok(serve(move |stream| {
+            let acceptor = acceptor.clone();
+            async move {
+                let _ = acceptor.accept(stream).await;
+            }
+        })
+        .await?)
+    }
+
+    /// A TLS server for `localhost` that completes handshakes and closes.
+    #[cfg(feature = "rustls")]
+    async fn serve_tls(
+        cert: &rcgen::CertifiedKey<rcgen::KeyPair>,
+    ) -> TestResult<std::net::SocketAddr> {
+        let config = crate::test_util::server_config(
+            cert,
+            &[&rustls::version::TLS12, &rustls::version::TLS13],
+            &[],
+        )?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        Ok(serve(move |stream| {
+            let acceptor = acceptor.clone();
+            async move {
+                let _ = acceptor.accept(stream).await;
+            }
+        })
+        .await?)
+    }
+
+    async fn handshake(
+        address: std::net::SocketAddr,
+        options: TlsOptions,
+    ) -> TestResult<TlsStream> {
+        let tls = TlsConfig::load(options)?;
+        let stream = TcpStream::connect(address).await?;
+        Ok(tls.connect("localhost", stream, Alpn::None).await?)
+    }
+
+    #[tokio::test]
+    async fn test_private_ca_and_insecure() -> TestResult {
+        let cert = localhost_cert()?;
+        let address = serve_tls(&cert).await?;
+
+        assert!(handshake(address, TlsOptions::default()).await.is_err());
+        let ca_file = temp_file("tls-ca.pem", &cert.cert.pem())?;
+        handshake(
+            address,
+            TlsOptions {
+                ca_file: Some(ca_file.clone()),
+                ..TlsOptions::default()
+            },
+        )
+        .await?;
+        handshake(
+            address,
+            TlsOptions {
+                insecure: true,
+                ..TlsOptions::default()
+            },
+        )
+        .await?;
+        std::fs::remove_file(ca_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_certificate() -> TestResult {
+        let cert = rcgen::generate_simple_self_signed(vec!["client".to_string()])?;
+        let cert_file = temp_file("tls-client.pem", &cert.cert.pem())?;
+        let key_file = temp_file("tls-client.key", &cert.signing_key.serialize_pem())?;
+        let tls = TlsConfig::load(TlsOptions {
+            cert: Some(cert_file.clone()),
+            key: Some(key_file.clone()),
+            ..TlsOptions::default()
+        })?;
+        assert!(
+            tls.quic_client_config()?
+                .client_auth_cert_resolver
+                .has_certs()
+        );
+
+        assert!(matches!(
+            TlsConfig::load(TlsOptions {
+                cert: Some(cert_file.clone()),
+                key: Some(cert_file.clone()),
+                ..TlsOptions::default()
+            }),
+            Err(TlsError::InvalidKey(..))
+        ));
+        std::fs::remove_file(cert_file)?;
+        std::fs::remove_file(key_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_pinning() -> TestResult {
+        assert!(matches!(
+            TlsConfig::load(TlsOptions {
+                min_version: Some(TlsVersion::Tls13),
+                max_version: Some(TlsVersion::Tls12),
+                ..TlsOptions::default()
+            }),
+            Err(TlsError::VersionRange(..))
+        ));
+        let tls12 = TlsConfig::load(TlsOptions {
+            max_version: Some(TlsVersion::Tls12),
+            ..TlsOptions::default()
+        })?;
+        assert!(matches!(
+            tls12.quic_client_config(),
+            Err(TlsError::Http3NeedsTls13)
+        ));
+
+        let ciphers = TlsConfig::load(TlsOptions {
+            ciphers: vec!["TLS13_AES_512_GCM".to_string()],
+            ..TlsOptions::default()
+        });
+        #[cfg(feature = "rustls")]
+        assert!(matches!(ciphers, Err(TlsError::UnknownCipher(_))));
+        #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+        assert!(matches!(ciphers, Err(TlsError::CiphersNeedRustls)));
+
+        let no_resumption = TlsConfig::load(TlsOptions {
+            no_resumption: true,
+            ..TlsOptions::default()
+        });
+        #[cfg(feature = "rustls")]
+        assert!(no_resumption.is_ok());
+        #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+        assert!(matches!(
+            no_resumption,
+            Err(TlsError::ResumptionNeedsRustls)
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn test_version_and_cipher_pinning() -> TestResult {
+        let cert = localhost_cert()?;
+        let address = serve_tls(&cert).await?;
+
+        let stream = handshake(
+            address,
+            TlsOptions {
+                insecure: true,
+                max_version: Some(TlsVersion::Tls12),
+                ciphers: vec!["tls_ecdhe_ecdsa_with_chacha20_poly1305_sha256".to_string()],
+                ..TlsOptions::default()
+            },
+        )
+        .await?;
+        let session = stream.get_ref().1;
+        assert_eq!(
+            session.protocol_version(),
+            Some(rustls::ProtocolVersion::TLSv1_2)
+        );
+        assert_eq!(
+            session.negotiated_cipher_suite().map(|suite| suite.suite()),
+            Some(rustls::CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256)
+        );
+
+        let stream = handshake(
+            address,
+            TlsOptions {
+                insecure: true,
+                min_version: Some(TlsVersion::Tls13),
+                ..TlsOptions::default()
+            },
+        )
+        .await?;
+        assert_eq!(
+            stream.get_ref().1.protocol_version(),
+            Some(rustls::ProtocolVersion::TLSv1_3)
+        );
+        Ok(())
+    }
+}
@@ -1,24 +1,32 @@
+use crate::check::CheckKind;
+use crate::client::WorkInstance;
+use crate::latency::{format_micros, format_percentiles};
+use crate::run::ProgressCallback;
+use crate::timeseries::IntervalSample;
 use bytes::Bytes;
 use compact_str::CompactString;
 use std::sync::atomic::AtomicU64;
 #[derive(Debug, Clone)]
-pub struct PostWorkModeSpec {
+pub struct BodySpec {
     pub body: Bytes,
     pub content_type: Option<CompactString>,
 }
 
+/// The request every worker sends, any method with or without a body.
 #[derive(Debug, Clone)]
-pub enum WorkMode {
-    Get,
-    Post(PostWorkModeSpec),
+pub struct WorkMode {
+    pub method: hyper::Method,
+    pub body: Option<BodySpec>,
 }
 
 impl WorkMode {
     pub fn method(&self) -> hyper::Method {
-        match self {
-            WorkMode::Get => hyper::Method::GET,
-            WorkMode::Post(_) => hyper::Method::POST,
-        }
+        self.method.clone()
+    }
+
+    /// The response to a HEAD request never has a body, even if it announces a length.
+    pub fn expects_body(&self) -> bool {
+        self.method != hyper::Method::HEAD
     }
 }
 
@@ -36,11 +44,135 @@ pub struct RequestCounter {
     /// HTTP 5xx
     code5_count: AtomicU64,
 
-    /// Timeout, TLS error, Hyper error
+    /// Any other status code, like a 1xx as the final response
+    other_status_count: AtomicU64,
+
+    /// Connect, TLS, timeout, protocol, reset and other errors, see [`FailureKind`]
     failure_count: AtomicU64,
-    
+
+    /// Responses that failed a check, see [`CheckKind`]
+    check_failed_count: AtomicU64,
+
     /// Total number of requests sent
     total_count: AtomicU64,
+
+    /// Responses per exact status code since the start of the run
+    status_codes: StatusCodeCounter,
+
+    /// Failures per kind since the start of the run
+    failures: [AtomicU64; FailureKind::ALL.len()],
+
+    /// Failed checks per kind since the start of the run
+    check_failures: [AtomicU64; CheckKind::ALL.len()],
+
+    /// Approximate bytes sent since the last call to `take_bytes`
+    interval_bytes_out: AtomicU64,
+
+    /// Approximate bytes received since the last call to `take_bytes`
+    interval_bytes_in: AtomicU64,
+
+    /// Approximate bytes sent since the start of the run
+    bytes_out: AtomicU64,
+
+    /// Approximate bytes received since the start of the run
+    bytes_in: AtomicU64,
+}
+
+/// Why a request did not get a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// TCP connect failed: refused, unreachable, no route
+    Connect,
+
+    /// TLS handshake failed
+    Tls,
+
+    /// No response within the request timeout, or the OS gave up on the connection
+    Timeout,
+
+    /// The server spoke invalid HTTP
+    Protocol,
+
+    /// The connection was reset or closed before the response was complete
+    Reset,
+
+    /// The proxy could not be reached, refused the tunnel or answered `CONNECT` with something else than HTTP
+    Proxy,
+
+    /// The feed file had no valid row for the request, the first such error is printed unless the run is quiet
+    Feed,
+
+    /// The request could not be built, a template rendered an invalid URI or header
+    Request,
+
+    /// Anything that does not fit the other kinds
+    Other,
+}
+
+impl FailureKind {
+    pub const ALL: [FailureKind; 9] = [
+        FailureKind::Connect,
+        FailureKind::Tls,
+        FailureKind::Timeout,
+        FailureKind::Protocol,
+        FailureKind::Reset,
+        FailureKind::Proxy,
+        FailureKind::Feed,
+        FailureKind::Request,
+        FailureKind::Other,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailureKind::Connect => "connect",
+            FailureKind::Tls => "tls",
+            FailureKind::Timeout => "timeout",
+            FailureKind::Protocol => "protocol",
+            FailureKind::Reset => "reset",
+            FailureKind::Proxy => "proxy",
+            FailureKind::Feed => "feed",
+            FailureKind::Request => "request",
+            FailureKind::Other => "other",
+        }
+    }
+}
+
+/// Lowest and highest status code `hyper` accepts.
+const MIN_STATUS_CODE: u16 = 100;
+const MAX_STATUS_CODE: u16 = 999;
+
+#[derive(Debug)]
+struct StatusCodeCounter {
+    /// Indexed by `status - MIN_STATUS_CODE`
+    counts: Box<[AtomicU64]>,
+}
+
+impl Default for StatusCodeCounter {
+    fn default() -> Self {
+        Self {
+            counts: (MIN_STATUS_CODE..=MAX_STATUS_CODE)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+}
+
+impl StatusCodeCounter {
+    fn inc(&self, status: hyper::StatusCode) {
+        let index = usize::from(status.as_u16().saturating_sub(MIN_STATUS_CODE));
+        if let Some(count) = self.counts.get(index) {
+            count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Every status code seen so far with its count, in ascending order.
+    fn snapshot(&self) -> Vec<(u16, u64)> {
+        (MIN_STATUS_CODE..=MAX_STATUS_CODE)
+            .zip(self.counts.iter())
+            .map(|(status, count)| (status, count.load(std::sync::atomic::Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -49,7 +181,46 @@ pub enum ClientResponseCodeType {
     Code3,
     Code4,
     Code5,
+    /// A response with a status outside of 2xx to 5xx, e.g. a `101` the client did not ask for
+    OtherStatus,
     Failure,
+    /// A response that failed a check, whatever its status
+    CheckFailed,
+}
+
+impl ClientResponseCodeType {
+    pub const ALL: [ClientResponseCodeType; 7] = [
+        ClientResponseCodeType::Code2,
+        ClientResponseCodeType::Code3,
+        ClientResponseCodeType::Code4,
+        ClientResponseCodeType::Code5,
+        ClientResponseCodeType::OtherStatus,
+        ClientResponseCodeType::Failure,
+        ClientResponseCodeType::CheckFailed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientResponseCodeType::Code2 => "2xx",
+            ClientResponseCodeType::Code3 => "3xx",
+            ClientResponseCodeType::Code4 => "4xx",
+            ClientResponseCodeType::Code5 => "5xx",
+            ClientResponseCodeType::OtherStatus => "other_status",
+            ClientResponseCodeType::Failure => "failure",
+            ClientResponseCodeType::CheckFailed => "failed_check",
+        }
+    }
+
+    /// Determines the ClientResponseCodeType from an HTTP status code
+    pub fn from_status(status: hyper::StatusCode) -> Self {
+        match status.as_u16() {
+            200..=299 => ClientResponseCodeType::Code2,
+            300..=399 => ClientResponseCodeType::Code3,
+            400..=499 => ClientResponseCodeType::Code4,
+            500..=599 => ClientResponseCodeType::Code5,
+            _ => ClientResponseCodeType::OtherStatus,
+        }
+    }
 }
 
 impl RequestCounter {
@@ -57,13 +228,109 @@ impl RequestCounter {
         Self::default()
     }
 
+    /// Counts a response by its status code.
+    pub fn record_status(&self, status: hyper::StatusCode) {
+        self.inc(ClientResponseCodeType::from_status(status));
+        self.status_codes.inc(status);
+    }
+
+    /// Counts a request that did not get a response.
+    pub fn record_failure(&self, kind: FailureKind) {
+        self.inc(ClientResponseCodeType::Failure);
+        if let Some(index) = FailureKind::ALL.iter().position(|k| *k == kind) {
+            self.failures[index].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a response that failed a check, instead of by its status code.
+    pub fn record_check_failure(&self, kind: CheckKind) {
+        self.inc(ClientResponseCodeType::CheckFailed);
+        if let Some(index) = CheckKind::ALL.iter().position(|k| *k == kind) {
+            self.check_failures[index].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Counts the size of an exchanged request and response.
+    pub fn record_bytes(&self, bytes_out: u64, bytes_in: u64) {
+        self.interval_bytes_out
+            .fetch_add(bytes_out, std::sync::atomic::Ordering::Relaxed);
+        self.interval_bytes_in
+            .fetch_add(bytes_in, std::sync::atomic::Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(bytes_out, std::sync::atomic::Ordering::Relaxed);
+        self.bytes_in
+            .fetch_add(bytes_in, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Returns `(bytes_out, bytes_in)` since the last call and resets them.
+    pub fn take_bytes(&self) -> (u64, u64) {
+        (
+            self.interval_bytes_out
+                .swap(0, std::sync::atomic::Ordering::Relaxed),
+            self.interval_bytes_in
+                .swap(0, std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Returns `(bytes_out, bytes_in)` since the start of the run.
+    pub fn total_bytes(&self) -> (u64, u64) {
+        (
+            self.bytes_out.load(std::sync::atomic::Ordering::Relaxed),
+            self.bytes_in.load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Responses per exact status code since the start of the run.
+    pub fn status_codes(&self) -> Vec<(u16, u64)> {
+        self.status_codes.snapshot()
+    }
+
+    /// Failures per kind since the start of the run.
+    pub fn failures(&self) -> Vec<(FailureKind, u64)> {
+        FailureKind::ALL
+            .into_iter()
+            .zip(self.failures.iter())
+            .map(|(kind, count)| (kind, count.load(std::sync::atomic::Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Failed checks per kind since the start of the run.
+    pub fn check_failures(&self) -> Vec<(CheckKind, u64)> {
+        CheckKind::ALL
+            .into_iter()
+            .zip(self.check_failures.iter())
+            .map(|(kind, count)| (kind, count.load(std::sync::atomic::Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Count of a response class since the start of the run.
+    pub fn get_cumulative(&self, code_type: ClientResponseCodeType) -> u64 {
+        match code_type {
+            ClientResponseCodeType::Failure => self.failures().iter().map(|(_, count)| count).sum(),
+            ClientResponseCodeType::CheckFailed => {
+                self.check_failures().iter().map(|(_, count)| count).sum()
+            }
+            code_type => self
+                .status_codes()
+                .into_iter()
+                .filter(|(status, _)| {
+                    hyper::StatusCode::from_u16(*status)
+                        .is_ok_and(|s| ClientResponseCodeType::from_status(s) == code_type)
+                })
+                .map(|(_, count)| count)
+                .sum(),
+        }
+    }
+
     pub fn inc(&self, code_type: ClientResponseCodeType) {
         match code_type {
             ClientResponseCodeType::Code2 => &self.code2_count,
             ClientResponseCodeType::Code3 => &self.code3_count,
             ClientResponseCodeType::Code4 => &self.code4_count,
             ClientResponseCodeType::Code5 => &self.code5_count,
+            ClientResponseCodeType::OtherStatus => &self.other_status_count,
             ClientResponseCodeType::Failure => &self.failure_count,
+            ClientResponseCodeType::CheckFailed => &self.check_failed_count,
         }
         .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         self.total_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -75,7 +342,9 @@ impl RequestCounter {
             ClientResponseCodeType::Code3 => &self.code3_count,
             ClientResponseCodeType::Code4 => &self.code4_count,
             ClientResponseCodeType::Code5 => &self.code5_count,
+            ClientResponseCodeType::OtherStatus => &self.other_status_count,
             ClientResponseCodeType::Failure => &self.failure_count,
+            ClientResponseCodeType::CheckFailed => &self.check_failed_count,
         }
         .load(std::sync::atomic::Ordering::Relaxed)
     }
@@ -84,42 +353,202 @@ impl RequestCounter {
         self.total_count.load(std::sync::atomic::Ordering::Relaxed)
     }
 
-    pub fn reset(&self, code_type: ClientResponseCodeType) {
+    /// Returns the count of one class since the last call and resets it in the same step.
+    pub fn take(&self, code_type: ClientResponseCodeType) -> u64 {
         match code_type {
             ClientResponseCodeType::Code2 => &self.code2_count,
             ClientResponseCodeType::Code3 => &self.code3_count,
             ClientResponseCodeType::Code4 => &self.code4_count,
             ClientResponseCodeType::Code5 => &self.code5_count,
+            ClientResponseCodeType::OtherStatus => &self.other_status_count,
             ClientResponseCodeType::Failure => &self.failure_count,
+            ClientResponseCodeType::CheckFailed => &self.check_failed_count,
         }
-        .store(0, std::sync::atomic::Ordering::Relaxed);
+        .swap(0, std::sync::atomic::Ordering::Relaxed)
     }
 }
 
+/// Collects the per-second statistics until shutdown, printing them unless the run is quiet.
 pub async fn counter_print(
-    counter: &RequestCounter,
+    work_instance: &WorkInstance,
     shutdown_signal: &mut tokio::sync::watch::Receiver<bool>,
+    on_progress: Option<&ProgressCallback>,
 ) {
+    let counter = &work_instance.request_counter;
+    let start = std::time::Instant::now();
+    let mut last_tick = start;
+    let mut last_status_codes = Vec::new();
+    let mut last_failures = Vec::new();
+    let mut last_check_failures = Vec::new();
     loop {
         tokio::select! {
             _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {
-                println!("2xx: {}, 3xx: {}, 4xx: {}, 5xx: {}, failure: {}, total: {}",
-                    counter.get(ClientResponseCodeType::Code2),
-                    counter.get(ClientResponseCodeType::Code3),
-                    counter.get(ClientResponseCodeType::Code4),
-                    counter.get(ClientResponseCodeType::Code5),
-                    counter.get(ClientResponseCodeType::Failure),
-                    counter.get_total(),
+                let now = std::time::Instant::now();
+                let interval_length = now.duration_since(last_tick);
+                last_tick = now;
+                let interval = work_instance.latency.take_interval();
+                let sample = IntervalSample::collect(
+                    counter,
+                    &interval,
+                    now.duration_since(start),
+                    interval_length,
+                );
+                let schedule = work_instance.rate_limiter.as_ref()
+                    .map(|r| format!(
+                        " | corrected {} | behind schedule: {}",
+                        format_percentiles(&interval.corrected),
+                        format_micros(r.take_interval_max_lag()),
+                    ))
+                    .unwrap_or_default();
+                let stage = work_instance.profile.as_ref()
+                    .map(|p| format!(" | {}", p.describe_current()))
+                    .unwrap_or_default();
+                let status_codes = counter.status_codes();
+                let failures = counter.failures();
+                let check_failures = counter.check_failures();
+                let breakdown = format_breakdown(
+                    &counts_since(&status_codes, &last_status_codes),
+                    &counts_since(&failures, &last_failures),
+                    &counts_since(&check_failures, &last_check_failures),
                 );
-                counter.reset(ClientResponseCodeType::Code2);
-                counter.reset(ClientResponseCodeType::Code3);
-                counter.reset(ClientResponseCodeType::Code4);
-                counter.reset(ClientResponseCodeType::Code5);
-                counter.reset(ClientResponseCodeType::Failure);
+                last_status_codes = status_codes;
+                last_failures = failures;
+                last_check_failures = check_failures;
+                if !work_instance.quiet {
+                    let other_status = if sample.other_status > 0 {
+                        format!(", other status: {}", sample.other_status)
+                    } else {
+                        String::new()
+                    };
+                    let failed_checks = if sample.failed_check > 0 {
+                        format!(", failed check: {}", sample.failed_check)
+                    } else {
+                        String::new()
+                    };
+                    println!("2xx: {}, 3xx: {}, 4xx: {}, 5xx: {}{}, failure: {}{}, total: {} | latency {}{}{}{}",
+                        sample.code2,
+                        sample.code3,
+                        sample.code4,
+                        sample.code5,
+                        other_status,
+                        sample.failure,
+                        failed_checks,
+                        counter.get_total(),
+                        format_percentiles(&interval.full),
+                        schedule,
+                        stage,
+                        breakdown,
+                    );
+                }
+                if let Some(on_progress) = on_progress {
+                    on_progress(&sample);
+                }
+                // A failed write is kept by the time series and told at the end of the run
+                let _ = work_instance.time_series.push(sample);
             }
             _ = shutdown_signal.changed() => {
                 break;
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Turns two snapshots of cumulative counts into the counts in between, dropping the ones that did not change.
+fn counts_since<K: PartialEq + Copy>(current: &[(K, u64)], previous: &[(K, u64)]) -> Vec<(K, u64)> {
+    current
+        .iter()
+        .map(|(key, count)| {
+            let before = previous
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or(0, |(_, count)| *count);
+            (*key, count - before)
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+/// ` | codes: 200: 950, 503: 12 | errors: timeout: 3 | failed checks: body: 5`, leaving out empty parts.
+fn format_breakdown(
+    status_codes: &[(u16, u64)],
+    failures: &[(FailureKind, u64)],
+    check_failures: &[(CheckKind, u64)],
+) -> String {
+    let mut breakdown = String::new();
+    if !status_codes.is_empty() {
+        let codes = status_codes
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect::<Vec<_>>();
+        breakdown.push_str(&format!(" | codes: {}", codes.join(", ")));
+    }
+    if !failures.is_empty() {
+        let errors = failures
+            .iter()
+            .map(|(kind, count)| format!("{}: {}", kind.name(), count))
+            .collect::<Vec<_>>();
+        breakdown.push_str(&format!(" | errors: {}", errors.join(", ")));
+    }
+    if !check_failures.is_empty() {
+        let checks = check_failures
+            .iter()
+            .map(|(kind, count)| format!("{}: {}", kind.name(), count))
+            .collect::<Vec<_>>();
+        breakdown.push_str(&format!(" | failed checks: {}", checks.join(", ")));
+    }
+    breakdown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expects_body() {
+        let mode = |method: &[u8]| WorkMode {
+            method: hyper::Method::from_bytes(method).unwrap_or_default(),
+            body: None,
+        };
+        assert!(!mode(b"HEAD").expects_body());
+        assert!(mode(b"GET").expects_body());
+        assert!(mode(b"PURGE").expects_body());
+    }
+
+    #[test]
+    fn test_record_status_and_failure() {
+        let counter = RequestCounter::new();
+        counter.record_status(hyper::StatusCode::OK);
+        counter.record_status(hyper::StatusCode::NO_CONTENT);
+        counter.record_status(hyper::StatusCode::SERVICE_UNAVAILABLE);
+        counter.record_status(hyper::StatusCode::SWITCHING_PROTOCOLS);
+        counter.record_failure(FailureKind::Timeout);
+        counter.record_check_failure(CheckKind::Body);
+        assert_eq!(
+            counter.status_codes(),
+            vec![(101, 1), (200, 1), (204, 1), (503, 1)]
+        );
+        assert_eq!(counter.get_cumulative(ClientResponseCodeType::Code2), 2);
+        // A 1xx is a response, not a protocol failure
+        assert_eq!(
+            counter.get_cumulative(ClientResponseCodeType::OtherStatus),
+            1
+        );
+        assert_eq!(counter.get_cumulative(ClientResponseCodeType::Failure), 1);
+        assert_eq!(counter.failures()[3], (FailureKind::Protocol, 0));
+        assert_eq!(
+            counter.get_cumulative(ClientResponseCodeType::CheckFailed),
+            1
+        );
+        assert_eq!(counter.get_total(), 6);
+        assert_eq!(counter.take(ClientResponseCodeType::Code2), 2);
+        assert_eq!(counter.take(ClientResponseCodeType::Code2), 0);
+        assert_eq!(counter.get_cumulative(ClientResponseCodeType::Code2), 2);
+    }
+
+    #[test]
+    fn test_counts_since() {
+        let previous = vec![(200, 10), (503, 2)];
+        let current = vec![(200, 15), (404, 1), (503, 2)];
+        assert_eq!(counts_since(&current, &previous), vec![(200, 5), (404, 1)]);
+    }
+}